@@ -0,0 +1,383 @@
+use std::fmt;
+
+use enquote::enquote;
+
+use crate::dockerfile_parser::Dockerfile;
+use crate::error::*;
+
+/// A pending `FROM` instruction whose alias and flags may still be modified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PendingFrom {
+  image: String,
+  alias: Option<String>,
+  flags: Vec<(String, String)>,
+}
+
+/// A single line (or instruction) queued in a `DockerfileBuilder`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BuilderLine {
+  From(PendingFrom),
+  Instruction(String),
+  Comment(String),
+  Blank,
+}
+
+impl fmt::Display for BuilderLine {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BuilderLine::From(from) => {
+        write!(f, "FROM")?;
+        for (name, value) in &from.flags {
+          write!(f, " --{}={}", name, value)?;
+        }
+
+        write!(f, " {}", from.image)?;
+
+        if let Some(alias) = &from.alias {
+          write!(f, " AS {}", alias)?;
+        }
+
+        Ok(())
+      },
+      BuilderLine::Instruction(s) => write!(f, "{}", s),
+      BuilderLine::Comment(s) => write!(f, "# {}", s),
+      BuilderLine::Blank => Ok(()),
+    }
+  }
+}
+
+/// Quotes a value if it would otherwise be split or misinterpreted by the
+/// Dockerfile grammar.
+fn maybe_quote(s: &str) -> String {
+  let needs_quotes = s.is_empty() || s.chars().any(|c| {
+    c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == '='
+  });
+
+  if needs_quotes {
+    enquote('"', s)
+  } else {
+    s.to_string()
+  }
+}
+
+/// Formats a list of strings as an exec-form JSON array.
+fn exec_array<S: AsRef<str>>(args: &[S]) -> String {
+  let elements: Vec<String> = args
+    .iter()
+    .map(|a| enquote('"', a.as_ref()))
+    .collect();
+
+  format!("[{}]", elements.join(", "))
+}
+
+/// A builder to programmatically construct a `Dockerfile` from typed pieces.
+///
+/// Values are quoted and escaped as needed, and the resulting `Dockerfile` is
+/// produced by the regular parser, so its `content`, `global_args`,
+/// `instructions` and all spans are exactly what parsing the rendered text
+/// would produce.
+///
+/// `ARG` instructions added before the first `FROM` become global args.
+/// Methods that modify a `FROM` (e.g. `alias()` and `platform()`) apply to the
+/// most recently added `FROM`.
+///
+/// # Example
+/// ```
+/// use dockerfile_parser::DockerfileBuilder;
+///
+/// let dockerfile = DockerfileBuilder::new()
+///   .arg("RUST_VERSION", Some("1.75"))
+///   .from("rust:${RUST_VERSION}").alias("build")
+///   .run_shell("cargo build --release")
+///   .from("debian:bookworm-slim")
+///   .copy_from("build", &["/target/release/app"], "/usr/local/bin/app")
+///   .env("RUST_LOG", "info")
+///   .cmd_exec(&["app", "--serve"])
+///   .build()?;
+///
+/// assert_eq!(dockerfile.global_args.len(), 1);
+/// assert_eq!(dockerfile.instructions.len(), 7);
+/// assert_eq!(dockerfile.stages().get("build").unwrap().index, 0);
+/// # Ok::<(), dockerfile_parser::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DockerfileBuilder {
+  lines: Vec<BuilderLine>,
+}
+
+impl DockerfileBuilder {
+  /// Creates a new, empty builder.
+  pub fn new() -> DockerfileBuilder {
+    DockerfileBuilder::default()
+  }
+
+  fn push(mut self, line: BuilderLine) -> Self {
+    self.lines.push(line);
+
+    self
+  }
+
+  fn last_from_mut(&mut self) -> Option<&mut PendingFrom> {
+    self.lines.iter_mut().rev().find_map(|l| match l {
+      BuilderLine::From(f) => Some(f),
+      _ => None
+    })
+  }
+
+  /// Adds an arbitrary instruction with pre-formatted arguments.
+  ///
+  /// Arguments are not quoted or escaped.
+  pub fn instruction(self, name: &str, arguments: &str) -> Self {
+    self.push(BuilderLine::Instruction(
+      format!("{} {}", name.to_ascii_uppercase(), arguments)
+    ))
+  }
+
+  /// Adds a comment line.
+  pub fn comment(self, comment: &str) -> Self {
+    comment.lines().fold(self, |b, line| b.push(BuilderLine::Comment(line.to_string())))
+  }
+
+  /// Adds an empty line.
+  pub fn blank(self) -> Self {
+    self.push(BuilderLine::Blank)
+  }
+
+  /// Adds a `FROM` instruction, beginning a new stage.
+  pub fn from(self, image: &str) -> Self {
+    self.push(BuilderLine::From(PendingFrom {
+      image: image.to_string(),
+      alias: None,
+      flags: Vec::new(),
+    }))
+  }
+
+  /// Sets the alias of the most recently added `FROM` instruction.
+  ///
+  /// Has no effect if no `FROM` has been added.
+  pub fn alias(mut self, alias: &str) -> Self {
+    if let Some(from) = self.last_from_mut() {
+      from.alias = Some(alias.to_string());
+    }
+
+    self
+  }
+
+  /// Sets the `--platform` flag of the most recently added `FROM` instruction.
+  ///
+  /// Has no effect if no `FROM` has been added.
+  pub fn platform(mut self, platform: &str) -> Self {
+    if let Some(from) = self.last_from_mut() {
+      from.flags.retain(|(name, _)| name != "platform");
+      from.flags.push(("platform".into(), platform.to_string()));
+    }
+
+    self
+  }
+
+  /// Adds an `ARG` instruction with an optional default value.
+  pub fn arg(self, name: &str, value: Option<&str>) -> Self {
+    let s = match value {
+      Some(value) => format!("ARG {}={}", name, maybe_quote(value)),
+      None => format!("ARG {}", name)
+    };
+
+    self.push(BuilderLine::Instruction(s))
+  }
+
+  /// Adds a shell-form `RUN` instruction.
+  pub fn run_shell(self, command: &str) -> Self {
+    self.push(BuilderLine::Instruction(format!("RUN {}", command)))
+  }
+
+  /// Adds an exec-form `RUN` instruction.
+  pub fn run_exec<S: AsRef<str>>(self, args: &[S]) -> Self {
+    self.push(BuilderLine::Instruction(format!("RUN {}", exec_array(args))))
+  }
+
+  /// Adds a shell-form `RUN` instruction with the given `--mount`, `--network`,
+  /// etc options.
+  pub fn run_shell_with_options(self, options: &[(&str, &str)], command: &str) -> Self {
+    let options: Vec<String> = options
+      .iter()
+      .map(|(name, value)| format!("--{}={}", name, value))
+      .collect();
+
+    self.push(BuilderLine::Instruction(
+      format!("RUN {} {}", options.join(" "), command)
+    ))
+  }
+
+  /// Adds a `COPY` instruction copying from the build context.
+  pub fn copy<S: AsRef<str>>(self, sources: &[S], destination: &str) -> Self {
+    self.copy_with_flags(&[], sources, destination)
+  }
+
+  /// Adds a `COPY --from=...` instruction copying from another stage or image.
+  pub fn copy_from<S: AsRef<str>>(self, from: &str, sources: &[S], destination: &str) -> Self {
+    self.copy_with_flags(&[("from", from)], sources, destination)
+  }
+
+  /// Adds a `COPY` instruction with arbitrary flags, e.g. `--chown`.
+  pub fn copy_with_flags<S: AsRef<str>>(
+    self,
+    flags: &[(&str, &str)],
+    sources: &[S],
+    destination: &str
+  ) -> Self {
+    let mut parts = vec!["COPY".to_string()];
+    parts.extend(flags.iter().map(|(name, value)| format!("--{}={}", name, value)));
+    parts.extend(sources.iter().map(|s| s.as_ref().to_string()));
+    parts.push(destination.to_string());
+
+    self.push(BuilderLine::Instruction(parts.join(" ")))
+  }
+
+  /// Adds an `ENV` instruction setting a single variable.
+  pub fn env(self, key: &str, value: &str) -> Self {
+    self.push(BuilderLine::Instruction(format!("ENV {}={}", key, maybe_quote(value))))
+  }
+
+  /// Adds a `LABEL` instruction setting a single label.
+  pub fn label(self, name: &str, value: &str) -> Self {
+    self.push(BuilderLine::Instruction(
+      format!("LABEL {}={}", maybe_quote(name), maybe_quote(value))
+    ))
+  }
+
+  /// Adds a `WORKDIR` instruction.
+  pub fn workdir(self, path: &str) -> Self {
+    self.push(BuilderLine::Instruction(format!("WORKDIR {}", path)))
+  }
+
+  /// Adds a `USER` instruction.
+  pub fn user(self, user: &str) -> Self {
+    self.push(BuilderLine::Instruction(format!("USER {}", user)))
+  }
+
+  /// Adds an `EXPOSE` instruction.
+  pub fn expose(self, port: &str) -> Self {
+    self.push(BuilderLine::Instruction(format!("EXPOSE {}", port)))
+  }
+
+  /// Adds a shell-form `ENTRYPOINT` instruction.
+  pub fn entrypoint_shell(self, command: &str) -> Self {
+    self.push(BuilderLine::Instruction(format!("ENTRYPOINT {}", command)))
+  }
+
+  /// Adds an exec-form `ENTRYPOINT` instruction.
+  pub fn entrypoint_exec<S: AsRef<str>>(self, args: &[S]) -> Self {
+    self.push(BuilderLine::Instruction(format!("ENTRYPOINT {}", exec_array(args))))
+  }
+
+  /// Adds a shell-form `CMD` instruction.
+  pub fn cmd_shell(self, command: &str) -> Self {
+    self.push(BuilderLine::Instruction(format!("CMD {}", command)))
+  }
+
+  /// Adds an exec-form `CMD` instruction.
+  pub fn cmd_exec<S: AsRef<str>>(self, args: &[S]) -> Self {
+    self.push(BuilderLine::Instruction(format!("CMD {}", exec_array(args))))
+  }
+
+  /// Renders the Dockerfile text without parsing it.
+  pub fn render(&self) -> String {
+    let mut out = String::new();
+    for line in &self.lines {
+      out.push_str(&line.to_string());
+      out.push('\n');
+    }
+
+    out
+  }
+
+  /// Renders and parses the Dockerfile.
+  ///
+  /// Returns an error if any raw arguments (e.g. shell commands) produced an
+  /// unparseable Dockerfile.
+  pub fn build(&self) -> Result<Dockerfile> {
+    Dockerfile::parse(&self.render())
+  }
+}
+
+impl fmt::Display for DockerfileBuilder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.render())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::TryInto;
+
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+  use crate::instructions::*;
+
+  #[test]
+  fn test_builder_render() {
+    let builder = DockerfileBuilder::new()
+      .comment("generated")
+      .arg("VERSION", Some("1.75"))
+      .arg("EMPTY", None)
+      .from("rust:${VERSION}").platform("linux/amd64").alias("build")
+      .run_shell("cargo build --release")
+      .blank()
+      .from("debian:bookworm-slim")
+      .copy_from("build", &["/target/release/app"], "/usr/local/bin/app")
+      .env("GREETING", "hello \"world\"")
+      .label("org.opencontainers.image.title", "app")
+      .workdir("/app")
+      .user("nobody")
+      .expose("8080")
+      .entrypoint_exec(&["app"])
+      .cmd_exec(&["--port", "8080"]);
+
+    assert_eq!(builder.render(), indoc!(r#"
+      # generated
+      ARG VERSION=1.75
+      ARG EMPTY
+      FROM --platform=linux/amd64 rust:${VERSION} AS build
+      RUN cargo build --release
+
+      FROM debian:bookworm-slim
+      COPY --from=build /target/release/app /usr/local/bin/app
+      ENV GREETING="hello \"world\""
+      LABEL org.opencontainers.image.title=app
+      WORKDIR /app
+      USER nobody
+      EXPOSE 8080
+      ENTRYPOINT ["app"]
+      CMD ["--port", "8080"]
+    "#));
+  }
+
+  #[test]
+  fn test_builder_build() -> Result<()> {
+    let dockerfile = DockerfileBuilder::new()
+      .arg("image", Some("alpine:3.12"))
+      .from("$image").alias("base")
+      .env("FOO", "bar baz")
+      .from("base")
+      .run_exec(&["echo", "hello world"])
+      .build()?;
+
+    assert_eq!(dockerfile, Dockerfile::parse(&dockerfile.content)?);
+    assert_eq!(dockerfile.global_args.len(), 1);
+    assert_eq!(dockerfile.global_args[0].name.content, "image");
+
+    let env: &EnvInstruction = (&dockerfile.instructions[2]).try_into()?;
+    assert_eq!(env.vars[0].value.to_string(), "bar baz");
+    assert_eq!(&dockerfile.content[env.span.start..env.span.end], "ENV FOO=\"bar baz\"");
+
+    let run = dockerfile.instructions[4].as_run().unwrap();
+    assert_eq!(run.as_exec().unwrap().as_str_vec(), vec!["echo", "hello world"]);
+
+    let stages = dockerfile.stages();
+    assert_eq!(stages[1].parent, crate::StageParent::Stage(0));
+
+    Ok(())
+  }
+}
@@ -0,0 +1,519 @@
+//! Export to BuildKit's parser AST.
+//!
+//! BuildKit (and the Go tooling built around it) represents a parsed Dockerfile
+//! as a tree of [`parser.Node`][node] values: a root node with one child per
+//! instruction, where each instruction's arguments form a linked list through
+//! `Next`. This module converts a `Dockerfile` into an equivalent tree so that
+//! results can be compared against (or shared with) the reference
+//! implementation.
+//!
+//! [node]: https://pkg.go.dev/github.com/moby/buildkit/frontend/dockerfile/parser#Node
+
+use std::collections::BTreeMap;
+
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::instructions::*;
+use crate::line_index::LineIndex;
+use crate::splicer::Span;
+use crate::util::*;
+
+/// Instructions that BuildKit parses as a single string argument rather than a
+/// whitespace-delimited list.
+const SINGLE_STRING_INSTRUCTIONS: &[&str] = &[
+  "maintainer", "user", "workdir", "stopsignal", "onbuild", "healthcheck"
+];
+
+/// A heredoc attached to a BuildKit node, mirroring BuildKit's
+/// `parser.Heredoc`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildkitHeredoc {
+  /// The heredoc delimiter, e.g. `EOF`
+  pub name: String,
+
+  /// The file descriptor the heredoc is redirected from, generally 0
+  pub file_descriptor: usize,
+
+  /// If true, variables in the heredoc body will be expanded (i.e. the
+  /// delimiter was not quoted)
+  pub expand: bool,
+
+  /// If true, leading tabs are stripped from the body (i.e. `<<-EOF`)
+  pub chomp: bool,
+
+  /// The heredoc body, including the trailing newline
+  pub content: String,
+}
+
+/// A single node in a BuildKit-compatible parse tree, mirroring BuildKit's
+/// `parser.Node`.
+///
+/// The root node contains one child per instruction. Each instruction node's
+/// `value` is the lowercased instruction keyword, and its arguments form a
+/// linked list via `next`.
+///
+/// # Example
+/// ```
+/// use dockerfile_parser::Dockerfile;
+///
+/// let dockerfile = Dockerfile::parse(r#"
+///   FROM alpine:3.11 as build
+///   COPY --from=build /foo /bar
+/// "#).unwrap();
+///
+/// let root = dockerfile.to_buildkit_node();
+/// assert_eq!(root.children[0].value, "from");
+/// assert_eq!(root.children[0].next_values(), vec!["alpine:3.11", "as", "build"]);
+/// assert_eq!(root.children[1].flags, vec!["--from=build"]);
+/// assert_eq!(root.children[1].start_line, 3);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BuildkitNode {
+  /// The content of this node
+  pub value: String,
+
+  /// The next argument in this instruction, if any
+  pub next: Option<Box<BuildkitNode>>,
+
+  /// Child nodes; only set on the root node
+  pub children: Vec<BuildkitNode>,
+
+  /// Heredocs attached to this instruction
+  pub heredocs: Vec<BuildkitHeredoc>,
+
+  /// Special attributes of this node, e.g. `json` for exec-form instructions
+  pub attributes: BTreeMap<String, bool>,
+
+  /// The original instruction text, with line continuations and comments
+  /// removed
+  pub original: String,
+
+  /// Flags passed to this instruction, e.g. `--from=build`
+  pub flags: Vec<String>,
+
+  /// The 1-indexed line on which this node begins
+  pub start_line: usize,
+
+  /// The 1-indexed line on which this node ends
+  pub end_line: usize,
+}
+
+impl BuildkitNode {
+  /// Creates a new root node for the given Dockerfile, with one child for each
+  /// instruction.
+  pub fn from_dockerfile(dockerfile: &Dockerfile) -> BuildkitNode {
+    let lines = dockerfile.line_index();
+    let children: Vec<BuildkitNode> = dockerfile.instructions
+      .iter()
+      .map(|ins| instruction_node(dockerfile, &lines, ins))
+      .collect();
+
+    BuildkitNode {
+      start_line: children.first().map(|c| c.start_line).unwrap_or(0),
+      end_line: children.last().map(|c| c.end_line).unwrap_or(0),
+      children,
+      ..Default::default()
+    }
+  }
+
+  /// Returns an iterator over this node's `next` chain, not including this
+  /// node itself.
+  pub fn iter_next(&self) -> impl Iterator<Item = &BuildkitNode> {
+    std::iter::successors(self.next.as_deref(), |n| n.next.as_deref())
+  }
+
+  /// Returns the values of this node's `next` chain, i.e. the arguments of an
+  /// instruction node.
+  pub fn next_values(&self) -> Vec<&str> {
+    self.iter_next().map(|n| n.value.as_str()).collect()
+  }
+
+  /// Dumps this node in the same format as BuildKit's `Node.Dump()`, useful for
+  /// diffing against the reference implementation.
+  pub fn dump(&self) -> String {
+    let mut out = self.value.to_lowercase();
+
+    if !self.flags.is_empty() {
+      let flags: Vec<String> = self.flags.iter().map(|f| go_quote(f)).collect();
+      out.push_str(&format!(" [{}]", flags.join(" ")));
+    }
+
+    for child in &self.children {
+      out.push_str(&format!("({})\n", child.dump()));
+    }
+
+    for next in self.iter_next() {
+      out.push(' ');
+      if next.children.is_empty() {
+        out.push_str(&go_quote(&next.value));
+      } else {
+        out.push_str(&next.dump());
+      }
+    }
+
+    out.trim().to_string()
+  }
+}
+
+/// Quotes a string the way Go's `strconv.Quote` does.
+fn go_quote(s: &str) -> String {
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\u{7}' => out.push_str("\\a"),
+      '\u{8}' => out.push_str("\\b"),
+      '\u{c}' => out.push_str("\\f"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      '\u{b}' => out.push_str("\\v"),
+      c if (c as u32) < 0x20 || c == '\u{7f}' => out.push_str(&format!("\\x{:02x}", c as u32)),
+      c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c)
+    }
+  }
+
+  out.push('"');
+  out
+}
+
+/// Builds a linked list of nodes from the given values.
+fn chain(values: Vec<String>) -> Option<Box<BuildkitNode>> {
+  values.into_iter().rev().fold(None, |next, value| {
+    Some(Box::new(BuildkitNode {
+      value,
+      next,
+      ..Default::default()
+    }))
+  })
+}
+
+fn raw(dockerfile: &Dockerfile, span: Span) -> &str {
+  &dockerfile.content[span.start..span.end]
+}
+
+/// Joins an instruction's physical lines into a single logical line the way
+/// BuildKit does, dropping line continuations and interleaved comment lines.
+///
+/// Heredoc bodies are not part of the logical line.
+fn logical_line(text: &str, has_heredoc: bool) -> String {
+  let mut out = String::new();
+
+  for (i, line) in text.lines().enumerate() {
+    if i > 0 {
+      let trimmed = line.trim_start();
+      if trimmed.is_empty() || trimmed.starts_with('#') {
+        continue;
+      }
+    }
+
+    let stripped = line.trim_end();
+    match stripped.strip_suffix('\\') {
+      Some(rest) if !has_heredoc => out.push_str(rest),
+      _ => {
+        out.push_str(stripped);
+        break;
+      }
+    }
+  }
+
+  out.trim().to_string()
+}
+
+fn has_heredoc(ins: &Instruction) -> bool {
+  match ins {
+    Instruction::Run(r) => matches!(r.expr, ShellOrExecExpr::ShellWithHeredoc(..)),
+    Instruction::Copy(c) => matches!(c.sources.first(), Some(SourceType::FileContents(_))),
+    _ => false
+  }
+}
+
+/// Returns an instruction's text as a single logical line, as BuildKit reports
+/// it.
+pub(crate) fn instruction_line(dockerfile: &Dockerfile, ins: &Instruction) -> String {
+  logical_line(raw(dockerfile, ins.span()), has_heredoc(ins))
+}
+
+/// Parses a heredoc from its raw text, i.e. `<<EOF\nbody\nEOF`, returning the
+/// remainder of the opening line and the parsed heredoc.
+fn parse_raw_heredoc(text: &str) -> Option<(String, BuildkitHeredoc)> {
+  let op_pos = text.find("<<")?;
+  let (first_line, body) = match text.find('\n') {
+    Some(i) => (&text[..i], &text[i + 1..]),
+    None => (text, "")
+  };
+
+  let after_op = &first_line[op_pos + 2..];
+  let chomp = after_op.starts_with('-');
+  let delim = after_op.trim_start_matches('-').trim_start();
+  let delim = delim.split_whitespace().next().unwrap_or("");
+  let expand = !(delim.starts_with('\'') || delim.starts_with('"'));
+  let name = delim.trim_matches(|c| c == '\'' || c == '"').to_string();
+
+  // drop the terminator line, if any
+  let content = match body.trim_end_matches('\n').rfind('\n') {
+    Some(i) => body[..=i].to_string(),
+    None if body.trim_start_matches('\t').starts_with(name.as_str()) => String::new(),
+    None => body.to_string(),
+  };
+
+  Some((first_line.to_string(), BuildkitHeredoc {
+    name,
+    file_descriptor: 0,
+    expand,
+    chomp,
+    content,
+  }))
+}
+
+fn shell_or_exec(node: &mut BuildkitNode, expr: &ShellOrExecExpr) {
+  match expr {
+    ShellOrExecExpr::Shell(s) => {
+      node.next = chain(vec![s.to_string().trim().to_string()]);
+    },
+    ShellOrExecExpr::ShellWithHeredoc(s, heredoc) => {
+      if let Some((first_line, parsed)) = parse_raw_heredoc(&heredoc.content) {
+        let value = format!("{}{}", s, first_line);
+        node.next = chain(vec![value.trim().to_string()]);
+        node.heredocs.push(parsed);
+      }
+    },
+    ShellOrExecExpr::Exec(array) => {
+      node.attributes.insert("json".into(), true);
+      node.next = chain(
+        array.elements.iter().map(|e| e.content.clone()).collect()
+      );
+    }
+  }
+}
+
+/// Builds a key/value/separator chain as used by BuildKit for `ENV` and
+/// `LABEL`.
+fn key_value_chain(pairs: Vec<(String, String, &str)>) -> Option<Box<BuildkitNode>> {
+  chain(
+    pairs
+      .into_iter()
+      .flat_map(|(k, v, sep)| vec![k, v, sep.to_string()])
+      .collect()
+  )
+}
+
+fn instruction_node(
+  dockerfile: &Dockerfile,
+  lines: &LineIndex,
+  ins: &Instruction
+) -> BuildkitNode {
+  let span = ins.span();
+  let text = raw(dockerfile, span);
+  let has_heredoc = has_heredoc(ins);
+
+  let mut node = BuildkitNode {
+    original: logical_line(text, has_heredoc),
+    start_line: lines.line(span.start) + 1,
+    end_line: lines.line(span.end.saturating_sub(1).max(span.start)) + 1,
+    ..Default::default()
+  };
+
+  match ins {
+    Instruction::From(from) => {
+      node.value = "from".into();
+      node.flags = from.flags.iter().map(|f| raw(dockerfile, f.span).to_string()).collect();
+
+      let mut values = vec![from.image.content.clone()];
+      if let Some(alias) = &from.alias {
+        let between = raw(dockerfile, Span::new(from.image.span.end, alias.span.start));
+        let as_token = between
+          .split_whitespace()
+          .find(|t| t.eq_ignore_ascii_case("as"))
+          .unwrap_or("AS");
+
+        values.push(as_token.to_string());
+        values.push(alias.content.clone());
+      }
+
+      node.next = chain(values);
+    },
+    Instruction::Arg(arg) => {
+      node.value = "arg".into();
+      let end = arg.value.as_ref().map(|v| v.span.end).unwrap_or(arg.name.span.end);
+      node.next = chain(vec![raw(dockerfile, Span::new(arg.name.span.start, end)).to_string()]);
+    },
+    Instruction::Label(label) => {
+      node.value = "label".into();
+      node.next = key_value_chain(label.labels.iter().map(|l| {
+        let between = raw(dockerfile, Span::new(l.name.span.end, l.value.span.start));
+        let sep = if between.contains('=') { "=" } else { "" };
+        (
+          clean_escaped_breaks(raw(dockerfile, l.name.span)),
+          clean_escaped_breaks(raw(dockerfile, l.value.span)),
+          sep
+        )
+      }).collect());
+    },
+    Instruction::Env(env) => {
+      node.value = "env".into();
+      node.next = key_value_chain(env.vars.iter().map(|v| {
+        let between = raw(dockerfile, Span::new(v.key.span.end, v.value.span.start));
+        let sep = if between.contains('=') { "=" } else { "" };
+        let value = if sep.is_empty() {
+          v.value.to_string().trim().to_string()
+        } else {
+          raw(dockerfile, v.value.span).to_string()
+        };
+
+        (v.key.content.clone(), value, sep)
+      }).collect());
+    },
+    Instruction::Run(run) => {
+      node.value = "run".into();
+      node.flags = run.options.iter().map(|o| o.original.clone()).collect();
+      shell_or_exec(&mut node, &run.expr);
+    },
+    Instruction::Entrypoint(entrypoint) => {
+      node.value = "entrypoint".into();
+      shell_or_exec(&mut node, &entrypoint.expr);
+    },
+    Instruction::Cmd(cmd) => {
+      node.value = "cmd".into();
+      shell_or_exec(&mut node, &cmd.expr);
+    },
+    Instruction::Copy(copy) => {
+      node.value = "copy".into();
+      node.flags = copy.flags.iter().map(|f| raw(dockerfile, f.span).to_string()).collect();
+
+      if has_heredoc {
+        // the heredoc text starts at the `<<` operator following any flags
+        let flags_end = copy.flags.last().map(|f| f.span.end).unwrap_or(span.start);
+        let heredoc_text = raw(dockerfile, Span::new(flags_end, span.end));
+        let op_pos = heredoc_text.find("<<").unwrap_or(0);
+        let dest_pos = copy.destination.span.start - flags_end;
+
+        let mut values = vec![heredoc_text[op_pos..dest_pos].trim().to_string()];
+        values.push(copy.destination.content.clone());
+        node.next = chain(values);
+
+        if let Some((_, parsed)) = parse_raw_heredoc(&heredoc_text[op_pos..]) {
+          node.heredocs.push(BuildkitHeredoc {
+            content: copy.sources.iter().map(|s| match s {
+              SourceType::FileContents(c) | SourceType::FileName(c) => c.content.as_str()
+            }).collect(),
+            ..parsed
+          });
+        }
+      } else {
+        let mut values: Vec<String> = copy.sources.iter().map(|s| match s {
+          SourceType::FileName(f) | SourceType::FileContents(f) => f.content.clone()
+        }).collect();
+        values.push(copy.destination.content.clone());
+        node.next = chain(values);
+      }
+    },
+    Instruction::Misc(misc) => {
+      node.value = misc.instruction.content.to_ascii_lowercase();
+
+      let args = misc.arguments.to_string();
+      let args = args.trim();
+      node.next = if SINGLE_STRING_INSTRUCTIONS.contains(&node.value.as_str()) {
+        chain(vec![args.to_string()])
+      } else {
+        chain(args.split_whitespace().map(String::from).collect())
+      };
+    }
+  }
+
+  node
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use crate::Dockerfile;
+
+  use super::go_quote;
+
+  #[test]
+  fn test_buildkit_node_basic() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG image=alpine:3.12
+      FROM --platform=linux/amd64 $image as build
+      RUN apk add --no-cache \
+        curl
+      ENV foo=bar baz="qux"
+      LABEL hello world
+      COPY --from=build /foo /bar /baz
+      EXPOSE 80 443
+      WORKDIR /hello world
+      CMD ["echo", "hello"]
+    "#)).unwrap();
+
+    let root = dockerfile.to_buildkit_node();
+    assert_eq!(root.children.len(), 9);
+    assert_eq!(root.start_line, 1);
+    assert_eq!(root.end_line, 10);
+
+    assert_eq!(root.dump(), indoc!(r#"
+      (arg "image=alpine:3.12")
+      (from ["--platform=linux/amd64"] "$image" "as" "build")
+      (run "apk add --no-cache   curl")
+      (env "foo" "bar" "=" "baz" "\"qux\"" "=")
+      (label "hello" "world" "")
+      (copy ["--from=build"] "/foo" "/bar" "/baz")
+      (expose "80" "443")
+      (workdir "/hello world")
+      (cmd "echo" "hello")"#));
+
+    let run = &root.children[2];
+    assert_eq!(run.start_line, 3);
+    assert_eq!(run.end_line, 4);
+    assert_eq!(run.original, "RUN apk add --no-cache   curl");
+
+    let cmd = &root.children[8];
+    assert_eq!(cmd.attributes.get("json"), Some(&true));
+    assert!(run.attributes.is_empty());
+  }
+
+  #[test]
+  fn test_buildkit_node_heredoc() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.12
+      RUN cat > /test.conf <<-'EOF'
+      hello
+      EOF
+      COPY <<EOF /hello.txt
+      world
+      EOF
+    "#)).unwrap();
+
+    let root = dockerfile.to_buildkit_node();
+
+    let run = &root.children[1];
+    assert_eq!(run.next_values(), vec!["cat > /test.conf <<-'EOF'"]);
+    assert_eq!(run.original, "RUN cat > /test.conf <<-'EOF'");
+    assert_eq!((run.start_line, run.end_line), (2, 4));
+    assert_eq!(run.heredocs.len(), 1);
+    assert_eq!(run.heredocs[0].name, "EOF");
+    assert_eq!(run.heredocs[0].content, "hello\n");
+    assert!(run.heredocs[0].chomp);
+    assert!(!run.heredocs[0].expand);
+
+    let copy = &root.children[2];
+    assert_eq!(copy.next_values(), vec!["<<EOF", "/hello.txt"]);
+    assert_eq!((copy.start_line, copy.end_line), (5, 7));
+    assert_eq!(copy.heredocs[0].name, "EOF");
+    assert_eq!(copy.heredocs[0].content, "world\n");
+    assert!(copy.heredocs[0].expand);
+    assert!(!copy.heredocs[0].chomp);
+  }
+
+  #[test]
+  fn test_go_quote() {
+    assert_eq!(go_quote("hello"), r#""hello""#);
+    assert_eq!(go_quote("a \"b\" \\ c\n"), r#""a \"b\" \\ c\n""#);
+    assert_eq!(go_quote("\u{1b}[0m\u{85}é"), r#""\x1b[0m\u0085é""#);
+  }
+}
@@ -0,0 +1,449 @@
+use std::fmt::Write;
+
+use crate::buildkit::instruction_line;
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::error::StageGraphError;
+use crate::graph::{mount_from, StageEdgeKind, StageGraph};
+use crate::scope::{BuildOptions, Evaluator};
+use crate::sources::{classify_from, CopyFromSource};
+use crate::stage::StageParent;
+
+/// Options for rendering a Dockerfile's stages as a diagram.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiagramOptions {
+  /// If true, lists each stage's instructions inside its node. Otherwise,
+  /// stages are collapsed to just their name.
+  pub show_instructions: bool,
+
+  /// Build options used to resolve stage parents and identify named build
+  /// contexts.
+  pub build_options: BuildOptions
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+  Stage,
+  Image,
+  Scratch,
+  Context,
+
+  /// An image or `COPY --from` value that could not be expanded.
+  Unresolved
+}
+
+#[derive(Debug)]
+struct Node {
+  id: String,
+  kind: NodeKind,
+  label: String,
+  instructions: Vec<String>
+}
+
+#[derive(Debug)]
+struct Diagram {
+  nodes: Vec<Node>,
+  edges: Vec<(String, String, StageEdgeKind)>
+}
+
+impl Diagram {
+  fn new(dockerfile: &Dockerfile, options: &DiagramOptions) -> Result<Diagram, StageGraphError> {
+    // ensures all numeric stage references are valid
+    StageGraph::new(dockerfile)?;
+
+    let evaluator = Evaluator::with_options(dockerfile, options.build_options.clone());
+    let stages = evaluator.stages();
+    let copies = evaluator.copy_from_sources();
+
+    let mut diagram = Diagram { nodes: Vec::new(), edges: Vec::new() };
+    for stage in stages.iter() {
+      let from = stage.instructions[0].as_from().unwrap();
+      let instructions = if options.show_instructions {
+        stage.instructions.iter().map(|ins| instruction_line(dockerfile, ins)).collect()
+      } else {
+        Vec::new()
+      };
+
+      diagram.nodes.push(Node {
+        id: format!("stage_{}", stage.index),
+        kind: NodeKind::Stage,
+        label: match &from.alias {
+          Some(alias) => alias.content.clone(),
+          None => format!("stage {}", stage.index)
+        },
+        instructions
+      });
+    }
+
+    for stage in stages.iter() {
+      let id = format!("stage_{}", stage.index);
+      match &stage.parent {
+        StageParent::Image(image) => {
+          let image = match &stage.resolved_parent {
+            Some(resolved) => diagram.external(NodeKind::Image, &resolved.to_string()),
+            None => diagram.external(NodeKind::Unresolved, &image.to_string())
+          };
+          diagram.edges.push((image, id.clone(), StageEdgeKind::From));
+        },
+        StageParent::Scratch => {
+          let scratch = diagram.external(NodeKind::Scratch, "scratch");
+          diagram.edges.push((scratch, id.clone(), StageEdgeKind::From));
+        },
+        StageParent::Stage(parent) => {
+          diagram.edges.push((format!("stage_{}", parent), id.clone(), StageEdgeKind::From));
+        },
+        StageParent::NamedContext(name) => {
+          let context = diagram.external(NodeKind::Context, name);
+          diagram.edges.push((context, id.clone(), StageEdgeKind::From));
+        }
+      }
+
+      for ins in &stage.instructions {
+        // `COPY --from` values are expanded, but `RUN --mount` values are not
+        let references: Vec<(String, CopyFromSource, StageEdgeKind)> = match ins {
+          Instruction::Copy(copy) => copy.flags
+            .iter()
+            .filter(|f| f.name.as_ref() == "from")
+            .filter_map(|f| copies.iter().find(|c| c.span == f.value.span))
+            .map(|c| (c.value.clone(), c.source.clone(), StageEdgeKind::CopyFrom))
+            .collect(),
+          Instruction::Run(run) => run.options
+            .iter()
+            .filter_map(|o| mount_from(&dockerfile.content, o))
+            .map(|(name, _)| {
+              let source = classify_from(stages, &options.build_options, &name);
+              (name, source, StageEdgeKind::RunMount)
+            })
+            .collect(),
+          _ => Vec::new()
+        };
+
+        for (name, source, kind) in references {
+          let source = match source {
+            CopyFromSource::Stage(index) => format!("stage_{}", index),
+            CopyFromSource::Image(_) => diagram.external(NodeKind::Image, &name),
+            CopyFromSource::NamedContext(_) => diagram.external(NodeKind::Context, &name),
+            CopyFromSource::Unresolved(_) => diagram.external(NodeKind::Unresolved, &name)
+          };
+
+          diagram.edges.push((source, id.clone(), kind));
+        }
+      }
+    }
+
+    Ok(diagram)
+  }
+
+  /// Returns the ID of the node for an external image or `scratch`, adding it
+  /// if necessary.
+  fn external(&mut self, kind: NodeKind, label: &str) -> String {
+    if let Some(node) = self.nodes.iter().find(|n| n.kind == kind && n.label == label) {
+      return node.id.clone();
+    }
+
+    let count = self.nodes.iter().filter(|n| n.kind == kind).count();
+    let id = match kind {
+      NodeKind::Scratch => "scratch".to_string(),
+      NodeKind::Context => format!("context_{}", count),
+      NodeKind::Unresolved => format!("unresolved_{}", count),
+      _ => format!("image_{}", count)
+    };
+
+    self.nodes.push(Node {
+      id: id.clone(),
+      kind,
+      label: label.to_string(),
+      instructions: Vec::new()
+    });
+
+    id
+  }
+}
+
+fn escape_dot(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_mermaid(s: &str) -> String {
+  s.replace('#', "#35;")
+    .replace('"', "#quot;")
+    .replace('<', "#lt;")
+    .replace('>', "#gt;")
+}
+
+impl Dockerfile {
+  /// Renders this Dockerfile's stages as a Graphviz DOT digraph.
+  ///
+  /// Stages are drawn as rounded boxes, external images as cylinders, named
+  /// build contexts as folders, `scratch` as a dotted box and images that
+  /// could not be resolved as dashed boxes. `FROM` dependencies are drawn as
+  /// solid edges, `COPY --from` as dashed edges and `RUN --mount=from=` as
+  /// dotted edges.
+  ///
+  /// Variables in `FROM` images and `COPY --from` values are expanded using
+  /// the options' build args.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   FROM golang:1.15 as build
+  ///   RUN go build
+  ///
+  ///   FROM scratch
+  ///   COPY --from=build /app /app
+  /// "#)?;
+  ///
+  /// let dot = dockerfile.to_dot(&DiagramOptions::default()).unwrap();
+  /// assert!(dot.contains(r#"stage_0 -> stage_1 [style=dashed, label="COPY --from"];"#));
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn to_dot(&self, options: &DiagramOptions) -> Result<String, StageGraphError> {
+    let diagram = Diagram::new(self, options)?;
+
+    let mut out = String::from("digraph stages {\n");
+    for node in &diagram.nodes {
+      let mut label = escape_dot(&node.label);
+      if !node.instructions.is_empty() {
+        label.push_str("\\n");
+        for ins in &node.instructions {
+          write!(label, "{}\\l", escape_dot(ins)).unwrap();
+        }
+      }
+
+      let style = match node.kind {
+        NodeKind::Stage => "shape=box, style=rounded",
+        NodeKind::Image => "shape=cylinder",
+        NodeKind::Scratch => "shape=box, style=dotted",
+        NodeKind::Context => "shape=folder",
+        NodeKind::Unresolved => "shape=box, style=dashed"
+      };
+
+      writeln!(out, "  {} [label=\"{}\", {}];", node.id, label, style).unwrap();
+    }
+
+    for (source, target, kind) in &diagram.edges {
+      let style = match kind {
+        StageEdgeKind::From => "",
+        StageEdgeKind::CopyFrom => " [style=dashed, label=\"COPY --from\"]",
+        StageEdgeKind::RunMount => " [style=dotted, label=\"RUN --mount\"]"
+      };
+
+      writeln!(out, "  {} -> {}{};", source, target, style).unwrap();
+    }
+
+    out.push_str("}\n");
+    Ok(out)
+  }
+
+  /// Renders this Dockerfile's stages as a Mermaid flowchart.
+  ///
+  /// Stages are drawn as boxes, external images as cylinders, named build
+  /// contexts as parallelograms, `scratch` as a stadium and images that could
+  /// not be resolved as hexagons. `FROM` dependencies are drawn as solid edges,
+  /// `COPY --from` as dotted edges and `RUN --mount=from=` as thick edges.
+  /// Variables are expanded as in `to_dot`.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   FROM golang:1.15 as build
+  ///   RUN go build
+  ///
+  ///   FROM scratch
+  ///   COPY --from=build /app /app
+  /// "#)?;
+  ///
+  /// let mermaid = dockerfile.to_mermaid(&DiagramOptions::default()).unwrap();
+  /// assert!(mermaid.starts_with("flowchart TD\n"));
+  /// assert!(mermaid.contains(r#"stage_0 -. "COPY --from" .-> stage_1"#));
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn to_mermaid(&self, options: &DiagramOptions) -> Result<String, StageGraphError> {
+    let diagram = Diagram::new(self, options)?;
+
+    let mut out = String::from("flowchart TD\n");
+    for node in &diagram.nodes {
+      let mut label = escape_mermaid(&node.label);
+      for ins in &node.instructions {
+        write!(label, "<br/>{}", escape_mermaid(ins)).unwrap();
+      }
+
+      let (open, close) = match node.kind {
+        NodeKind::Stage => ("[", "]"),
+        NodeKind::Image => ("[(", ")]"),
+        NodeKind::Scratch => ("([", "])"),
+        NodeKind::Context => ("[/", "/]"),
+        NodeKind::Unresolved => ("{{", "}}")
+      };
+
+      writeln!(out, "  {}{}\"{}\"{}", node.id, open, label, close).unwrap();
+    }
+
+    for (source, target, kind) in &diagram.edges {
+      let arrow = match kind {
+        StageEdgeKind::From => "-->",
+        StageEdgeKind::CopyFrom => "-. \"COPY --from\" .->",
+        StageEdgeKind::RunMount => "== \"RUN --mount\" ==>"
+      };
+
+      writeln!(out, "  {} {} {}", source, arrow, target).unwrap();
+    }
+
+    Ok(out)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  fn dockerfile() -> Dockerfile {
+    Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.12 as base
+      RUN apk add "make"
+
+      FROM base as Build
+      RUN --mount=type=cache,target=/cache,from=base \
+        make
+      COPY --from=alpine:3.12 /a /a
+
+      FROM scratch
+      COPY --from=build /app /app
+    "#)).unwrap()
+  }
+
+  #[test]
+  fn test_to_dot() {
+    assert_eq!(dockerfile().to_dot(&DiagramOptions::default()).unwrap(), indoc!(r#"
+      digraph stages {
+        stage_0 [label="base", shape=box, style=rounded];
+        stage_1 [label="Build", shape=box, style=rounded];
+        stage_2 [label="stage 2", shape=box, style=rounded];
+        image_0 [label="alpine:3.12", shape=cylinder];
+        scratch [label="scratch", shape=box, style=dotted];
+        image_0 -> stage_0;
+        stage_0 -> stage_1;
+        stage_0 -> stage_1 [style=dotted, label="RUN --mount"];
+        image_0 -> stage_1 [style=dashed, label="COPY --from"];
+        scratch -> stage_2;
+        stage_1 -> stage_2 [style=dashed, label="COPY --from"];
+      }
+    "#));
+
+    let options = DiagramOptions { show_instructions: true, ..Default::default() };
+    let dot = dockerfile().to_dot(&options).unwrap();
+    assert!(dot.contains(
+      r#"stage_0 [label="base\nFROM alpine:3.12 as base\lRUN apk add \"make\"\l", shape=box, style=rounded];"#
+    ));
+  }
+
+  #[test]
+  fn test_to_mermaid() {
+    assert_eq!(dockerfile().to_mermaid(&DiagramOptions::default()).unwrap(), indoc!(r#"
+      flowchart TD
+        stage_0["base"]
+        stage_1["Build"]
+        stage_2["stage 2"]
+        image_0[("alpine:3.12")]
+        scratch(["scratch"])
+        image_0 --> stage_0
+        stage_0 --> stage_1
+        stage_0 == "RUN --mount" ==> stage_1
+        image_0 -. "COPY --from" .-> stage_1
+        scratch --> stage_2
+        stage_1 -. "COPY --from" .-> stage_2
+    "#));
+
+    let options = DiagramOptions { show_instructions: true, ..Default::default() };
+    let mermaid = dockerfile().to_mermaid(&options).unwrap();
+    assert!(mermaid.contains(
+      r#"stage_1["Build<br/>FROM base as Build<br/>RUN --mount=type=cache,target=/cache,from=base   make<br/>COPY --from=alpine:3.12 /a /a"]"#
+    ));
+    assert!(mermaid.contains(r#"RUN apk add #quot;make#quot;"]"#));
+  }
+
+  #[test]
+  fn test_named_contexts() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM base
+      COPY --from=assets /a /a
+    "#)).unwrap();
+
+    let options = DiagramOptions {
+      build_options: BuildOptions::default()
+        .build_context("base", "docker-image://alpine:3.12")
+        .build_context("assets", "./assets"),
+      ..Default::default()
+    };
+
+    assert_eq!(dockerfile.to_dot(&options).unwrap(), indoc!(r#"
+      digraph stages {
+        stage_0 [label="stage 0", shape=box, style=rounded];
+        context_0 [label="base", shape=folder];
+        context_1 [label="assets", shape=folder];
+        context_0 -> stage_0;
+        context_1 -> stage_0 [style=dashed, label="COPY --from"];
+      }
+    "#));
+  }
+
+  #[test]
+  fn test_variables() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG BASE=alpine:3.12
+      FROM ${BASE} as base
+      ARG NGINX=1.25
+      COPY --from=nginx:${NGINX} /a /a
+
+      FROM scratch
+      ARG SOURCE=base
+      COPY --from=$SOURCE /b /b
+    "#)).unwrap();
+
+    let options = DiagramOptions {
+      build_options: BuildOptions::default().build_arg("NGINX", "1.27"),
+      ..Default::default()
+    };
+
+    assert_eq!(dockerfile.to_dot(&options).unwrap(), indoc!(r#"
+      digraph stages {
+        stage_0 [label="base", shape=box, style=rounded];
+        stage_1 [label="stage 1", shape=box, style=rounded];
+        image_0 [label="alpine:3.12", shape=cylinder];
+        image_1 [label="nginx:1.27", shape=cylinder];
+        scratch [label="scratch", shape=box, style=dotted];
+        image_0 -> stage_0;
+        image_1 -> stage_0 [style=dashed, label="COPY --from"];
+        scratch -> stage_1;
+        stage_0 -> stage_1 [style=dashed, label="COPY --from"];
+      }
+    "#));
+  }
+
+  #[test]
+  fn test_unresolved() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM $undefined
+      COPY --from=${missing:?required} /a /a
+    "#)).unwrap();
+
+    assert_eq!(dockerfile.to_dot(&DiagramOptions::default()).unwrap(), indoc!(r#"
+      digraph stages {
+        stage_0 [label="stage 0", shape=box, style=rounded];
+        unresolved_0 [label="$undefined", shape=box, style=dashed];
+        unresolved_1 [label="${missing:?required}", shape=box, style=dashed];
+        unresolved_0 -> stage_0;
+        unresolved_1 -> stage_0 [style=dashed, label="COPY --from"];
+      }
+    "#));
+
+    let mermaid = dockerfile.to_mermaid(&DiagramOptions::default()).unwrap();
+    assert!(mermaid.contains("  unresolved_0{{\"$undefined\"}}\n"));
+  }
+}
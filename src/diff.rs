@@ -0,0 +1,339 @@
+//! Output of recorded `Splicer` edits as unified diffs or LSP-style text
+//! edits, so that autofixes can be presented as suggestions rather than
+//! rewritten files.
+
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::line_index::{ColumnEncoding, LineIndex, Position};
+use crate::splicer::{Span, Splicer};
+
+/// A single LSP-style text edit, relative to the original document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextEdit {
+  pub range: Range<Position>,
+  pub new_text: String,
+}
+
+/// Returns the offset just past the end of the line containing `offset`,
+/// including its line ending.
+fn line_end(lines: &LineIndex, offset: usize) -> usize {
+  lines.line_start(lines.line(offset) + 1).unwrap_or_else(|| lines.content().len())
+}
+
+/// A changed region in terms of whole lines: `old` lines in the original
+/// document were replaced by `new` lines in the spliced content.
+#[derive(Debug)]
+struct LineRegion {
+  old: Range<usize>,
+  new: Range<usize>,
+}
+
+/// Expands changed regions to whole lines, merging any that share a line.
+fn line_regions(original: &LineIndex, content: &LineIndex, regions: &[(Span, Span)]) -> Vec<LineRegion> {
+  let mut expanded: Vec<(Range<usize>, Range<usize>)> = Vec::new();
+
+  for (old, new) in regions {
+    let old_start = original.line_start(original.line(old.start)).unwrap();
+    let inserted = &content.content()[new.start..new.end];
+
+    // whole lines inserted at the start of a line don't touch that line
+    let old_end = if old.start == old.end && old.start == old_start
+      && (inserted.is_empty() || inserted.ends_with('\n')) {
+      old_start
+    } else if old.end > old_start && original.content().as_bytes()[old.end - 1] == b'\n' {
+      old.end
+    } else {
+      line_end(original, old.end)
+    };
+
+    let new_start = new.start - (old.start - old_start);
+    let new_end = new.end + (old_end - old.end);
+
+    match expanded.last_mut() {
+      Some((last_old, last_new)) if old_start < last_old.end => {
+        last_old.end = old_end;
+        last_new.end = new_end;
+      },
+      _ => expanded.push((old_start..old_end, new_start..new_end))
+    }
+  }
+
+  let to_lines = |lines: &LineIndex, range: &Range<usize>| {
+    let start = lines.line(range.start);
+    let end = if range.end == range.start { start } else { lines.line(range.end - 1) + 1 };
+    start..end
+  };
+
+  expanded
+    .iter()
+    .map(|(old, new)| LineRegion {
+      old: to_lines(original, old),
+      new: to_lines(content, new),
+    })
+    .collect()
+}
+
+/// Formats a hunk header range in the GNU unified diff style.
+fn hunk_range(range: &Range<usize>) -> String {
+  match range.end - range.start {
+    0 => format!("{},0", range.start),
+    1 => format!("{}", range.start + 1),
+    n => format!("{},{}", range.start + 1, n),
+  }
+}
+
+fn write_line(out: &mut String, prefix: char, line: &str) {
+  out.push(prefix);
+  out.push_str(line);
+  if !line.ends_with('\n') {
+    out.push_str("\n\\ No newline at end of file\n");
+  }
+}
+
+impl Splicer {
+  /// Returns all edits applied so far as a list of non-overlapping text edits
+  /// relative to the original document, sorted by position.
+  ///
+  /// Overlapping edits are merged into a single text edit.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse("FROM alpine:3.10\nLABEL a=\"\u{1F600}\" b=c\n")?;
+  /// let label = dockerfile.instructions[1].as_label().unwrap();
+  ///
+  /// let mut splicer = dockerfile.splicer();
+  /// splicer.splice(&label.labels[1].value.span, "d");
+  ///
+  /// let edits = splicer.text_edits(ColumnEncoding::Utf16);
+  /// assert_eq!(edits[0].range, Position::new(1, 15)..Position::new(1, 16));
+  /// assert_eq!(edits[0].new_text, "d");
+  ///
+  /// let edits = splicer.text_edits(ColumnEncoding::Utf8);
+  /// assert_eq!(edits[0].range, Position::new(1, 17)..Position::new(1, 18));
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn text_edits(&self, encoding: ColumnEncoding) -> Vec<TextEdit> {
+    let original = LineIndex::new(self.original());
+
+    self.changed_regions()
+      .into_iter()
+      .map(|(old, new)| TextEdit {
+        range: original.position(old.start, encoding)..original.position(old.end, encoding),
+        new_text: self.content[new.start..new.end].to_string(),
+      })
+      .collect()
+  }
+
+  /// Returns all edits applied so far as a unified diff against the original
+  /// document, with 3 lines of context.
+  ///
+  /// `path` is used in the `---` and `+++` headers as `a/<path>` and
+  /// `b/<path>`. If no changes were made, returns an empty string.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse("FROM alpine:3.10\nRUN echo hi\n")?;
+  /// let from = dockerfile.instructions[0].as_from().unwrap();
+  ///
+  /// let mut splicer = dockerfile.splicer();
+  /// splicer.splice(&from.image.span, "alpine:3.11");
+  ///
+  /// assert_eq!(splicer.unified_diff("Dockerfile"), concat!(
+  ///   "--- a/Dockerfile\n",
+  ///   "+++ b/Dockerfile\n",
+  ///   "@@ -1,2 +1,2 @@\n",
+  ///   "-FROM alpine:3.10\n",
+  ///   "+FROM alpine:3.11\n",
+  ///   " RUN echo hi\n",
+  /// ));
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn unified_diff(&self, path: &str) -> String {
+    self.unified_diff_with_context(path, 3)
+  }
+
+  /// Like `unified_diff(...)`, but with a configurable number of context
+  /// lines.
+  pub fn unified_diff_with_context(&self, path: &str, context: usize) -> String {
+    let original = LineIndex::new(self.original());
+    let content = LineIndex::new(&self.content);
+    let regions = line_regions(&original, &content, &self.changed_regions());
+    if regions.is_empty() {
+      return String::new();
+    }
+
+    let old_lines: Vec<&str> = original.content().split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = content.content().split_inclusive('\n').collect();
+
+    // group regions into hunks whose context lines touch or overlap
+    let mut hunks: Vec<Vec<&LineRegion>> = Vec::new();
+    for region in &regions {
+      match hunks.last_mut() {
+        Some(hunk) if region.old.start <= hunk[hunk.len() - 1].old.end + 2 * context => {
+          hunk.push(region)
+        },
+        _ => hunks.push(vec![region])
+      }
+    }
+
+    let mut out = format!("--- a/{}\n+++ b/{}\n", path, path);
+    for hunk in hunks {
+      let first = hunk[0];
+      let last = hunk[hunk.len() - 1];
+
+      let leading = context.min(first.old.start);
+      let trailing = context.min(old_lines.len() - last.old.end);
+      let old_range = (first.old.start - leading)..(last.old.end + trailing);
+      let new_range = (first.new.start - leading)..(last.new.end + trailing);
+
+      writeln!(out, "@@ -{} +{} @@", hunk_range(&old_range), hunk_range(&new_range)).unwrap();
+
+      let mut cursor = old_range.start;
+      for region in hunk {
+        for line in &old_lines[cursor..region.old.start] {
+          write_line(&mut out, ' ', line);
+        }
+        for line in &old_lines[region.old.clone()] {
+          write_line(&mut out, '-', line);
+        }
+        for line in &new_lines[region.new.clone()] {
+          write_line(&mut out, '+', line);
+        }
+
+        cursor = region.old.end;
+      }
+
+      for line in &old_lines[cursor..old_range.end] {
+        write_line(&mut out, ' ', line);
+      }
+    }
+
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+  use crate::Dockerfile;
+
+  #[test]
+  fn test_unified_diff() {
+    let d = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.10 as build
+      RUN echo one
+      RUN echo two
+      RUN echo three
+      RUN echo four
+      RUN echo five
+      RUN echo six
+      RUN echo seven
+      RUN echo eight
+      CMD ["sh"]
+    "#)).unwrap();
+
+    let mut splicer = d.splicer();
+    let from = d.instructions[0].as_from().unwrap();
+    splicer.splice(&from.image.span, "alpine:3.11");
+    splicer.splice(&from.alias.as_ref().unwrap().span, "builder");
+    splicer.insert_before(&d.instructions[2], "USER nobody").unwrap();
+    splicer.delete_instruction(&d.instructions[9]).unwrap();
+
+    assert_eq!(splicer.unified_diff("Dockerfile"), indoc!(r#"
+      --- a/Dockerfile
+      +++ b/Dockerfile
+      @@ -1,5 +1,6 @@
+      -FROM alpine:3.10 as build
+      +FROM alpine:3.11 as builder
+       RUN echo one
+      +USER nobody
+       RUN echo two
+       RUN echo three
+       RUN echo four
+      @@ -7,4 +8,3 @@
+       RUN echo six
+       RUN echo seven
+       RUN echo eight
+      -CMD ["sh"]
+    "#));
+
+    assert_eq!(splicer.unified_diff_with_context("Dockerfile", 0), indoc!(r#"
+      --- a/Dockerfile
+      +++ b/Dockerfile
+      @@ -1 +1 @@
+      -FROM alpine:3.10 as build
+      +FROM alpine:3.11 as builder
+      @@ -2,0 +3 @@
+      +USER nobody
+      @@ -10 +10,0 @@
+      -CMD ["sh"]
+    "#));
+
+    assert_eq!(d.splicer().unified_diff("Dockerfile"), "");
+  }
+
+  #[test]
+  fn test_unified_diff_no_newline() {
+    let d = Dockerfile::parse("FROM alpine:3.10\nRUN echo hi").unwrap();
+
+    let mut splicer = d.splicer();
+    splicer.splice(&d.instructions[1].span(), "RUN echo hello");
+
+    assert_eq!(splicer.unified_diff("Dockerfile"), indoc!(r#"
+      --- a/Dockerfile
+      +++ b/Dockerfile
+      @@ -1,2 +1,2 @@
+       FROM alpine:3.10
+      -RUN echo hi
+      \ No newline at end of file
+      +RUN echo hello
+      \ No newline at end of file
+    "#));
+  }
+
+  #[test]
+  fn test_text_edits() {
+    let d = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.10
+      LABEL emoji="😀" foo=bar
+      RUN echo hi
+    "#)).unwrap();
+
+    let label = d.instructions[1].as_label().unwrap();
+    let mut splicer = d.splicer();
+    splicer.splice(&label.labels[1].value.span, "baz");
+    splicer.insert_after(&d.instructions[2], "USER nobody").unwrap();
+
+    // overlapping splices are merged into a single edit
+    splicer.splice(&d.instructions[0].span(), "FROM alpine:3.11");
+    splicer.splice(&Span::new(5, 11), "debian");
+
+    assert_eq!(splicer.text_edits(ColumnEncoding::Utf16), vec![
+      TextEdit {
+        range: Position::new(0, 0)..Position::new(0, 16),
+        new_text: "FROM debian:3.11".into(),
+      },
+      TextEdit {
+        range: Position::new(1, 21)..Position::new(1, 24),
+        new_text: "baz".into(),
+      },
+      TextEdit {
+        range: Position::new(3, 0)..Position::new(3, 0),
+        new_text: "USER nobody\n".into(),
+      },
+    ]);
+
+    assert_eq!(
+      splicer.text_edits(ColumnEncoding::Utf8)[1].range,
+      Position::new(1, 23)..Position::new(1, 26)
+    );
+  }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::DigestError;
+
+/// The minimum hex length accepted for unknown algorithms in lenient mode.
+const MIN_LENIENT_LENGTH: usize = 32;
+
+/// Returns the expected hex length of a known digest algorithm.
+fn expected_length(algorithm: &str) -> Option<usize> {
+  match algorithm {
+    "sha256" => Some(64),
+    "sha512" => Some(128),
+    _ => None
+  }
+}
+
+/// Determines if an algorithm matches the OCI digest grammar: alphanumeric
+/// components separated by `+`, `.`, `_` or `-`.
+fn is_valid_algorithm(algorithm: &str) -> bool {
+  !algorithm.is_empty() && algorithm
+    .split(['+', '.', '_', '-'])
+    .all(|component| {
+      !component.is_empty() && component.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    })
+}
+
+/// A content digest, e.g. `sha256:...`, as used to pin images or in
+/// `ADD --checksum=` flags.
+///
+/// ```
+/// use dockerfile_parser::Digest;
+///
+/// let digest = Digest::parse(
+///   "sha256:e7d88de73db3d3fd9b2d63aa7f447a10fd0220b7cbf39803c803f2af9ba256b3"
+/// ).unwrap();
+/// assert_eq!(digest.algorithm, "sha256");
+/// assert_eq!(digest.hex.len(), 64);
+///
+/// assert!(Digest::parse("sha256:e7d88de73db3d3fd").is_err());
+/// assert!(Digest::parse("md5:d41d8cd98f00b204e9800998ecf8427e").is_err());
+/// assert!(Digest::parse_lenient("md5:d41d8cd98f00b204e9800998ecf8427e").is_ok());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest {
+  /// The digest algorithm, e.g. `sha256`
+  pub algorithm: String,
+
+  /// The lowercase hex-encoded digest value
+  pub hex: String
+}
+
+impl Digest {
+  /// Parses a digest of the form `algorithm:hex`. Only the known `sha256` and
+  /// `sha512` algorithms are accepted, and the hex value must be lowercase and
+  /// of the algorithm's expected length.
+  pub fn parse(s: &str) -> Result<Digest, DigestError> {
+    Digest::parse_inner(s, false)
+  }
+
+  /// Parses a digest of the form `algorithm:hex`, also accepting unknown
+  /// algorithms with a hex value of at least 32 characters. Known algorithms
+  /// are validated as in `parse()`.
+  pub fn parse_lenient(s: &str) -> Result<Digest, DigestError> {
+    Digest::parse_inner(s, true)
+  }
+
+  fn parse_inner(s: &str, lenient: bool) -> Result<Digest, DigestError> {
+    let (algorithm, hex) = s
+      .split_once(':')
+      .ok_or_else(|| DigestError::MalformedDigest { digest: s.to_string() })?;
+
+    if !is_valid_algorithm(algorithm) {
+      return Err(DigestError::InvalidAlgorithm { algorithm: algorithm.to_string() });
+    }
+
+    let expected = match expected_length(algorithm) {
+      Some(expected) => Some(expected),
+      None if lenient => None,
+      None => return Err(DigestError::UnsupportedAlgorithm { algorithm: algorithm.to_string() })
+    };
+
+    let invalid = hex
+      .char_indices()
+      .find(|(_, c)| !matches!(c, '0'..='9' | 'a'..='f'));
+    if let Some((i, character)) = invalid {
+      return Err(DigestError::InvalidHex {
+        algorithm: algorithm.to_string(),
+        character,
+        position: algorithm.len() + 1 + i
+      });
+    }
+
+    let valid_length = match expected {
+      Some(expected) => hex.len() == expected,
+      None => hex.len() >= MIN_LENIENT_LENGTH
+    };
+
+    if !valid_length {
+      return Err(DigestError::InvalidLength {
+        algorithm: algorithm.to_string(),
+        expected: expected.unwrap_or(MIN_LENIENT_LENGTH),
+        found: hex.len()
+      });
+    }
+
+    Ok(Digest {
+      algorithm: algorithm.to_string(),
+      hex: hex.to_string()
+    })
+  }
+
+  /// Returns true if this digest uses a known algorithm.
+  pub fn is_known_algorithm(&self) -> bool {
+    expected_length(&self.algorithm).is_some()
+  }
+}
+
+impl FromStr for Digest {
+  type Err = DigestError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Digest::parse(s)
+  }
+}
+
+impl fmt::Display for Digest {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.algorithm, self.hex)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  const SHA256: &str = "e7d88de73db3d3fd9b2d63aa7f447a10fd0220b7cbf39803c803f2af9ba256b3";
+
+  #[test]
+  fn test_digest_parse() {
+    let digest = Digest::parse(&format!("sha256:{}", SHA256)).unwrap();
+    assert_eq!(digest, Digest { algorithm: "sha256".into(), hex: SHA256.into() });
+    assert_eq!(digest.to_string(), format!("sha256:{}", SHA256));
+    assert!(digest.is_known_algorithm());
+
+    let sha512 = format!("sha512:{}", "0".repeat(128));
+    assert_eq!(sha512.parse::<Digest>().unwrap().to_string(), sha512);
+
+    let lenient = Digest::parse_lenient("multihash+base58:0123456789abcdef0123456789abcdef").unwrap();
+    assert_eq!(lenient.algorithm, "multihash+base58");
+    assert!(!lenient.is_known_algorithm());
+  }
+
+  #[test]
+  fn test_digest_parse_errors() {
+    assert_eq!(
+      Digest::parse(SHA256),
+      Err(DigestError::MalformedDigest { digest: SHA256.into() })
+    );
+    assert_eq!(
+      Digest::parse_lenient("SHA256:abcd"),
+      Err(DigestError::InvalidAlgorithm { algorithm: "SHA256".into() })
+    );
+    assert_eq!(
+      Digest::parse_lenient("sha+:abcd"),
+      Err(DigestError::InvalidAlgorithm { algorithm: "sha+".into() })
+    );
+    assert_eq!(
+      Digest::parse("md5:d41d8cd98f00b204e9800998ecf8427e"),
+      Err(DigestError::UnsupportedAlgorithm { algorithm: "md5".into() })
+    );
+    assert_eq!(
+      Digest::parse(&format!("sha256:{}", SHA256.to_uppercase())),
+      Err(DigestError::InvalidHex { algorithm: "sha256".into(), character: 'E', position: 7 })
+    );
+    assert_eq!(
+      Digest::parse(&format!("sha256:{}", &SHA256[1..])),
+      Err(DigestError::InvalidLength { algorithm: "sha256".into(), expected: 64, found: 63 })
+    );
+    assert_eq!(
+      Digest::parse_lenient(&format!("sha512:{}", SHA256)),
+      Err(DigestError::InvalidLength { algorithm: "sha512".into(), expected: 128, found: 64 })
+    );
+    assert_eq!(
+      Digest::parse_lenient("md5:abcd"),
+      Err(DigestError::InvalidLength { algorithm: "md5".into(), expected: 32, found: 4 })
+    );
+  }
+}
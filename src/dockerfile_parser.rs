@@ -8,11 +8,25 @@ use pest::Parser;
 use snafu::ResultExt;
 
 pub use crate::image::*;
+pub use crate::digest::*;
+pub use crate::tag::*;
+pub use crate::expand::*;
 pub use crate::error::*;
 pub use crate::parser::*;
 pub use crate::instructions::*;
 pub use crate::splicer::*;
+pub use crate::line_index::*;
+pub use crate::platform::*;
 pub use crate::stage::*;
+pub use crate::graph::*;
+pub use crate::diagram::*;
+pub use crate::scope::*;
+pub use crate::references::*;
+pub use crate::sources::*;
+pub use crate::pin::*;
+pub use crate::buildkit::*;
+pub use crate::builder::*;
+pub use crate::diff::*;
 
 /// A single Dockerfile instruction.
 ///
@@ -342,12 +356,10 @@ fn parse_dockerfile(input: &str) -> Result<Dockerfile> {
         from_index += 1;
         from_found = true;
       },
-      Instruction::Arg(ref arg) => {
-        // args preceding the first FROM instruction may be substituted into
-        // all subsequent FROM image refs
-        if !from_found {
-          global_args.push(arg.clone());
-        }
+      // args preceding the first FROM instruction may be substituted into
+      // all subsequent FROM image refs
+      Instruction::Arg(ref arg) if !from_found => {
+        global_args.push(arg.clone());
       },
       _ => ()
     };
@@ -380,7 +392,7 @@ impl Dockerfile {
   }
 
   /// Returns a `Stages`, which splits this Dockerfile into its build stages.
-  pub fn stages(&self) -> Stages {
+  pub fn stages(&self) -> Stages<'_> {
     Stages::new(self)
   }
 
@@ -396,18 +408,29 @@ impl Dockerfile {
     Splicer::from(self)
   }
 
+  /// Builds a `LineIndex` over this Dockerfile's content, for converting spans
+  /// to and from line/column positions.
+  ///
+  /// The index should be built once and reused for any number of lookups.
+  pub fn line_index(&self) -> LineIndex<'_> {
+    LineIndex::new(&self.content)
+  }
+
+  /// Converts this Dockerfile into a BuildKit-compatible parse tree.
+  ///
+  /// The returned root node mirrors BuildKit's `parser.Node`, containing one
+  /// child node per instruction.
+  pub fn to_buildkit_node(&self) -> BuildkitNode {
+    BuildkitNode::from_dockerfile(self)
+  }
+
   /// Attempts to find a global argument by name. Returns None if no global ARG
   /// with the given name exists.
   pub fn get_global_arg(&self, name: &str) -> Option<&ArgInstruction> {
     for ins in &self.instructions {
       match ins {
-        Instruction::Arg(a) => {
-          if a.name.content == name {
-            return Some(a);
-          } else {
-            continue
-          }
-        },
+        Instruction::Arg(a) if a.name.content == name => return Some(a),
+        Instruction::Arg(_) => continue,
         _ => return None
       }
     }
@@ -0,0 +1,530 @@
+//! In-place editing of a parsed `Dockerfile`.
+//!
+//! Unlike the `Splicer`, which only produces a new string, these operations
+//! update `Dockerfile::content` along with the affected instructions and the
+//! spans of every following instruction, so a chain of edits can be applied
+//! without re-parsing the whole document between steps. Only the edited
+//! instruction itself is re-parsed.
+
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::error::*;
+use crate::instructions::*;
+use crate::splicer::Span;
+use crate::util::*;
+
+/// Types containing spans that can be shifted by a fixed offset.
+pub(crate) trait ShiftSpans {
+  fn shift_spans(&mut self, offset: isize);
+}
+
+impl ShiftSpans for Span {
+  fn shift_spans(&mut self, offset: isize) {
+    self.start = (self.start as isize + offset) as usize;
+    self.end = (self.end as isize + offset) as usize;
+  }
+}
+
+impl<T: ShiftSpans> ShiftSpans for Vec<T> {
+  fn shift_spans(&mut self, offset: isize) {
+    for item in self {
+      item.shift_spans(offset);
+    }
+  }
+}
+
+impl<T: ShiftSpans> ShiftSpans for Option<T> {
+  fn shift_spans(&mut self, offset: isize) {
+    if let Some(item) = self {
+      item.shift_spans(offset);
+    }
+  }
+}
+
+/// Implements `ShiftSpans` for a struct by shifting each of the listed fields.
+macro_rules! impl_shift_spans {
+  ($struct:ident, $($field:ident),+) => {
+    impl ShiftSpans for $struct {
+      fn shift_spans(&mut self, offset: isize) {
+        $(self.$field.shift_spans(offset);)+
+      }
+    }
+  };
+}
+
+impl_shift_spans!(SpannedString, span);
+impl_shift_spans!(SpannedComment, span);
+impl_shift_spans!(BreakableString, span, components);
+impl_shift_spans!(StringArray, span, elements);
+impl_shift_spans!(Heredoc, span);
+impl_shift_spans!(FromFlag, span, name, value);
+impl_shift_spans!(FromInstruction, span, flags, image, alias);
+impl_shift_spans!(ArgInstruction, span, name, value);
+impl_shift_spans!(Label, span, name, value);
+impl_shift_spans!(LabelInstruction, span, labels);
+impl_shift_spans!(RunOption, span, name, value);
+impl_shift_spans!(RunInstruction, span, options, expr);
+impl_shift_spans!(EntrypointInstruction, span, expr);
+impl_shift_spans!(CmdInstruction, span, expr);
+impl_shift_spans!(CopyFlag, span, name, value);
+impl_shift_spans!(CopyInstruction, span, flags, sources, destination);
+impl_shift_spans!(EnvVar, span, key, value);
+impl_shift_spans!(EnvInstruction, span, vars);
+impl_shift_spans!(MiscInstruction, span, instruction, arguments);
+
+impl ShiftSpans for BreakableStringComponent {
+  fn shift_spans(&mut self, offset: isize) {
+    match self {
+      BreakableStringComponent::String(s) => s.shift_spans(offset),
+      BreakableStringComponent::Comment(c) => c.shift_spans(offset),
+    }
+  }
+}
+
+impl ShiftSpans for ShellOrExecExpr {
+  fn shift_spans(&mut self, offset: isize) {
+    match self {
+      ShellOrExecExpr::Shell(s) => s.shift_spans(offset),
+      ShellOrExecExpr::ShellWithHeredoc(s, h) => {
+        s.shift_spans(offset);
+        h.shift_spans(offset);
+      },
+      ShellOrExecExpr::Exec(a) => a.shift_spans(offset),
+    }
+  }
+}
+
+impl ShiftSpans for SourceType {
+  fn shift_spans(&mut self, offset: isize) {
+    match self {
+      SourceType::FileName(s) | SourceType::FileContents(s) => s.shift_spans(offset),
+    }
+  }
+}
+
+impl ShiftSpans for Instruction {
+  fn shift_spans(&mut self, offset: isize) {
+    match self {
+      Instruction::From(i) => i.shift_spans(offset),
+      Instruction::Arg(i) => i.shift_spans(offset),
+      Instruction::Label(i) => i.shift_spans(offset),
+      Instruction::Run(i) => i.shift_spans(offset),
+      Instruction::Entrypoint(i) => i.shift_spans(offset),
+      Instruction::Cmd(i) => i.shift_spans(offset),
+      Instruction::Copy(i) => i.shift_spans(offset),
+      Instruction::Env(i) => i.shift_spans(offset),
+      Instruction::Misc(i) => i.shift_spans(offset),
+    }
+  }
+}
+
+/// Parses a string containing exactly one instruction, shifting its spans as
+/// if it were located at `offset` in a larger document.
+fn parse_instruction_at(text: &str, offset: usize) -> Result<Instruction> {
+  let mut parsed = Dockerfile::parse(text)?;
+  if parsed.instructions.len() != 1 {
+    return Err(Error::EditError {
+      message: format!(
+        "expected exactly one instruction, found {}: {:?}",
+        parsed.instructions.len(), text
+      )
+    });
+  }
+
+  let mut instruction = parsed.instructions.remove(0);
+  instruction.shift_spans(offset as isize);
+
+  Ok(instruction)
+}
+
+/// Returns the line ending used by the given document.
+pub(crate) fn line_ending(content: &str) -> &'static str {
+  if content.contains("\r\n") {
+    "\r\n"
+  } else {
+    "\n"
+  }
+}
+
+/// Returns the indentation preceding the given offset, if only whitespace
+/// precedes it on its line.
+pub(crate) fn indentation_at(content: &str, offset: usize) -> &str {
+  let line_start = content[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+  let prefix = &content[line_start..offset];
+
+  if prefix.chars().all(|c| c == ' ' || c == '\t') {
+    prefix
+  } else {
+    ""
+  }
+}
+
+/// Expands a span to cover its full line(s), including leading indentation
+/// and the trailing line ending, if nothing else shares those lines.
+pub(crate) fn full_line_span(content: &str, span: &Span) -> Span {
+  let indent = indentation_at(content, span.start);
+  let start = span.start - indent.len();
+
+  let rest = &content[span.end..];
+  let trailing_ws = rest.len() - rest.trim_start_matches([' ', '\t']).len();
+  let after = &rest[trailing_ws..];
+  let end = if after.starts_with("\r\n") {
+    span.end + trailing_ws + 2
+  } else if after.starts_with('\n') {
+    span.end + trailing_ws + 1
+  } else if after.is_empty() {
+    span.end + trailing_ws
+  } else {
+    span.end
+  };
+
+  Span::new(start, end)
+}
+
+impl Dockerfile {
+  fn check_index(&self, index: usize) -> Result<()> {
+    if index >= self.instructions.len() {
+      return Err(Error::EditError {
+        message: format!(
+          "instruction index {} out of range ({} instructions)",
+          index, self.instructions.len()
+        )
+      });
+    }
+
+    Ok(())
+  }
+
+  /// Replaces `span` in `content`, shifting the spans of all instructions at or
+  /// after `first_shifted`.
+  fn splice_content(&mut self, span: Span, replacement: &str, first_shifted: usize) {
+    let offset = replacement.len() as isize - (span.end - span.start) as isize;
+    self.content.replace_range(span.start..span.end, replacement);
+
+    for ins in self.instructions.iter_mut().skip(first_shifted) {
+      ins.shift_spans(offset);
+    }
+  }
+
+  /// Recomputes `FROM` indices and global args after the instruction list has
+  /// changed.
+  fn reindex(&mut self) {
+    let mut from_index = 0;
+    let mut from_found = false;
+    self.global_args.clear();
+
+    for ins in &mut self.instructions {
+      match ins {
+        Instruction::From(from) => {
+          from.index = from_index;
+          from_index += 1;
+          from_found = true;
+        },
+        Instruction::Arg(arg) if !from_found => self.global_args.push(arg.clone()),
+        _ => ()
+      }
+    }
+  }
+
+  /// Inserts a new instruction, given as Dockerfile text, before the
+  /// instruction at `index`. If `index` is equal to the number of
+  /// instructions, the new instruction is appended to the end of the file.
+  ///
+  /// The new instruction is placed on its own line using the indentation and
+  /// line endings of the surrounding document.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::Dockerfile;
+  ///
+  /// let mut dockerfile = Dockerfile::parse("FROM alpine:3.12\nCMD [\"sh\"]\n")?;
+  /// dockerfile.insert_instruction(1, "USER nobody")?;
+  ///
+  /// assert_eq!(dockerfile.content, "FROM alpine:3.12\nUSER nobody\nCMD [\"sh\"]\n");
+  /// assert_eq!(dockerfile, Dockerfile::parse(&dockerfile.content)?);
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn insert_instruction(&mut self, index: usize, text: &str) -> Result<()> {
+    if index > self.instructions.len() {
+      return Err(Error::EditError {
+        message: format!(
+          "insert index {} out of range ({} instructions)",
+          index, self.instructions.len()
+        )
+      });
+    }
+
+    let newline = line_ending(&self.content);
+    let text = text.trim();
+
+    let (position, prefix, suffix) = if index < self.instructions.len() {
+      let start = self.instructions[index].span().start;
+      let indent = indentation_at(&self.content, start).to_string();
+      (start, String::new(), format!("{}{}", newline, indent))
+    } else {
+      let prefix = if self.content.is_empty() || self.content.ends_with('\n') {
+        ""
+      } else {
+        newline
+      };
+      (self.content.len(), prefix.to_string(), newline.to_string())
+    };
+
+    let instruction = parse_instruction_at(text, position + prefix.len())?;
+    let replacement = format!("{}{}{}", prefix, text, suffix);
+    self.splice_content(Span::new(position, position), &replacement, index);
+    self.instructions.insert(index, instruction);
+    self.reindex();
+
+    Ok(())
+  }
+
+  /// Removes the instruction at `index`, along with its line (including
+  /// indentation and trailing line ending) if no other content shares it.
+  ///
+  /// Returns the removed instruction, with its spans relative to the original
+  /// content.
+  pub fn remove_instruction(&mut self, index: usize) -> Result<Instruction> {
+    self.check_index(index)?;
+
+    let span = full_line_span(&self.content, &self.instructions[index].span());
+    let removed = self.instructions.remove(index);
+    self.splice_content(span, "", index);
+    self.reindex();
+
+    Ok(removed)
+  }
+
+  /// Replaces the instruction at `index` with a new instruction, given as
+  /// Dockerfile text.
+  ///
+  /// Returns the replaced instruction, with its spans relative to the original
+  /// content.
+  pub fn replace_instruction(&mut self, index: usize, text: &str) -> Result<Instruction> {
+    self.check_index(index)?;
+
+    let span = self.instructions[index].span();
+    let text = text.trim();
+    let instruction = parse_instruction_at(text, span.start)?;
+
+    self.splice_content(span, text, index + 1);
+    let old = std::mem::replace(&mut self.instructions[index], instruction);
+    self.reindex();
+
+    Ok(old)
+  }
+
+  /// Replaces an arbitrary span within a single instruction, re-parsing only
+  /// that instruction.
+  ///
+  /// Returns an error if the span is invalid (reversed, out of bounds or not on
+  /// a character boundary), is not contained within a single instruction, or
+  /// if the edited instruction is no longer valid.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let mut dockerfile = Dockerfile::parse("FROM alpine:3.12\nRUN echo hi\n")?;
+  /// let span = dockerfile.instructions[1].as_run().unwrap().as_shell().unwrap().span;
+  /// dockerfile.replace_span(&span, "echo hello")?;
+  ///
+  /// assert_eq!(dockerfile.content, "FROM alpine:3.12\nRUN echo hello\n");
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn replace_span(&mut self, span: &Span, replacement: &str) -> Result<()> {
+    let valid = span.start <= span.end
+      && span.end <= self.content.len()
+      && self.content.is_char_boundary(span.start)
+      && self.content.is_char_boundary(span.end);
+
+    if !valid {
+      return Err(Error::EditError {
+        message: format!("span {:?} is not a valid span of the content", span)
+      });
+    }
+
+    let index = self.instructions
+      .iter()
+      .position(|ins| {
+        let ins_span = ins.span();
+        ins_span.start <= span.start && span.end <= ins_span.end
+      })
+      .ok_or_else(|| Error::EditError {
+        message: format!("span {:?} is not within a single instruction", span)
+      })?;
+
+    let ins_span = self.instructions[index].span();
+    let text = format!(
+      "{}{}{}",
+      &self.content[ins_span.start..span.start],
+      replacement,
+      &self.content[span.end..ins_span.end]
+    );
+
+    let instruction = parse_instruction_at(&text, ins_span.start)?;
+    self.splice_content(ins_span, &text, index + 1);
+    self.instructions[index] = instruction;
+    self.reindex();
+
+    Ok(())
+  }
+
+  fn stage_from(&self, stage_index: usize) -> Result<&FromInstruction> {
+    self.instructions
+      .iter()
+      .filter_map(|ins| ins.as_from())
+      .nth(stage_index)
+      .ok_or_else(|| Error::EditError {
+        message: format!("no FROM instruction for stage {}", stage_index)
+      })
+  }
+
+  /// Sets the image of the `FROM` instruction for the given stage index.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let mut dockerfile = Dockerfile::parse("FROM alpine:3.12 as build\n")?;
+  /// dockerfile.set_from_image(0, "alpine:3.13")?;
+  ///
+  /// let from = dockerfile.instructions[0].as_from().unwrap();
+  /// assert_eq!(from.image_parsed.tag.as_deref(), Some("3.13"));
+  /// assert_eq!(dockerfile.content, "FROM alpine:3.13 as build\n");
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn set_from_image(&mut self, stage_index: usize, image: &str) -> Result<()> {
+    let span = self.stage_from(stage_index)?.image.span;
+
+    self.replace_span(&span, image)
+  }
+
+  /// Sets (or removes) the alias of the `FROM` instruction for the given
+  /// stage index.
+  pub fn set_from_alias(&mut self, stage_index: usize, alias: Option<&str>) -> Result<()> {
+    let from = self.stage_from(stage_index)?;
+
+    match (&from.alias, alias) {
+      (Some(old), Some(new)) => {
+        let span = old.span;
+        self.replace_span(&span, new)
+      },
+      (Some(_), None) => {
+        let span = Span::new(from.image.span.end, from.span.end);
+        self.replace_span(&span, "")
+      },
+      (None, Some(new)) => {
+        let end = from.image.span.end;
+        self.replace_span(&Span::new(end, end), &format!(" AS {}", new))
+      },
+      (None, None) => Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  fn assert_consistent(dockerfile: &Dockerfile) {
+    assert_eq!(dockerfile, &Dockerfile::parse(&dockerfile.content).unwrap());
+  }
+
+  #[test]
+  fn test_edit_chain() -> Result<()> {
+    let mut dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG base=alpine:3.12
+      FROM $base as build
+      RUN apk add --no-cache \
+        curl
+
+      FROM build
+        ENV foo=bar
+        CMD ["sh"]
+    "#))?;
+
+    dockerfile.insert_instruction(0, "ARG tag=latest")?;
+    assert_consistent(&dockerfile);
+    assert_eq!(dockerfile.global_args.len(), 2);
+
+    dockerfile.insert_instruction(6, "USER nobody")?;
+    assert_consistent(&dockerfile);
+
+    dockerfile.set_from_image(0, "alpine:$tag")?;
+    assert_consistent(&dockerfile);
+
+    dockerfile.remove_instruction(3)?;
+    assert_consistent(&dockerfile);
+
+    dockerfile.replace_instruction(4, "ENV foo=\"bar baz\"")?;
+    assert_consistent(&dockerfile);
+
+    dockerfile.set_from_alias(1, Some("final"))?;
+    assert_consistent(&dockerfile);
+
+    dockerfile.insert_instruction(dockerfile.instructions.len(), "EXPOSE 80")?;
+    assert_consistent(&dockerfile);
+
+    assert_eq!(dockerfile.content, indoc!(r#"
+      ARG tag=latest
+      ARG base=alpine:3.12
+      FROM alpine:$tag as build
+
+      FROM build AS final
+        ENV foo="bar baz"
+        USER nobody
+        CMD ["sh"]
+      EXPOSE 80
+    "#));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_edit_heredoc() -> Result<()> {
+    let mut dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.12
+      RUN <<EOF
+      echo hello
+      EOF
+    "#))?;
+
+    dockerfile.insert_instruction(1, "WORKDIR /app")?;
+    assert_consistent(&dockerfile);
+
+    dockerfile.set_from_alias(0, Some("base"))?;
+    dockerfile.set_from_alias(0, None)?;
+    assert_consistent(&dockerfile);
+    assert_eq!(dockerfile.content, "FROM alpine:3.12\nWORKDIR /app\nRUN <<EOF\necho hello\nEOF\n");
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_edit_errors() -> Result<()> {
+    let mut dockerfile = Dockerfile::parse("FROM alpine:3.12\n")?;
+
+    assert!(dockerfile.remove_instruction(1).is_err());
+    assert!(dockerfile.insert_instruction(2, "RUN foo").is_err());
+    assert!(dockerfile.insert_instruction(0, "RUN foo\nRUN bar").is_err());
+    assert!(dockerfile.set_from_image(1, "alpine").is_err());
+    assert!(dockerfile.replace_span(&Span::new(0, 17), "").is_err());
+    assert!(dockerfile.replace_span(&Span::new(10, 5), "").is_err());
+    assert!(dockerfile.replace_span(&Span::new(20, 30), "").is_err());
+
+    // failed edits leave the dockerfile untouched
+    assert_eq!(dockerfile, Dockerfile::parse("FROM alpine:3.12\n")?);
+
+    let mut dockerfile = Dockerfile::parse("FROM alpine\nLABEL name=café\n")?;
+    assert!(matches!(
+      dockerfile.replace_span(&Span::new(26, 27), "e"),
+      Err(Error::EditError { .. })
+    ));
+    assert_eq!(dockerfile.content, "FROM alpine\nLABEL name=café\n");
+
+    Ok(())
+  }
+}
@@ -3,7 +3,9 @@
 use pest::iterators::Pair;
 use snafu::Snafu;
 
+use crate::image::ImageRefComponent;
 use crate::parser::*;
+use crate::splicer::{Span, SpliceEdit};
 
 /// A Dockerfile parsing error.
 #[derive(Debug, Snafu)]
@@ -48,6 +50,258 @@ pub enum Error {
   ConversionError {
     from: String,
     to: String
+  },
+
+  #[snafu(display(
+    "unable to edit Dockerfile: {}", message
+  ))]
+  EditError {
+    message: String
+  }
+}
+
+/// An error encountered while splicing content.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum SpliceError {
+  #[snafu(display(
+    "span {:?} is out of range for content of length {}", span, len
+  ))]
+  OutOfRange {
+    span: Span,
+    len: usize
+  },
+
+  #[snafu(display(
+    "span {:?} does not fall on a character boundary", span
+  ))]
+  NotCharBoundary {
+    span: Span
+  },
+
+  #[snafu(display(
+    "edit {:?} conflicts with edit {:?}", first, second
+  ))]
+  Conflict {
+    first: SpliceEdit,
+    second: SpliceEdit
+  }
+}
+
+/// An error encountered while expanding variable references. Spans are byte
+/// offsets into the expanded string.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum SubstitutionError {
+  #[snafu(display(
+    "variable '{}' is not defined", name
+  ))]
+  UndefinedVariable {
+    name: String,
+    span: Span
+  },
+
+  #[snafu(display(
+    "{}: {}", name, message
+  ))]
+  RequiredVariable {
+    name: String,
+    message: String,
+    span: Span
+  },
+
+  #[snafu(display(
+    "bad substitution at {:?}: {}", span, message
+  ))]
+  BadSubstitution {
+    message: String,
+    span: Span
+  }
+}
+
+/// An error encountered while building or traversing a stage graph.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum StageGraphError {
+  #[snafu(display(
+    "reference to undefined stage '{}' at {:?}", name, span
+  ))]
+  UndefinedStage {
+    name: String,
+    span: Span
+  },
+
+  #[snafu(display(
+    "target stage '{}' does not exist", name
+  ))]
+  UndefinedTarget {
+    name: String
+  },
+
+  #[snafu(display(
+    "stages form a cycle: {:?}", stages
+  ))]
+  StageCycle {
+    stages: Vec<usize>
+  }
+}
+
+/// An error encountered while pruning a Dockerfile or extracting a stage.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum PruneError {
+  #[snafu(display("{}", source))]
+  PruneGraphError {
+    source: StageGraphError
+  },
+
+  #[snafu(display("unable to remove stages: {}", source))]
+  PruneSpliceError {
+    source: SpliceError
+  }
+}
+
+/// An error encountered while strictly parsing an image reference. Positions
+/// are byte offsets into the reference string.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum ImageRefError {
+  #[snafu(display(
+    "invalid image reference: {} at position {}: {}", component, position, message
+  ))]
+  InvalidComponent {
+    component: ImageRefComponent,
+    position: usize,
+    message: String
+  },
+
+  #[snafu(display(
+    "invalid image reference: repository name must not exceed 255 characters (found {})", length
+  ))]
+  NameTooLong {
+    length: usize
+  }
+}
+
+/// An error encountered while parsing a content digest.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum DigestError {
+  #[snafu(display("digest '{}' must be of the form <algorithm>:<hex>", digest))]
+  MalformedDigest {
+    digest: String
+  },
+
+  #[snafu(display("invalid digest algorithm '{}'", algorithm))]
+  InvalidAlgorithm {
+    algorithm: String
+  },
+
+  #[snafu(display("unsupported digest algorithm '{}'", algorithm))]
+  UnsupportedAlgorithm {
+    algorithm: String
+  },
+
+  #[snafu(display(
+    "invalid {} digest: unexpected character '{}' at position {}", algorithm, character, position
+  ))]
+  InvalidHex {
+    algorithm: String,
+    character: char,
+    position: usize
+  },
+
+  #[snafu(display(
+    "invalid {} digest: expected {} hex characters, found {}", algorithm, expected, found
+  ))]
+  InvalidLength {
+    algorithm: String,
+    expected: usize,
+    found: usize
+  }
+}
+
+/// An error encountered while parsing a lockfile. Line numbers are 1-based.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum LockfileError {
+  #[snafu(display("line {}: expected '<image> <digest>'", line))]
+  MalformedEntry {
+    line: usize
+  },
+
+  #[snafu(display("line {}: {}", line, source))]
+  InvalidLockedImage {
+    line: usize,
+    source: ImageRefError
+  },
+
+  #[snafu(display("line {}: {}", line, source))]
+  InvalidLockedDigest {
+    line: usize,
+    source: DigestError
+  },
+
+  #[snafu(display("line {}: duplicate entry for image {}", line, image))]
+  DuplicateEntry {
+    line: usize,
+    image: String
+  }
+}
+
+/// An error encountered while renaming a stage.
+#[derive(Debug, Clone, PartialEq, Eq, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum RenameStageError {
+  #[snafu(display(
+    "stage '{}' does not exist", name
+  ))]
+  UnknownStage {
+    name: String
+  },
+
+  #[snafu(display(
+    "'{}' is not a valid stage name", name
+  ))]
+  InvalidStageName {
+    name: String
+  },
+
+  #[snafu(display(
+    "stage name '{}' is already used by stage {}", name, stage
+  ))]
+  StageNameCollision {
+    name: String,
+    stage: usize
+  },
+
+  #[snafu(display(
+    "reference to image '{}' at {:?} would refer to the renamed stage", name, span
+  ))]
+  ImageNameCollision {
+    name: String,
+    span: Span
+  },
+
+  #[snafu(display(
+    "unable to build stage graph: {}", source
+  ))]
+  RenameGraphError {
+    source: StageGraphError
+  },
+
+  #[snafu(display(
+    "unable to rewrite stage references: {}", source
+  ))]
+  RenameSpliceError {
+    source: SpliceError
+  },
+
+  #[snafu(display(
+    "stage is referenced via variables that cannot be rewritten at {:?}", spans
+  ))]
+  UnrewritableReferences {
+    spans: Vec<Span>
   }
 }
 
@@ -0,0 +1,901 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::error::SubstitutionError;
+use crate::splicer::{Span, Splicer};
+
+/// Given a map of key/value pairs, expands variable references in the input
+/// string following BuildKit's rules.
+///
+/// Supported forms are:
+///  * `$name` and `${name}`
+///  * `${name:-word}` / `${name-word}`: `word` if `name` is unset or empty
+///    (or only if unset, without the colon)
+///  * `${name:+word}` / `${name+word}`: `word` if `name` is set and not
+///    empty (or only if set, without the colon), otherwise an empty string
+///  * `${name:?message}` / `${name?message}`: an error if `name` is unset or
+///    empty (or only if unset, without the colon)
+///  * `${name#pattern}` / `${name##pattern}`: removes the shortest / longest
+///    matching prefix
+///  * `${name%pattern}` / `${name%%pattern}`: removes the shortest / longest
+///    matching suffix
+///  * `${name/pattern/replacement}` / `${name//pattern/replacement}`: replaces
+///    the first / every longest match
+///
+/// Patterns may use the `*`, `?` and `[...]` shell wildcards. Words, messages,
+/// patterns and replacements may themselves contain variable references, which
+/// are only evaluated if used. `\$` produces a literal `$`; within `${...}` a
+/// backslash escapes any character.
+///
+/// Quotes are handled as in a shell and removed: nothing is expanded within
+/// single quotes, e.g. `'$name'` produces `$name`, while variables within
+/// double quotes are expanded. Within double quotes, a backslash only escapes
+/// `"`, `$` and `\`. `\'` and `\"` produce literal quotes.
+///
+/// Expansion happens in a single pass: variable values are inserted as-is and
+/// are never expanded themselves. As in Docker, references to unset variables
+/// expand to an empty string; see `expand_vars_strict(...)` to treat them as
+/// errors instead.
+///
+/// The names of all referenced variables that are set are added to
+/// `used_vars`.
+///
+/// # Example
+/// ```
+/// use std::collections::{HashMap, HashSet};
+/// use dockerfile_parser::*;
+///
+/// let mut vars = HashMap::new();
+/// vars.insert("image", "docker.io/library/alpine");
+/// vars.insert("version", "v3.12.1");
+///
+/// let mut used_vars = HashSet::new();
+/// assert_eq!(
+///   expand_vars("${image##*/}:${version#v}-${variant:-slim}", &vars, &mut used_vars),
+///   Ok("alpine:3.12.1-slim".to_string())
+/// );
+/// assert_eq!(used_vars.len(), 2);
+///
+/// assert_eq!(
+///   expand_vars("$image$variant", &vars, &mut used_vars),
+///   Ok("docker.io/library/alpine".to_string())
+/// );
+///
+/// assert_eq!(
+///   expand_vars("${variant:?must be set}", &vars, &mut used_vars),
+///   Err(SubstitutionError::RequiredVariable {
+///     name: "variant".into(),
+///     message: "must be set".into(),
+///     span: Span::new(0, 23),
+///   })
+/// );
+/// ```
+pub fn expand_vars<K, V>(
+  s: &str,
+  vars: &HashMap<K, V>,
+  used_vars: &mut HashSet<String>
+) -> Result<String, SubstitutionError>
+where
+  K: Borrow<str> + Hash + Eq,
+  V: AsRef<str>
+{
+  let lookup = |name: &str| vars.get(name).map(|v| v.as_ref().to_string());
+
+  expand(s, &lookup, used_vars, Some(&mut Vec::new()), true)
+}
+
+/// Like `expand_vars(...)`, but a reference to an unset variable without a
+/// `-`, `+` or `?` operator returns a `SubstitutionError::UndefinedVariable`
+/// rather than expanding to an empty string.
+///
+/// # Example
+/// ```
+/// use std::collections::{HashMap, HashSet};
+/// use dockerfile_parser::*;
+///
+/// let mut vars = HashMap::new();
+/// vars.insert("image", "alpine");
+///
+/// let mut used_vars = HashSet::new();
+/// assert_eq!(
+///   expand_vars_strict("$image:$tag", &vars, &mut used_vars),
+///   Err(SubstitutionError::UndefinedVariable {
+///     name: "tag".into(),
+///     span: Span::new(7, 11),
+///   })
+/// );
+/// ```
+pub fn expand_vars_strict<K, V>(
+  s: &str,
+  vars: &HashMap<K, V>,
+  used_vars: &mut HashSet<String>
+) -> Result<String, SubstitutionError>
+where
+  K: Borrow<str> + Hash + Eq,
+  V: AsRef<str>
+{
+  let lookup = |name: &str| vars.get(name).map(|v| v.as_ref().to_string());
+
+  expand(s, &lookup, used_vars, None, true)
+}
+
+/// Given a map of key/value pairs, perform variable substitution on a given
+/// input string. `max_recursion_depth` controls the maximum allowed recursion
+/// depth if variables refer to other strings themselves containing variable
+/// references. A small number but reasonable is recommended by default, e.g.
+/// 16.
+/// If None is returned, substitution was impossible, either because a
+/// referenced variable did not exist, or recursion depth was exceeded.
+///
+/// Only `$name` and `${name}` are supported, and unlike Docker, variable
+/// values are themselves substituted recursively.
+#[deprecated(note = "use `expand_vars(...)` or `expand_vars_strict(...)`, which follow BuildKit's rules")]
+pub fn substitute<'b>(
+  s: &str,
+  vars: &'b HashMap<&'b str, &'b str>,
+  used_vars: &mut HashSet<String>,
+  max_recursion_depth: u8
+) -> Option<String> {
+  lazy_static! {
+    static ref VAR: Regex = Regex::new(r"\$(?:([A-Za-z0-9_]+)|\{([A-Za-z0-9_]+)\})").unwrap();
+  }
+
+  let mut splicer = Splicer::from_str(s);
+
+  for caps in VAR.captures_iter(s) {
+    if max_recursion_depth == 0 {
+      // can't substitute, so give up
+      return None;
+    }
+
+    let full_range = caps.get(0)?.range();
+    let var_name = caps.get(1).or_else(|| caps.get(2))?;
+    let var_content = vars.get(var_name.as_str())?;
+    #[allow(deprecated)]
+    let substituted_content = substitute(
+      var_content,
+      vars,
+      used_vars,
+      max_recursion_depth.saturating_sub(1)
+    )?;
+    used_vars.insert(var_name.as_str().to_string());
+
+    // splice the substituted content back into the output string
+    splicer.splice(&Span::new(full_range.start, full_range.end), &substituted_content);
+  }
+
+  Some(splicer.content)
+}
+
+/// Returns the names of all variables the input string may refer to,
+/// regardless of which variables are set.
+pub(crate) fn referenced_vars(s: &str) -> HashSet<String> {
+  // words are evaluated lazily, so e.g. `${a:-$b}` only refers to `b` when `a`
+  // is unset, and `${a:+$b}` only when `a` is set: try both
+  let mut vars = HashSet::new();
+  let mut undefined = Vec::new();
+  let _ = expand(s, &|_| None, &mut vars, Some(&mut undefined), true);
+  let _ = expand(s, &|_| Some("x".to_string()), &mut vars, None, true);
+
+  vars.extend(undefined.into_iter().map(|(name, _)| name));
+  vars
+}
+
+/// Expands variable references in the input string using the given lookup
+/// function.
+///
+/// If `undefined` is set, references to unset variables expand to an empty
+/// string (as Docker does) and are recorded rather than returning an error.
+/// If `quotes` is false, quotes are treated as ordinary characters.
+pub(crate) fn expand(
+  s: &str,
+  lookup: &dyn Fn(&str) -> Option<String>,
+  used_vars: &mut HashSet<String>,
+  undefined: Option<&mut Vec<(String, Span)>>,
+  quotes: bool
+) -> Result<String, SubstitutionError> {
+  let mut expander = Expander {
+    input: s,
+    chars: s.char_indices().collect(),
+    pos: 0,
+    lookup,
+    used_vars,
+    undefined,
+    quotes,
+  };
+
+  let word = expander.word(&[], false, true)?;
+
+  Ok(word.into_iter().map(|(c, _)| c).collect())
+}
+
+/// A character of an expanded word, and whether it must be matched literally
+/// if the word is used as a pattern.
+type WordChar = (char, bool);
+
+struct Expander<'a> {
+  input: &'a str,
+  chars: Vec<(usize, char)>,
+  pos: usize,
+  lookup: &'a dyn Fn(&str) -> Option<String>,
+  used_vars: &'a mut HashSet<String>,
+  undefined: Option<&'a mut Vec<(String, Span)>>,
+  quotes: bool,
+}
+
+impl<'a> Expander<'a> {
+  fn peek(&self) -> Option<char> {
+    self.chars.get(self.pos).map(|(_, c)| *c)
+  }
+
+  fn peek_at(&self, n: usize) -> Option<char> {
+    self.chars.get(self.pos + n).map(|(_, c)| *c)
+  }
+
+  /// The byte offset of the current position.
+  fn offset(&self) -> usize {
+    self.chars.get(self.pos).map(|(i, _)| *i).unwrap_or(self.input.len())
+  }
+
+  fn eat(&mut self, c: char) -> bool {
+    if self.peek() == Some(c) {
+      self.pos += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn get(&mut self, name: &str) -> Option<String> {
+    let value = (self.lookup)(name);
+    if value.is_some() {
+      self.used_vars.insert(name.to_string());
+    }
+
+    value
+  }
+
+  /// Handles a reference to an unset variable, either recording it (and
+  /// expanding to an empty string) or returning an error.
+  fn undefined(&mut self, name: &str, span: Span) -> Result<String, SubstitutionError> {
+    match &mut self.undefined {
+      Some(undefined) => {
+        undefined.push((name.to_string(), span));
+        Ok(String::new())
+      },
+      None => Err(SubstitutionError::UndefinedVariable {
+        name: name.to_string(),
+        span
+      })
+    }
+  }
+
+  /// Expands a word up to the end of input or any of the given (unescaped)
+  /// stop characters. If `evaluate` is false, the word is only checked for
+  /// syntax errors and variables are not looked up.
+  fn word(
+    &mut self, stops: &[char], nested: bool, evaluate: bool
+  ) -> Result<Vec<WordChar>, SubstitutionError> {
+    let mut out = Vec::new();
+
+    while let Some(c) = self.peek() {
+      if stops.contains(&c) {
+        break;
+      }
+
+      match (c, self.peek_at(1)) {
+        ('\\', Some(next)) if nested || next == '$' || (self.quotes && matches!(next, '\'' | '"')) => {
+          self.pos += 2;
+          out.push((next, true));
+        },
+        ('$', _) => {
+          let value = self.variable(evaluate)?;
+          out.extend(value.chars().map(|c| (c, true)));
+        },
+        ('\'', _) if self.quotes => self.single_quoted(&mut out)?,
+        ('"', _) if self.quotes => self.double_quoted(&mut out, evaluate)?,
+        _ => {
+          self.pos += 1;
+          out.push((c, false));
+        }
+      }
+    }
+
+    Ok(out)
+  }
+
+  /// Reads a single-quoted string, in which nothing is expanded.
+  fn single_quoted(&mut self, out: &mut Vec<WordChar>) -> Result<(), SubstitutionError> {
+    let start = self.offset();
+    self.pos += 1;
+
+    loop {
+      match self.peek() {
+        Some('\'') => {
+          self.pos += 1;
+          return Ok(());
+        },
+        Some(c) => {
+          self.pos += 1;
+          out.push((c, true));
+        },
+        None => return Err(self.bad_substitution(start, "unterminated single quote"))
+      }
+    }
+  }
+
+  /// Reads a double-quoted string, expanding any variables.
+  fn double_quoted(
+    &mut self, out: &mut Vec<WordChar>, evaluate: bool
+  ) -> Result<(), SubstitutionError> {
+    let start = self.offset();
+    self.pos += 1;
+
+    loop {
+      match (self.peek(), self.peek_at(1)) {
+        (Some('"'), _) => {
+          self.pos += 1;
+          return Ok(());
+        },
+        (Some('\\'), Some(next @ ('"' | '$' | '\\'))) => {
+          self.pos += 2;
+          out.push((next, true));
+        },
+        (Some('$'), _) => {
+          let value = self.variable(evaluate)?;
+          out.extend(value.chars().map(|c| (c, true)));
+        },
+        (Some(c), _) => {
+          self.pos += 1;
+          out.push((c, true));
+        },
+        (None, _) => return Err(self.bad_substitution(start, "unterminated double quote"))
+      }
+    }
+  }
+
+  /// Reads a variable name, which may be empty.
+  fn name(&mut self) -> &'a str {
+    let start = self.offset();
+    while let Some(c) = self.peek() {
+      if !(c.is_ascii_alphanumeric() || c == '_') {
+        break;
+      }
+
+      self.pos += 1;
+    }
+
+    &self.input[start..self.offset()]
+  }
+
+  /// Expands a variable reference at a `$`. A `$` not followed by a name or
+  /// `{` is returned literally.
+  fn variable(&mut self, evaluate: bool) -> Result<String, SubstitutionError> {
+    let start = self.offset();
+    self.pos += 1;
+
+    if self.eat('{') {
+      return self.braced(start, evaluate);
+    }
+
+    let name = self.name();
+    if name.is_empty() {
+      return Ok("$".to_string());
+    }
+
+    if !evaluate {
+      return Ok(String::new());
+    }
+
+    match self.get(name) {
+      Some(value) => Ok(value),
+      None => self.undefined(name, Span::new(start, self.offset()))
+    }
+  }
+
+  fn bad_substitution(&self, start: usize, message: &str) -> SubstitutionError {
+    SubstitutionError::BadSubstitution {
+      message: message.to_string(),
+      span: Span::new(start, self.offset())
+    }
+  }
+
+  /// Consumes the closing brace of a `${...}` expression.
+  fn close(&mut self, start: usize) -> Result<(), SubstitutionError> {
+    if self.eat('}') {
+      Ok(())
+    } else {
+      Err(self.bad_substitution(start, "unterminated '${'"))
+    }
+  }
+
+  /// Expands a `${...}` expression, just past the opening brace.
+  fn braced(&mut self, start: usize, evaluate: bool) -> Result<String, SubstitutionError> {
+    let name = self.name();
+    if name.is_empty() {
+      return Err(self.bad_substitution(start, "missing variable name"));
+    }
+
+    let value = if evaluate { self.get(name) } else { None };
+
+    let op = match self.peek() {
+      Some(c) => c,
+      None => return Err(self.bad_substitution(start, "unterminated '${'"))
+    };
+    self.pos += 1;
+
+    match op {
+      '}' => match (evaluate, value) {
+        (false, _) => Ok(String::new()),
+        (true, Some(value)) => Ok(value),
+        (true, None) => self.undefined(name, Span::new(start, self.offset())),
+      },
+      ':' | '-' | '+' | '?' => {
+        let colon = op == ':';
+        let op = if colon {
+          match self.peek() {
+            Some(c @ '-') | Some(c @ '+') | Some(c @ '?') => {
+              self.pos += 1;
+              c
+            },
+            _ => return Err(self.bad_substitution(start, "unsupported modifier after ':'"))
+          }
+        } else {
+          op
+        };
+
+        // with a colon, empty values are treated as unset
+        let set = value.as_deref().filter(|v| !colon || !v.is_empty());
+        let use_word = evaluate && match op {
+          '+' => set.is_some(),
+          _ => set.is_none(),
+        };
+
+        let word = self.word(&['}'], true, use_word)?;
+        self.close(start)?;
+
+        if !evaluate {
+          return Ok(String::new());
+        }
+
+        let word: String = word.into_iter().map(|(c, _)| c).collect();
+        match (op, set) {
+          ('-', Some(value)) => Ok(value.to_string()),
+          ('-', None) => Ok(word),
+          ('+', Some(_)) => Ok(word),
+          ('+', None) => Ok(String::new()),
+          ('?', Some(value)) => Ok(value.to_string()),
+          _ => {
+            let message = if !word.is_empty() {
+              word
+            } else if value.is_none() {
+              "is not allowed to be unset".to_string()
+            } else {
+              "is not allowed to be empty".to_string()
+            };
+
+            Err(SubstitutionError::RequiredVariable {
+              name: name.to_string(),
+              message,
+              span: Span::new(start, self.offset())
+            })
+          }
+        }
+      },
+      '#' | '%' => {
+        let longest = self.eat(op);
+        let pattern = self.word(&['}'], true, evaluate)?;
+        self.close(start)?;
+
+        if !evaluate {
+          return Ok(String::new());
+        }
+
+        let value = match value {
+          Some(value) => value,
+          None => self.undefined(name, Span::new(start, self.offset()))?
+        };
+        let value: Vec<char> = value.chars().collect();
+        let glob = Glob::parse(&pattern);
+        let len = value.len();
+
+        let end = if op == '#' {
+          let mut prefixes: Box<dyn Iterator<Item = usize>> = if longest {
+            Box::new((0..=len).rev())
+          } else {
+            Box::new(0..=len)
+          };
+
+          prefixes
+            .find(|i| glob.matches(&value[..*i]))
+            .map(|i| value[i..].iter().collect())
+        } else {
+          let mut suffixes: Box<dyn Iterator<Item = usize>> = if longest {
+            Box::new(0..=len)
+          } else {
+            Box::new((0..=len).rev())
+          };
+
+          suffixes
+            .find(|i| glob.matches(&value[*i..]))
+            .map(|i| value[..i].iter().collect())
+        };
+
+        Ok(end.unwrap_or_else(|| value.iter().collect()))
+      },
+      '/' => {
+        let all = self.eat('/');
+        let pattern = self.word(&['/', '}'], true, evaluate)?;
+        let replacement = if self.eat('/') {
+          self.word(&['}'], true, evaluate)?
+        } else {
+          Vec::new()
+        };
+        self.close(start)?;
+
+        if !evaluate {
+          return Ok(String::new());
+        }
+
+        let value = match value {
+          Some(value) => value,
+          None => self.undefined(name, Span::new(start, self.offset()))?
+        };
+        let value: Vec<char> = value.chars().collect();
+        let replacement: String = replacement.into_iter().map(|(c, _)| c).collect();
+
+        Ok(replace(&value, &Glob::parse(&pattern), &replacement, all))
+      },
+      _ => Err(self.bad_substitution(start, "unsupported modifier"))
+    }
+  }
+}
+
+/// Replaces the first (or every) leftmost-longest non-empty match of a glob.
+fn replace(value: &[char], glob: &Glob, replacement: &str, all: bool) -> String {
+  let mut out = String::new();
+  let mut i = 0;
+  let mut replaced = false;
+
+  while i < value.len() {
+    let found = if replaced && !all {
+      None
+    } else {
+      ((i + 1)..=value.len()).rev().find(|j| glob.matches(&value[i..*j]))
+    };
+
+    match found {
+      Some(j) => {
+        out.push_str(replacement);
+        replaced = true;
+        i = j;
+      },
+      None => {
+        out.push(value[i]);
+        i += 1;
+      }
+    }
+  }
+
+  out
+}
+
+#[derive(Debug)]
+enum GlobToken {
+  Char(char),
+  AnyChar,
+  AnyString,
+  Class {
+    negated: bool,
+    ranges: Vec<(char, char)>
+  },
+}
+
+/// A shell wildcard pattern.
+#[derive(Debug)]
+struct Glob(Vec<GlobToken>);
+
+impl Glob {
+  fn parse(pattern: &[WordChar]) -> Glob {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < pattern.len() {
+      let token = match pattern[i] {
+        ('*', false) => GlobToken::AnyString,
+        ('?', false) => GlobToken::AnyChar,
+        ('[', false) => match Glob::parse_class(&pattern[i + 1..]) {
+          Some((token, len)) => {
+            tokens.push(token);
+            i += len + 1;
+            continue;
+          },
+          None => GlobToken::Char('[')
+        },
+        (c, _) => GlobToken::Char(c),
+      };
+
+      tokens.push(token);
+      i += 1;
+    }
+
+    Glob(tokens)
+  }
+
+  /// Parses a bracket expression just past its `[`, returning the token and
+  /// number of characters consumed, or None if it is unterminated.
+  fn parse_class(pattern: &[WordChar]) -> Option<(GlobToken, usize)> {
+    let mut i = 0;
+    let negated = matches!(pattern.first(), Some(('!', false)) | Some(('^', false)));
+    if negated {
+      i += 1;
+    }
+
+    let mut ranges = Vec::new();
+    let first = i;
+    while i < pattern.len() {
+      let (c, literal) = pattern[i];
+      if c == ']' && !literal && i > first {
+        return Some((GlobToken::Class { negated, ranges }, i + 1));
+      }
+
+      match (pattern.get(i + 1), pattern.get(i + 2)) {
+        (Some(('-', false)), Some((end, _))) if *end != ']' => {
+          ranges.push((c, *end));
+          i += 3;
+        },
+        _ => {
+          ranges.push((c, c));
+          i += 1;
+        }
+      }
+    }
+
+    None
+  }
+
+  fn matches(&self, text: &[char]) -> bool {
+    fn matches_from(tokens: &[GlobToken], text: &[char]) -> bool {
+      match tokens.split_first() {
+        None => text.is_empty(),
+        Some((GlobToken::AnyString, rest)) => {
+          (0..=text.len()).any(|i| matches_from(rest, &text[i..]))
+        },
+        Some((token, rest)) => match text.split_first() {
+          None => false,
+          Some((c, text)) => {
+            let matched = match token {
+              GlobToken::Char(t) => t == c,
+              GlobToken::AnyChar => true,
+              GlobToken::Class { negated, ranges } => {
+                ranges.iter().any(|(start, end)| start <= c && c <= end) != *negated
+              },
+              GlobToken::AnyString => unreachable!(),
+            };
+
+            matched && matches_from(rest, text)
+          }
+        }
+      }
+    }
+
+    matches_from(&self.0, text)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  fn vars() -> HashMap<&'static str, &'static str> {
+    let mut vars = HashMap::new();
+    vars.insert("foo", "bar");
+    vars.insert("baz", "qux");
+    vars.insert("empty", "");
+    vars.insert("lorem", "$foo");
+    vars.insert("path", "/usr/local/lib.tar.gz");
+
+    vars
+  }
+
+  fn sub(s: &str) -> Result<String, SubstitutionError> {
+    expand_vars(s, &vars(), &mut HashSet::new())
+  }
+
+  fn strict(s: &str) -> Result<String, SubstitutionError> {
+    expand_vars_strict(s, &vars(), &mut HashSet::new())
+  }
+
+  #[test]
+  fn test_expand_vars() {
+    assert_eq!(sub("hello world"), Ok("hello world".into()));
+    assert_eq!(sub("hello $foo"), Ok("hello bar".into()));
+    assert_eq!(sub("hello ${foo}"), Ok("hello bar".into()));
+    assert_eq!(sub("$baz$foo"), Ok("quxbar".into()));
+    assert_eq!(sub("a $ b $"), Ok("a $ b $".into()));
+    assert_eq!(sub(r"\$foo ${foo}"), Ok("$foo bar".into()));
+    assert_eq!(sub(r"C:\dir\$foo"), Ok(r"C:\dir$foo".into()));
+
+    // values are not expanded again
+    assert_eq!(sub("hello $lorem"), Ok("hello $foo".into()));
+
+    let mut used_vars = HashSet::new();
+    expand_vars("$baz ${foo:-x} ${missing:-$foo} ${empty:+x} $unset", &vars(), &mut used_vars).unwrap();
+    assert_eq!(used_vars, {
+      let mut h = HashSet::new();
+      h.insert("baz".to_string());
+      h.insert("foo".to_string());
+      h.insert("empty".to_string());
+      h
+    });
+  }
+
+  #[test]
+  fn test_expand_vars_defaults() {
+    assert_eq!(sub("${foo:-x}"), Ok("bar".into()));
+    assert_eq!(sub("${missing:-x}"), Ok("x".into()));
+    assert_eq!(sub("${empty:-x}"), Ok("x".into()));
+    assert_eq!(sub("${empty-x}"), Ok("".into()));
+    assert_eq!(sub("${missing-x}"), Ok("x".into()));
+    assert_eq!(sub("${missing:-$foo-${baz}}"), Ok("bar-qux".into()));
+    assert_eq!(sub(r"${missing:-a\}b}"), Ok("a}b".into()));
+
+    assert_eq!(sub("${foo:+x}"), Ok("x".into()));
+    assert_eq!(sub("${empty:+x}"), Ok("".into()));
+    assert_eq!(sub("${empty+x}"), Ok("x".into()));
+    assert_eq!(sub("${missing+x}"), Ok("".into()));
+
+    // unused words are not evaluated
+    assert_eq!(sub("${foo:-$missing}"), Ok("bar".into()));
+    assert_eq!(sub("${missing:+$missing}"), Ok("".into()));
+  }
+
+  #[test]
+  fn test_expand_vars_required() {
+    assert_eq!(sub("${foo:?oops}"), Ok("bar".into()));
+    assert_eq!(sub("${empty?oops}"), Ok("".into()));
+    assert_eq!(sub("x ${missing:?need $foo}"), Err(SubstitutionError::RequiredVariable {
+      name: "missing".into(),
+      message: "need bar".into(),
+      span: Span::new(2, 23)
+    }));
+    assert_eq!(sub("${empty:?}"), Err(SubstitutionError::RequiredVariable {
+      name: "empty".into(),
+      message: "is not allowed to be empty".into(),
+      span: Span::new(0, 10)
+    }));
+    assert_eq!(sub("${missing?}"), Err(SubstitutionError::RequiredVariable {
+      name: "missing".into(),
+      message: "is not allowed to be unset".into(),
+      span: Span::new(0, 11)
+    }));
+  }
+
+  #[test]
+  fn test_expand_vars_patterns() {
+    assert_eq!(sub("${path#*/}"), Ok("usr/local/lib.tar.gz".into()));
+    assert_eq!(sub("${path##*/}"), Ok("lib.tar.gz".into()));
+    assert_eq!(sub("${path%.*}"), Ok("/usr/local/lib.tar".into()));
+    assert_eq!(sub("${path%%.*}"), Ok("/usr/local/lib".into()));
+    assert_eq!(sub("${path#nomatch}"), Ok("/usr/local/lib.tar.gz".into()));
+    assert_eq!(sub("${path##/[a-u]?r}"), Ok("/local/lib.tar.gz".into()));
+    assert_eq!(sub("${path%[!z].gz}"), Ok("/usr/local/lib.ta".into()));
+    assert_eq!(sub(r"${path%\*}"), Ok("/usr/local/lib.tar.gz".into()));
+    assert_eq!(sub("${foo#$foo}"), Ok("".into()));
+
+    assert_eq!(sub("${path/l*b/x}"), Ok("/usr/x.tar.gz".into()));
+    assert_eq!(sub("${path/./-}"), Ok("/usr/local/lib-tar.gz".into()));
+    assert_eq!(sub("${path//./-}"), Ok("/usr/local/lib-tar-gz".into()));
+    assert_eq!(sub(r"${path//[\/.]}"), Ok("usrlocallibtargz".into()));
+    assert_eq!(sub(r"${path/\/usr/$foo}"), Ok("bar/local/lib.tar.gz".into()));
+    assert_eq!(sub("${foo/}"), Ok("bar".into()));
+  }
+
+  #[test]
+  fn test_expand_vars_quotes() {
+    assert_eq!(sub("'$foo'"), Ok("$foo".into()));
+    assert_eq!(sub("pre'$foo'"), Ok("pre$foo".into()));
+    assert_eq!(sub(r#""$foo" '${baz}'"#), Ok("bar ${baz}".into()));
+    assert_eq!(sub(r#""a'$foo'b""#), Ok("a'bar'b".into()));
+    assert_eq!(sub(r#"'a"$foo"b'"#), Ok(r#"a"$foo"b"#.into()));
+    assert_eq!(sub(r#""\$foo \"\\ \n""#), Ok(r#"$foo "\ \n"#.into()));
+    assert_eq!(sub(r#"\'$foo\" "#), Ok(r#"'bar" "#.into()));
+    assert_eq!(sub("${missing:-'a}b'}"), Ok("a}b".into()));
+    assert_eq!(sub("${path##'/usr'*/}"), Ok("lib.tar.gz".into()));
+    assert_eq!(sub("${path%'.*'}"), Ok("/usr/local/lib.tar.gz".into()));
+    assert_eq!(sub("'$missing'"), Ok("$missing".into()));
+  }
+
+  #[test]
+  fn test_expand_vars_errors() {
+    assert_eq!(sub("hello $missing!"), Ok("hello !".into()));
+    assert_eq!(sub("${missing%x}"), Ok("".into()));
+    assert_eq!(strict("hello $foo"), Ok("hello bar".into()));
+    assert_eq!(strict("hello $missing!"), Err(SubstitutionError::UndefinedVariable {
+      name: "missing".into(),
+      span: Span::new(6, 14)
+    }));
+    assert_eq!(strict("${missing}"), Err(SubstitutionError::UndefinedVariable {
+      name: "missing".into(),
+      span: Span::new(0, 10)
+    }));
+    assert_eq!(strict("${missing%x}"), Err(SubstitutionError::UndefinedVariable {
+      name: "missing".into(),
+      span: Span::new(0, 12)
+    }));
+    assert_eq!(sub("a ${foo"), Err(SubstitutionError::BadSubstitution {
+      message: "unterminated '${'".into(),
+      span: Span::new(2, 7)
+    }));
+    assert_eq!(sub("${foo:-x"), Err(SubstitutionError::BadSubstitution {
+      message: "unterminated '${'".into(),
+      span: Span::new(0, 8)
+    }));
+    assert_eq!(sub("${}"), Err(SubstitutionError::BadSubstitution {
+      message: "missing variable name".into(),
+      span: Span::new(0, 2)
+    }));
+    assert_eq!(sub("${foo:0:1}"), Err(SubstitutionError::BadSubstitution {
+      message: "unsupported modifier after ':'".into(),
+      span: Span::new(0, 6)
+    }));
+    assert_eq!(sub("${foo!}"), Err(SubstitutionError::BadSubstitution {
+      message: "unsupported modifier".into(),
+      span: Span::new(0, 6)
+    }));
+    assert_eq!(sub("a 'b"), Err(SubstitutionError::BadSubstitution {
+      message: "unterminated single quote".into(),
+      span: Span::new(2, 4)
+    }));
+    assert_eq!(sub(r#"a "$foo"#), Err(SubstitutionError::BadSubstitution {
+      message: "unterminated double quote".into(),
+      span: Span::new(2, 7)
+    }));
+  }
+
+  #[test]
+  #[allow(deprecated)]
+  fn test_substitute() {
+    let mut vars = HashMap::new();
+    vars.insert("foo", "bar");
+    vars.insert("lorem", "$foo");
+    vars.insert("ipsum", "${lorem}");
+    vars.insert("recursion1", "$recursion2");
+    vars.insert("recursion2", "$recursion1");
+
+    let mut used_vars = HashSet::new();
+    assert_eq!(
+      substitute("hello ${foo}", &vars, &mut used_vars, 16).as_deref(),
+      Some("hello bar")
+    );
+    assert_eq!(used_vars.len(), 1);
+
+    let mut used_vars = HashSet::new();
+    assert_eq!(
+      substitute("hello $ipsum", &vars, &mut used_vars, 16).as_deref(),
+      Some("hello bar")
+    );
+    assert_eq!(used_vars.len(), 3);
+
+    let mut used_vars = HashSet::new();
+    assert_eq!(substitute("hello $ipsum", &vars, &mut used_vars, 2), None);
+    assert_eq!(substitute("hello $missing", &vars, &mut used_vars, 16), None);
+    assert_eq!(substitute("hello $recursion1", &vars, &mut used_vars, 16), None);
+    assert!(used_vars.is_empty());
+  }
+
+  #[test]
+  fn test_referenced_vars() {
+    let mut vars: Vec<String> = referenced_vars("$a ${b:-$c} ${d:+${e}} '$f' \"$g\" \\$h ${i:?$j}")
+      .into_iter()
+      .collect();
+    vars.sort();
+    assert_eq!(vars, vec!["a", "b", "c", "d", "e", "g", "i", "j"]);
+  }
+}
@@ -0,0 +1,309 @@
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::image::ImageRef;
+use crate::instructions::SourceType;
+use crate::scope::{Evaluator, Scope, ScopeError};
+use crate::util::{BreakableString, BreakableStringComponent, SpannedString};
+
+/// Misc instructions whose arguments are subject to variable expansion, per
+/// the Dockerfile reference.
+pub(crate) const EXPANDED_MISC_INSTRUCTIONS: &[&str] = &[
+  "add", "expose", "stopsignal", "user", "volume", "workdir"
+];
+
+struct Expansion<'a> {
+  scope: &'a Scope,
+  content: &'a str
+}
+
+impl<'a> Expansion<'a> {
+  fn string(&self, s: &mut SpannedString) -> Result<(), ScopeError> {
+    // expand the original text, as the parsed content may have lost quotes
+    // that affect expansion
+    s.content = self.scope
+      .expand(&self.content[s.span.start..s.span.end])
+      .map_err(|error| ScopeError { span: s.span, error })?;
+
+    Ok(())
+  }
+
+  fn breakable(&self, s: &mut BreakableString) -> Result<(), ScopeError> {
+    for component in &mut s.components {
+      if let BreakableStringComponent::String(s) = component {
+        self.string(s)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Expands the JSON arguments of an exec form instruction, in which quotes
+  /// delimit array elements.
+  fn exec_form(&self, s: &mut BreakableString) -> Result<(), ScopeError> {
+    for component in &mut s.components {
+      if let BreakableStringComponent::String(s) = component {
+        s.content = self.scope
+          .expand_unquoted(&s.content)
+          .map_err(|error| ScopeError { span: s.span, error })?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn instruction(&self, ins: &mut Instruction) -> Result<(), ScopeError> {
+    match ins {
+      Instruction::From(from) => {
+        for flag in &mut from.flags {
+          self.string(&mut flag.value)?;
+        }
+
+        self.string(&mut from.image)?;
+        from.image_parsed = ImageRef::parse(&from.image.content);
+      },
+      Instruction::Arg(arg) => {
+        if let Some(value) = &mut arg.value {
+          self.string(value)?;
+        }
+      },
+      Instruction::Label(label) => {
+        for label in &mut label.labels {
+          self.string(&mut label.name)?;
+          self.string(&mut label.value)?;
+        }
+      },
+      Instruction::Copy(copy) => {
+        for flag in &mut copy.flags {
+          self.string(&mut flag.value)?;
+        }
+
+        for source in &mut copy.sources {
+          if let SourceType::FileName(name) = source {
+            self.string(name)?;
+          }
+        }
+
+        self.string(&mut copy.destination)?;
+      },
+      Instruction::Env(env) => {
+        for var in &mut env.vars {
+          self.breakable(&mut var.value)?;
+        }
+      },
+      Instruction::Misc(misc) => {
+        let name = misc.instruction.as_ref().to_ascii_lowercase();
+        if EXPANDED_MISC_INSTRUCTIONS.contains(&name.as_str()) {
+          if misc.arguments.to_string().trim_start().starts_with('[') {
+            self.exec_form(&mut misc.arguments)?;
+          } else {
+            self.breakable(&mut misc.arguments)?;
+          }
+        }
+      },
+      Instruction::Run(_) | Instruction::Entrypoint(_) | Instruction::Cmd(_) => ()
+    }
+
+    Ok(())
+  }
+}
+
+impl Scope {
+  /// Returns a copy of the given instruction with variables expanded in every
+  /// value Docker would expand, such as `COPY` sources, destinations and
+  /// flags, `ENV` and `LABEL` values, and the arguments of `ADD`, `EXPOSE`,
+  /// `STOPSIGNAL`, `USER`, `VOLUME` and `WORKDIR`.
+  ///
+  /// `RUN`, `CMD` and `ENTRYPOINT` instructions (in both shell and exec form)
+  /// are returned unchanged. Quotes are removed from expanded values, and
+  /// single-quoted text is not expanded.
+  ///
+  /// All spans in the returned instruction are unchanged, so each expanded
+  /// value's span refers to its original text in the Dockerfile, including
+  /// any quotes.
+  ///
+  /// Returns an error, with the span of the offending value, if any value
+  /// fails to expand. The span within the error is relative to the value's
+  /// original text.
+  pub fn expand_instruction(
+    &self, dockerfile: &Dockerfile, ins: &Instruction
+  ) -> Result<Instruction, ScopeError> {
+    let expansion = Expansion { scope: self, content: &dockerfile.content };
+
+    let mut ins = ins.clone();
+    expansion.instruction(&mut ins)?;
+
+    Ok(ins)
+  }
+}
+
+impl<'a> Evaluator<'a> {
+  /// Returns expanded copies of each instruction in a stage, each expanded
+  /// using the scope visible to it. Returns an empty list if no stage exists
+  /// with the given index.
+  ///
+  /// See `Scope::expand_instruction` for details.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   FROM alpine:3.12
+  ///   ARG user=nobody
+  ///   ARG dest=/opt/app
+  ///   COPY --chown=$user app.tar.gz ${dest}/
+  ///   RUN echo $dest
+  /// "#)?;
+  ///
+  /// let evaluator = Evaluator::new(&dockerfile, vec![("user", "app")]);
+  /// let expanded = evaluator.expanded_instructions(0);
+  ///
+  /// let copy = expanded[3].as_ref().unwrap().as_copy().unwrap();
+  /// assert_eq!(copy.flags[0].value.content, "app");
+  /// assert_eq!(copy.destination.content, "/opt/app/");
+  /// assert_eq!(
+  ///   &dockerfile.content[copy.destination.span.start..copy.destination.span.end],
+  ///   "${dest}/"
+  /// );
+  ///
+  /// assert_eq!(expanded[4].as_ref().unwrap(), &dockerfile.instructions[4]);
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn expanded_instructions(&self, stage: usize) -> Vec<Result<Instruction, ScopeError>> {
+    let (stage, scopes) = match (self.stages().stages.get(stage), self.stage(stage)) {
+      (Some(stage), Some(scopes)) => (stage, scopes),
+      _ => return Vec::new()
+    };
+
+    stage.instructions
+      .iter()
+      .zip(&scopes.scopes)
+      .map(|(ins, scope)| scope.expand_instruction(self.dockerfile(), ins))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+  use crate::error::SubstitutionError;
+  use crate::splicer::Span;
+
+  #[test]
+  fn test_expanded_instructions() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG tag=3.12
+      FROM alpine:$tag
+      ARG tag
+      ENV dir=/opt/$tag \
+        # comment $tag
+        name=app
+      LABEL "$name"="${tag}" quoted='$tag'
+      WORKDIR $dir
+      USER ${name}:${name}
+      EXPOSE ${port:-8080}/tcp
+      VOLUME ["$dir/data"]
+      SHELL ["/bin/sh", "$tag"]
+      CMD ["echo", "$dir"]
+      ENTRYPOINT echo $dir
+    "#)).unwrap();
+
+    let evaluator = Evaluator::new(&dockerfile, vec![("tag", "3.13")]);
+    let expanded: Vec<Instruction> = evaluator
+      .expanded_instructions(0)
+      .into_iter()
+      .collect::<Result<_, _>>()
+      .unwrap();
+
+    let from = expanded[0].as_from().unwrap();
+    assert_eq!(from.image.content, "alpine:3.13");
+    assert_eq!(from.image.span, dockerfile.instructions[1].as_from().unwrap().image.span);
+    assert_eq!(from.image_parsed, ImageRef::parse("alpine:3.13"));
+
+    let env = expanded[2].as_env().unwrap();
+    assert_eq!(env.vars[0].value.to_string(), "/opt/3.13");
+    assert_eq!(env.vars[1].value.to_string(), "app");
+
+    let label = expanded[3].as_label().unwrap();
+    assert_eq!(label.labels[0].name.content, "app");
+    assert_eq!(label.labels[0].value.content, "3.13");
+    assert_eq!(label.labels[1].value.content, "$tag");
+
+    let args: Vec<String> = expanded[4..9]
+      .iter()
+      .map(|ins| ins.as_misc().unwrap().arguments.to_string().trim().to_string())
+      .collect();
+    assert_eq!(args, vec![
+      "/opt/3.13",
+      "app:app",
+      "8080/tcp",
+      r#"["/opt/3.13/data"]"#,
+      r#"["/bin/sh", "$tag"]"#,
+    ]);
+
+    assert_eq!(&expanded[9], &dockerfile.instructions[10]);
+    assert_eq!(&expanded[10], &dockerfile.instructions[11]);
+  }
+
+  #[test]
+  fn test_expanded_instructions_quotes() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine
+      ARG tag=3.12
+      LABEL a=pre'$tag'"-$tag" b="it's $tag" "c$tag"='$tag'
+      COPY --chown='$tag' pre'$tag'"$tag" /opt/"$tag"/'$tag'/
+      WORKDIR "/opt/$tag"/'$tag'
+    "#)).unwrap();
+
+    let evaluator = Evaluator::new(&dockerfile, Vec::<(String, String)>::new());
+    let expanded: Vec<Instruction> = evaluator
+      .expanded_instructions(0)
+      .into_iter()
+      .collect::<Result<_, _>>()
+      .unwrap();
+
+    let label = expanded[2].as_label().unwrap();
+    let labels: Vec<(&str, &str)> = label.labels
+      .iter()
+      .map(|l| (l.name.content.as_str(), l.value.content.as_str()))
+      .collect();
+    assert_eq!(labels, vec![("a", "pre$tag-3.12"), ("b", "it's 3.12"), ("c3.12", "$tag")]);
+    assert_eq!(
+      &dockerfile.content[label.labels[0].value.span.start..label.labels[0].value.span.end],
+      r#"pre'$tag'"-$tag""#
+    );
+
+    let copy = expanded[3].as_copy().unwrap();
+    assert_eq!(copy.flags[0].value.content, "$tag");
+    match &copy.sources[0] {
+      SourceType::FileName(name) => assert_eq!(name.content, "pre$tag3.12"),
+      other => panic!("unexpected source {:?}", other)
+    }
+    assert_eq!(copy.destination.content, "/opt/3.12/$tag/");
+
+    let workdir = expanded[4].as_misc().unwrap();
+    assert_eq!(workdir.arguments.to_string().trim(), "/opt/3.12/$tag");
+  }
+
+  #[test]
+  fn test_expanded_instructions_error() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine
+      WORKDIR ${dir:?is required}
+    "#)).unwrap();
+
+    let evaluator = Evaluator::new(&dockerfile, Vec::<(String, String)>::new());
+    let expanded = evaluator.expanded_instructions(0);
+    assert_eq!(expanded[1], Err(ScopeError {
+      span: Span::new(19, 39),
+      error: SubstitutionError::RequiredVariable {
+        name: "dir".into(),
+        message: "is required".into(),
+        span: Span::new(1, 20)
+      }
+    }));
+    assert!(evaluator.expanded_instructions(1).is_empty());
+  }
+}
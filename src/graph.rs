@@ -0,0 +1,543 @@
+use std::collections::{BTreeSet, HashSet};
+
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::error::StageGraphError;
+use crate::instructions::RunOption;
+use crate::splicer::Span;
+use crate::stage::{StageParent, Stages};
+
+/// The kind of instruction that created a dependency between stages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StageEdgeKind {
+  /// `FROM <stage>`
+  From,
+
+  /// `COPY --from=<stage>`
+  CopyFrom,
+
+  /// `RUN --mount=from=<stage>,...`
+  RunMount
+}
+
+/// A dependency of one stage on another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageEdge {
+  /// The index of the dependent stage.
+  pub stage: usize,
+
+  /// The index of the stage depended upon.
+  pub dependency: usize,
+
+  pub kind: StageEdgeKind,
+
+  /// The span of the instruction that created the dependency.
+  pub span: Span,
+
+  /// The span of the stage name or index within the instruction.
+  pub reference: Span
+}
+
+/// Splits a CSV record into its fields as Go's `encoding/csv` would, returning
+/// each unquoted field along with the offsets of its content in `s`, i.e.
+/// excluding any surrounding quotes. Escaped quotes (`""`) within quoted fields
+/// are unescaped.
+fn csv_fields(s: &str) -> Vec<(String, usize, usize)> {
+  let mut fields = Vec::new();
+  let mut chars = s.char_indices().peekable();
+  let mut start = 0;
+
+  loop {
+    let mut field = String::new();
+    let (content_start, content_end);
+
+    if s[start..].starts_with('"') {
+      chars.next();
+      content_start = start + 1;
+
+      let mut end = s.len();
+      while let Some((i, c)) = chars.next() {
+        if c == '"' {
+          if let Some((_, '"')) = chars.peek() {
+            chars.next();
+            field.push('"');
+          } else {
+            end = i;
+            break;
+          }
+        } else {
+          field.push(c);
+        }
+      }
+      content_end = end;
+
+      // skip anything between the closing quote and the next separator
+      while let Some((_, c)) = chars.peek() {
+        if *c == ',' {
+          break;
+        }
+        chars.next();
+      }
+    } else {
+      content_start = start;
+
+      let mut end = s.len();
+      while let Some((i, c)) = chars.peek() {
+        if *c == ',' {
+          end = *i;
+          break;
+        }
+        field.push(*c);
+        chars.next();
+      }
+      content_end = end;
+    }
+
+    fields.push((field, content_start, content_end));
+
+    match chars.next() {
+      Some((i, _)) => start = i + 1,
+      None => break
+    }
+  }
+
+  fields
+}
+
+/// Finds the `from=` field of a `RUN --mount` option, returning its value and
+/// span. Fields are parsed as CSV, as in BuildKit, so may be quoted, e.g.
+/// `--mount="from=foo",target=/foo`.
+pub(crate) fn mount_from(content: &str, option: &RunOption) -> Option<(String, Span)> {
+  if !option.name.as_ref().eq_ignore_ascii_case("mount") {
+    return None;
+  }
+
+  let value = option.value.span;
+  let raw = &content[value.start..value.end];
+
+  for (field, start, end) in csv_fields(raw) {
+    if let Some((key, from)) = field.split_once('=') {
+      if key.trim().eq_ignore_ascii_case("from") {
+        let from_start = value.start + start + key.len() + 1;
+        return Some((from.to_string(), Span::new(from_start, value.start + end)));
+      }
+    }
+  }
+
+  None
+}
+
+/// A `COPY --from` or `RUN --mount=from=` reference to a name that matches no
+/// stage.
+///
+/// Docker treats these as references to external images, but they may also
+/// be misspelled stage names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalReference {
+  /// The index of the referencing stage.
+  pub stage: usize,
+
+  pub kind: StageEdgeKind,
+
+  /// The referenced name.
+  pub name: String,
+
+  /// The span of the instruction containing the reference.
+  pub span: Span,
+
+  /// The span of the name within the instruction.
+  pub reference: Span
+}
+
+/// A graph of dependencies between the stages of a multi-stage build.
+///
+/// Dependencies are created by `FROM <stage>`, `COPY --from=<stage>` and
+/// `RUN --mount=from=<stage>`, where stages may be referred to by name or
+/// index. References that don't name a stage refer to external images and
+/// create no dependency, but are recorded as `external_references()` so that
+/// misspelled stage names can be reported. Variables in references are not
+/// expanded.
+///
+/// # Example
+/// ```
+/// use dockerfile_parser::*;
+///
+/// let dockerfile = Dockerfile::parse(r#"
+///   FROM alpine:3.12 as assets
+///   RUN touch /foo
+///
+///   FROM golang:1.15 as build
+///   RUN --mount=type=cache,target=/cache,from=assets go build
+///
+///   FROM build
+///   COPY --from=assets /foo /foo
+/// "#)?;
+///
+/// let graph = dockerfile.stage_graph().unwrap();
+/// let kinds: Vec<_> = graph.dependencies(2).map(|e| (e.dependency, e.kind)).collect();
+/// assert_eq!(kinds, vec![(1, StageEdgeKind::From), (0, StageEdgeKind::CopyFrom)]);
+/// assert_eq!(graph.topological_order(), Ok(vec![0, 1, 2]));
+/// assert!(graph.external_references().is_empty());
+/// # Ok::<(), dockerfile_parser::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageGraph {
+  stage_count: usize,
+  edges: Vec<StageEdge>,
+  external: Vec<ExternalReference>
+}
+
+impl StageGraph {
+  /// Builds the stage graph of a Dockerfile.
+  ///
+  /// Returns an error if any numeric stage reference refers to a stage that
+  /// does not exist.
+  pub fn new(dockerfile: &Dockerfile) -> Result<StageGraph, StageGraphError> {
+    let stages = dockerfile.stages();
+    let mut edges = Vec::new();
+    let mut external = Vec::new();
+
+    let resolve = |stages: &Stages, name: &str, reference: Span| {
+      match name.parse::<usize>() {
+        Ok(index) if index < stages.stages.len() => Ok(Some(index)),
+        Ok(_) => Err(StageGraphError::UndefinedStage {
+          name: name.to_string(),
+          span: reference
+        }),
+        Err(_) => Ok(stages.get_by_name(name).map(|s| s.index))
+      }
+    };
+
+    for stage in stages.iter() {
+      for ins in &stage.instructions {
+        let references = match ins {
+          Instruction::From(from) => match stage.parent {
+            StageParent::Stage(parent) => vec![
+              (StageEdgeKind::From, from.image.content.clone(), Some(parent), from.image.span)
+            ],
+            _ => Vec::new()
+          },
+          Instruction::Copy(copy) => {
+            let mut references = Vec::new();
+            for flag in &copy.flags {
+              if flag.name.as_ref() == "from" {
+                let name = flag.value.as_ref();
+                let dependency = resolve(&stages, name, flag.value.span)?;
+                references.push((StageEdgeKind::CopyFrom, name.to_string(), dependency, flag.value.span));
+              }
+            }
+
+            references
+          },
+          Instruction::Run(run) => {
+            let mut references = Vec::new();
+            for option in &run.options {
+              if let Some((name, span)) = mount_from(&dockerfile.content, option) {
+                let dependency = resolve(&stages, &name, span)?;
+                references.push((StageEdgeKind::RunMount, name, dependency, span));
+              }
+            }
+
+            references
+          },
+          _ => Vec::new()
+        };
+
+        for (kind, name, dependency, reference) in references {
+          match dependency {
+            Some(dependency) => edges.push(StageEdge {
+              stage: stage.index,
+              dependency,
+              kind,
+              span: ins.span(),
+              reference
+            }),
+            None => external.push(ExternalReference {
+              stage: stage.index,
+              kind,
+              name,
+              span: ins.span(),
+              reference
+            })
+          }
+        }
+      }
+    }
+
+    Ok(StageGraph {
+      stage_count: stages.stages.len(),
+      edges,
+      external
+    })
+  }
+
+  /// The number of stages in the graph.
+  pub fn stage_count(&self) -> usize {
+    self.stage_count
+  }
+
+  /// All edges in the graph, in the order their instructions appear.
+  pub fn edges(&self) -> &[StageEdge] {
+    &self.edges
+  }
+
+  /// All `COPY --from` and `RUN --mount=from=` references to names that match
+  /// no stage, in the order their instructions appear. These refer to external
+  /// images, unless misspelled.
+  pub fn external_references(&self) -> &[ExternalReference] {
+    &self.external
+  }
+
+  /// Returns the edges from the given stage to the stages it depends on.
+  pub fn dependencies(&self, stage: usize) -> impl Iterator<Item = &StageEdge> {
+    self.edges.iter().filter(move |e| e.stage == stage)
+  }
+
+  /// Returns the edges to the given stage from stages that depend on it.
+  pub fn dependents(&self, stage: usize) -> impl Iterator<Item = &StageEdge> {
+    self.edges.iter().filter(move |e| e.dependency == stage)
+  }
+
+  /// Returns the indices of all stages the given stage depends on, directly
+  /// or indirectly, not including the stage itself (unless part of a cycle).
+  pub fn transitive_dependencies(&self, stage: usize) -> BTreeSet<usize> {
+    let mut found = BTreeSet::new();
+    let mut pending = vec![stage];
+
+    while let Some(current) = pending.pop() {
+      for edge in self.dependencies(current) {
+        if found.insert(edge.dependency) {
+          pending.push(edge.dependency);
+        }
+      }
+    }
+
+    found
+  }
+
+  /// Returns the indices of the stages needed to build the given target
+  /// stage: the target itself and all of its transitive dependencies.
+  pub fn required_stages(&self, target: usize) -> BTreeSet<usize> {
+    let mut stages = self.transitive_dependencies(target);
+    stages.insert(target);
+    stages
+  }
+
+  /// Returns the indices of the stages that are not needed to build the given
+  /// target stage.
+  pub fn unreachable_stages(&self, target: usize) -> BTreeSet<usize> {
+    let required = self.required_stages(target);
+    (0..self.stage_count).filter(|s| !required.contains(s)).collect()
+  }
+
+  /// Finds a cycle in the graph, if any, returning the indices of the stages
+  /// involved in dependency order, e.g. `[0, 1]` if stage 0 depends on stage 1
+  /// and stage 1 depends on stage 0.
+  pub fn find_cycle(&self) -> Option<Vec<usize>> {
+    fn visit(
+      graph: &StageGraph,
+      stage: usize,
+      path: &mut Vec<usize>,
+      done: &mut HashSet<usize>
+    ) -> Option<Vec<usize>> {
+      if let Some(pos) = path.iter().position(|s| *s == stage) {
+        return Some(path[pos..].to_vec());
+      }
+
+      if done.contains(&stage) {
+        return None;
+      }
+
+      path.push(stage);
+      for edge in graph.dependencies(stage) {
+        if let Some(cycle) = visit(graph, edge.dependency, path, done) {
+          return Some(cycle);
+        }
+      }
+      path.pop();
+      done.insert(stage);
+
+      None
+    }
+
+    let mut done = HashSet::new();
+    (0..self.stage_count).find_map(|stage| visit(self, stage, &mut Vec::new(), &mut done))
+  }
+
+  /// Returns all stage indices ordered such that each stage comes after all
+  /// of its dependencies. Ties are broken by stage index.
+  ///
+  /// Returns an error if the graph contains a cycle.
+  pub fn topological_order(&self) -> Result<Vec<usize>, StageGraphError> {
+    if let Some(stages) = self.find_cycle() {
+      return Err(StageGraphError::StageCycle { stages });
+    }
+
+    let mut order = Vec::with_capacity(self.stage_count);
+    let mut placed = HashSet::new();
+
+    while order.len() < self.stage_count {
+      let next = (0..self.stage_count)
+        .find(|s| {
+          !placed.contains(s) && self.dependencies(*s).all(|e| placed.contains(&e.dependency))
+        })
+        .expect("acyclic graph must have a stage without pending dependencies");
+
+      placed.insert(next);
+      order.push(next);
+    }
+
+    Ok(order)
+  }
+}
+
+impl Dockerfile {
+  /// Builds the dependency graph of this Dockerfile's stages. See
+  /// `StageGraph`.
+  pub fn stage_graph(&self) -> Result<StageGraph, StageGraphError> {
+    StageGraph::new(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  fn edges(dockerfile: &Dockerfile) -> Vec<(usize, usize, StageEdgeKind, &str)> {
+    dockerfile.stage_graph()
+      .unwrap()
+      .edges()
+      .iter()
+      .map(|e| (
+        e.stage,
+        e.dependency,
+        e.kind,
+        &dockerfile.content[e.reference.start..e.reference.end]
+      ))
+      .collect()
+  }
+
+  #[test]
+  fn test_stage_graph() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.12 as base
+      COPY --from=alpine:3.11 /a /a
+
+      FROM base as Build
+      RUN --mount=type=cache,target=/root/.cache \
+        --mount=type=bind,FROM=base,source=/a,target=/b \
+        make
+
+      FROM scratch
+      COPY --from=build /out /out
+      COPY --from=0 /a /a
+      COPY --from=final /b /b
+
+      FROM alpine as final
+    "#)).unwrap();
+
+    assert_eq!(edges(&dockerfile), vec![
+      (1, 0, StageEdgeKind::From, "base"),
+      (1, 0, StageEdgeKind::RunMount, "base"),
+      (2, 1, StageEdgeKind::CopyFrom, "build"),
+      (2, 0, StageEdgeKind::CopyFrom, "0"),
+      (2, 3, StageEdgeKind::CopyFrom, "final"),
+    ]);
+
+    let graph = dockerfile.stage_graph().unwrap();
+    assert_eq!(graph.edges()[1].span, dockerfile.instructions[3].span());
+    assert_eq!(graph.dependents(0).count(), 3);
+    assert_eq!(graph.transitive_dependencies(2), vec![0, 1, 3].into_iter().collect());
+    assert_eq!(graph.find_cycle(), None);
+    assert_eq!(graph.topological_order(), Ok(vec![0, 1, 3, 2]));
+
+    let external: Vec<_> = graph.external_references()
+      .iter()
+      .map(|r| (r.stage, r.kind, r.name.as_str()))
+      .collect();
+    assert_eq!(external, vec![(0, StageEdgeKind::CopyFrom, "alpine:3.11")]);
+  }
+
+  #[test]
+  fn test_stage_graph_misspelled() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine as build
+      RUN make
+
+      FROM alpine
+      COPY --from=biuld /out /out
+      RUN --mount=from=buidl,target=/b true
+    "#)).unwrap();
+
+    let graph = dockerfile.stage_graph().unwrap();
+    assert_eq!(graph.edges(), &[]);
+
+    let external: Vec<_> = graph.external_references()
+      .iter()
+      .map(|r| (
+        r.stage,
+        r.kind,
+        r.name.as_str(),
+        &dockerfile.content[r.reference.start..r.reference.end]
+      ))
+      .collect();
+    assert_eq!(external, vec![
+      (1, StageEdgeKind::CopyFrom, "biuld", "biuld"),
+      (1, StageEdgeKind::RunMount, "buidl", "buidl"),
+    ]);
+    assert_eq!(graph.external_references()[1].span, dockerfile.instructions[4].span());
+  }
+
+  #[test]
+  fn test_stage_graph_quoted_mount() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine as a
+      FROM alpine
+      RUN --mount=type=bind,"from=a",target=/a \
+        --mount=type=bind,"from=b,c",target=/x \
+        --mount=type=bind,"target=/""y""",from=a \
+        true
+    "#)).unwrap();
+
+    assert_eq!(edges(&dockerfile), vec![
+      (1, 0, StageEdgeKind::RunMount, "a"),
+      (1, 0, StageEdgeKind::RunMount, "a"),
+    ]);
+
+    let graph = dockerfile.stage_graph().unwrap();
+    let external = &graph.external_references()[0];
+    assert_eq!(external.name, "b,c");
+    assert_eq!(&dockerfile.content[external.reference.start..external.reference.end], "b,c");
+  }
+
+  #[test]
+  fn test_stage_graph_errors() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine as a
+      COPY --from=2 /a /a
+    "#)).unwrap();
+
+    assert_eq!(dockerfile.stage_graph(), Err(StageGraphError::UndefinedStage {
+      name: "2".into(),
+      span: Span::new(29, 30)
+    }));
+
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine as a
+      COPY --from=c /a /a
+
+      FROM a as b
+
+      FROM alpine as c
+      RUN --mount=from=b,target=/b true
+    "#)).unwrap();
+
+    let graph = dockerfile.stage_graph().unwrap();
+    assert_eq!(graph.find_cycle(), Some(vec![0, 2, 1]));
+    assert_eq!(graph.topological_order(), Err(StageGraphError::StageCycle {
+      stages: vec![0, 2, 1]
+    }));
+  }
+}
@@ -2,12 +2,204 @@
 
 use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::iter::FromIterator;
+use std::hash::{Hash, Hasher};
 
-use lazy_static::lazy_static;
-use regex::Regex;
+use crate::Dockerfile;
+use crate::digest::Digest;
+use crate::error::{DigestError, ImageRefError};
+use crate::expand::expand_vars_strict;
+use crate::scope::{BuildOptions, PLATFORM_ARGS};
 
-use crate::{Dockerfile, Span, Splicer};
+/// The registry used for images without an explicit registry.
+const DEFAULT_DOMAIN: &str = "docker.io";
+
+/// A legacy alias for the default registry.
+const LEGACY_DEFAULT_DOMAIN: &str = "index.docker.io";
+
+/// The namespace of official images on the default registry.
+const OFFICIAL_REPO_PREFIX: &str = "library/";
+
+/// The tag implied when neither a tag nor a digest is given.
+const DEFAULT_TAG: &str = "latest";
+
+/// The maximum length of a tag.
+const MAX_TAG_LENGTH: usize = 128;
+
+/// The maximum length of a repository name, including its registry.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// A component of an image reference, used to report validation errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageRefComponent {
+  /// The registry host and optional port, e.g. `example.com:5000`
+  Registry,
+
+  /// The repository path, e.g. `library/alpine`
+  Path,
+
+  /// The tag, e.g. `3.12`
+  Tag,
+
+  /// The digest, e.g. `sha256:...`
+  Digest
+}
+
+impl fmt::Display for ImageRefComponent {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ImageRefComponent::Registry => write!(f, "registry"),
+      ImageRefComponent::Path => write!(f, "repository path"),
+      ImageRefComponent::Tag => write!(f, "tag"),
+      ImageRefComponent::Digest => write!(f, "digest")
+    }
+  }
+}
+
+/// Helper to create an invalid component error.
+fn invalid(component: ImageRefComponent, position: usize, message: impl Into<String>) -> ImageRefError {
+  ImageRefError::InvalidComponent { component, position, message: message.into() }
+}
+
+/// Validates a repository path: `/`-separated components of lowercase
+/// alphanumeric runs joined by `.`, `_`, `__` or any number of `-`.
+fn validate_path(path: &str, offset: usize) -> Result<(), ImageRefError> {
+  let component = ImageRefComponent::Path;
+  let mut start = 0;
+
+  for part in path.split('/') {
+    let position = offset + start;
+    start += part.len() + 1;
+
+    if part.is_empty() {
+      return Err(invalid(component, position, "path components must not be empty"));
+    }
+
+    let mut separator_start = None;
+    for (i, c) in part.char_indices() {
+      match c {
+        'a'..='z' | '0'..='9' => {
+          if let Some(sep_start) = separator_start.take() {
+            let separator = &part[sep_start..i];
+            if sep_start == 0 || !matches!(separator, "." | "_" | "__") && !separator.chars().all(|c| c == '-') {
+              return Err(invalid(
+                component, position + sep_start, format!("invalid separator '{}'", separator)
+              ));
+            }
+          }
+        },
+        '.' | '_' | '-' => {
+          separator_start.get_or_insert(i);
+        },
+        'A'..='Z' => {
+          return Err(invalid(component, position + i, "repository names must be lowercase"));
+        },
+        _ => {
+          return Err(invalid(component, position + i, format!("invalid character '{}'", c)));
+        }
+      }
+    }
+
+    if let Some(sep_start) = separator_start {
+      return Err(invalid(
+        component, position + sep_start, "path components must end with a letter or digit"
+      ));
+    }
+  }
+
+  Ok(())
+}
+
+/// Validates a registry: a hostname (or bracketed IPv6 address) and an
+/// optional numeric port.
+fn validate_registry(registry: &str) -> Result<(), ImageRefError> {
+  let component = ImageRefComponent::Registry;
+
+  let (host, port) = if registry.starts_with('[') {
+    let end = registry
+      .find(']')
+      .ok_or_else(|| invalid(component, 0, "unterminated IPv6 address"))?;
+
+    if let Some(i) = registry[1..end].find(|c: char| !(c.is_ascii_hexdigit() || c == ':')) {
+      return Err(invalid(component, i + 1, "invalid IPv6 address"));
+    }
+
+    match &registry[end + 1..] {
+      "" => (&registry[..=end], None),
+      rest if rest.starts_with(':') => (&registry[..=end], Some(&rest[1..])),
+      _ => return Err(invalid(component, end + 1, "expected ':' after IPv6 address"))
+    }
+  } else {
+    match registry.split_once(':') {
+      Some((host, port)) => (host, Some(port)),
+      None => (registry, None)
+    }
+  };
+
+  if !host.starts_with('[') {
+    let mut start = 0;
+    for label in host.split('.') {
+      let valid = !label.is_empty()
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !label.starts_with('-')
+        && !label.ends_with('-');
+
+      if !valid {
+        return Err(invalid(component, start, format!("invalid hostname component '{}'", label)));
+      }
+
+      start += label.len() + 1;
+    }
+  }
+
+  if let Some(port) = port {
+    if port.is_empty() || !port.chars().all(|c| c.is_ascii_digit()) {
+      return Err(invalid(component, host.len() + 1, format!("invalid port '{}'", port)));
+    }
+  }
+
+  Ok(())
+}
+
+/// Validates a tag: up to 128 word characters, dots and dashes, not starting
+/// with a dot or dash.
+fn validate_tag(tag: &str, offset: usize) -> Result<(), ImageRefError> {
+  let component = ImageRefComponent::Tag;
+
+  if tag.is_empty() {
+    return Err(invalid(component, offset, "tag must not be empty"));
+  }
+
+  for (i, c) in tag.char_indices() {
+    let valid = c.is_ascii_alphanumeric() || c == '_' || (i > 0 && (c == '.' || c == '-'));
+    if !valid {
+      return Err(invalid(component, offset + i, format!("invalid character '{}'", c)));
+    }
+  }
+
+  if tag.len() > MAX_TAG_LENGTH {
+    return Err(invalid(
+      component, offset + MAX_TAG_LENGTH, format!("tag must not exceed {} characters", MAX_TAG_LENGTH)
+    ));
+  }
+
+  Ok(())
+}
+
+/// Converts a digest error into an image reference error, offsetting its
+/// position by the digest's position in the reference.
+fn invalid_digest(error: DigestError, offset: usize) -> ImageRefError {
+  let component = ImageRefComponent::Digest;
+
+  match &error {
+    DigestError::InvalidHex { character, position, .. } => {
+      invalid(component, offset + position, format!("invalid hex character '{}'", character))
+    },
+    DigestError::InvalidLength { algorithm, .. } => {
+      invalid(component, offset + algorithm.len() + 1, error.to_string())
+    },
+    _ => invalid(component, offset, error.to_string())
+  }
+}
 
 /// A parsed docker image reference
 ///
@@ -22,7 +214,23 @@ use crate::{Dockerfile, Span, Splicer};
 /// assert_eq!(image.tag, Some("3.11".to_string()));
 /// assert_eq!(format!("{}", image), "alpine:3.11");
 /// ```
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Both the tag and digest are displayed if set, so parsing and displaying an
+/// image never drops its digest:
+/// ```
+/// use dockerfile_parser::ImageRef;
+///
+/// let image = ImageRef::parse("alpine:3.11@sha256:e7d88de73db3d3fd9b2d63aa7f447a10fd0220b7cbf39803c803f2af9ba256b3");
+/// assert_eq!(
+///   image.to_string(),
+///   "alpine:3.11@sha256:e7d88de73db3d3fd9b2d63aa7f447a10fd0220b7cbf39803c803f2af9ba256b3"
+/// );
+/// ```
+///
+/// Equality and hashing use the normalized form of the reference (see
+/// `normalized()`), so e.g. `alpine`, `docker.io/library/alpine:latest` and
+/// `index.docker.io/library/alpine` are all considered equal.
+#[derive(Debug, Clone)]
 pub struct ImageRef {
   /// an optional registry, generally Docker Hub if unset
   pub registry: Option<String>,
@@ -34,7 +242,8 @@ pub struct ImageRef {
   /// to mean `:latest` if unset
   pub tag: Option<String>,
 
-  /// An optional embedded image hash, e.g. `sha256:...`. Conflicts with `tag`.
+  /// An optional embedded image hash, e.g. `sha256:...`. If both a tag and a
+  /// hash are set, the hash takes precedence when pulling.
   pub hash: Option<String>
 }
 
@@ -45,54 +254,6 @@ fn is_registry(token: &str) -> bool {
   token == "localhost" || token.contains('.') || token.contains(':')
 }
 
-/// Given a map of key/value pairs, perform variable substitution on a given
-/// input string. `max_recursion_depth` controls the maximum allowed recursion
-/// depth if variables refer to other strings themselves containing variable
-/// references. A small number but reasonable is recommended by default, e.g.
-/// 16.
-/// If None is returned, substitution was impossible, either because a
-/// referenced variable did not exist, or recursion depth was exceeded.
-pub fn substitute<'a, 'b>(
-  s: &'a str,
-  vars: &'b HashMap<&'b str, &'b str>,
-  used_vars: &mut HashSet<String>,
-  max_recursion_depth: u8
-) -> Option<String> {
-  lazy_static! {
-    static ref VAR: Regex = Regex::new(r"\$(?:([A-Za-z0-9_]+)|\{([A-Za-z0-9_]+)\})").unwrap();
-  }
-
-  // note: docker also allows defaults in FROMs, e.g.
-  //   ARG tag
-  //   FROM alpine:${tag:-3.12}
-  // this isn't currently supported.
-
-  let mut splicer = Splicer::from_str(s);
-
-  for caps in VAR.captures_iter(s) {
-    if max_recursion_depth == 0 {
-      // can't substitute, so give up
-      return None;
-    }
-
-    let full_range = caps.get(0)?.range();
-    let var_name = caps.get(1).or_else(|| caps.get(2))?;
-    let var_content = vars.get(var_name.as_str())?;
-    let substituted_content = substitute(
-      var_content,
-      vars,
-      used_vars,
-      max_recursion_depth.saturating_sub(1)
-    )?;
-    used_vars.insert(var_name.as_str().to_string());
-
-    // splice the substituted content back into the output string
-    splicer.splice(&Span::new(full_range.start, full_range.end), &substituted_content);
-  }
-
-  Some(splicer.content)
-}
-
 impl ImageRef {
   /// Parses an `ImageRef` from a string.
   ///
@@ -155,46 +316,370 @@ impl ImageRef {
     }
   }
 
+  /// Strictly parses an `ImageRef` from a string, following the
+  /// [distribution reference grammar]:
+  ///
+  ///  * the registry, if any, must be a valid hostname or bracketed IPv6
+  ///    address with an optional numeric port
+  ///  * repository paths must be lowercase, with path components made of
+  ///    alphanumeric runs separated by `.`, `_`, `__` or dashes
+  ///  * tags must be at most 128 word characters, dots and dashes and may not
+  ///    start with a dot or dash
+  ///  * digests must be valid as in `Digest::parse_lenient()`
+  ///
+  /// Unlike `parse()`, the first path component is only treated as a registry
+  /// if it contains a `.` or `:`, is `localhost`, or contains uppercase
+  /// characters.
+  ///
+  /// Returns an error identifying the invalid component and the byte offset
+  /// at which it failed.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let image = ImageRef::try_parse("example.com:5000/team/app:1.0").unwrap();
+  /// assert_eq!(image.registry.as_deref(), Some("example.com:5000"));
+  /// assert_eq!(image.image, "team/app");
+  ///
+  /// assert_eq!(
+  ///   ImageRef::try_parse("team/App:1.0"),
+  ///   Err(ImageRefError::InvalidComponent {
+  ///     component: ImageRefComponent::Path,
+  ///     position: 5,
+  ///     message: "repository names must be lowercase".into()
+  ///   })
+  /// );
+  /// ```
+  ///
+  /// [distribution reference grammar]: https://github.com/distribution/reference/blob/main/reference.go
+  pub fn try_parse(s: &str) -> Result<ImageRef, ImageRefError> {
+    let (name_tag, hash) = match s.split_once('@') {
+      Some((name_tag, digest)) => {
+        Digest::parse_lenient(digest).map_err(|e| invalid_digest(e, name_tag.len() + 1))?;
+        (name_tag, Some(digest.to_string()))
+      },
+      None => (s, None)
+    };
+
+    // a colon after the last slash separates the tag
+    let last_slash = name_tag.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let (name, tag) = match name_tag[last_slash..].find(':') {
+      Some(i) => {
+        let tag = &name_tag[last_slash + i + 1..];
+        validate_tag(tag, last_slash + i + 1)?;
+        (&name_tag[..last_slash + i], Some(tag.to_string()))
+      },
+      None => (name_tag, None)
+    };
+
+    let (registry, path, path_offset) = match name.split_once('/') {
+      Some((first, rest)) if first.contains(|c: char| c == '.' || c == ':' || c.is_ascii_uppercase())
+          || first == "localhost" => {
+        validate_registry(first)?;
+        (Some(first.to_string()), rest, first.len() + 1)
+      },
+      _ => (None, name, 0)
+    };
+
+    validate_path(path, path_offset)?;
+
+    if name.len() > MAX_NAME_LENGTH {
+      return Err(ImageRefError::NameTooLong { length: name.len() });
+    }
+
+    Ok(ImageRef {
+      registry,
+      image: path.to_string(),
+      tag,
+      hash
+    })
+  }
+
+  /// Returns the registry domain of this image, including any port. Images
+  /// without a registry, or using the legacy `index.docker.io` domain, are on
+  /// `docker.io`.
+  pub fn domain(&self) -> &str {
+    match self.registry.as_deref() {
+      None | Some(LEGACY_DEFAULT_DOMAIN) => DEFAULT_DOMAIN,
+      Some(registry) => registry
+    }
+  }
+
+  /// Returns the port of this image's registry, if one is set and valid.
+  pub fn port(&self) -> Option<u16> {
+    let domain = self.domain();
+
+    // skip past any bracketed IPv6 address
+    let host_end = domain.rfind(']').unwrap_or(0);
+    domain[host_end..]
+      .split_once(':')
+      .and_then(|(_, port)| port.parse().ok())
+  }
+
+  /// Returns the repository path of this image on its registry. Official
+  /// images on Docker Hub are in the `library/` namespace.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::ImageRef;
+  ///
+  /// assert_eq!(ImageRef::parse("alpine").path(), "library/alpine");
+  /// assert_eq!(ImageRef::parse("example.com/alpine").path(), "alpine");
+  /// ```
+  pub fn path(&self) -> String {
+    if self.domain() == DEFAULT_DOMAIN && !self.image.contains('/') {
+      format!("{}{}", OFFICIAL_REPO_PREFIX, self.image)
+    } else {
+      self.image.clone()
+    }
+  }
+
+  /// Returns the fully-qualified form of this image, filling in the default
+  /// `docker.io` registry, the `library/` namespace and the `latest` tag as
+  /// Docker would. The `latest` tag is only added if the image has neither a
+  /// tag nor a digest.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::ImageRef;
+  ///
+  /// let image = ImageRef::parse("alpine").normalized();
+  /// assert_eq!(image.to_string(), "docker.io/library/alpine:latest");
+  /// assert_eq!(image, ImageRef::parse("index.docker.io/library/alpine"));
+  /// ```
+  pub fn normalized(&self) -> ImageRef {
+    let tag = match (&self.tag, &self.hash) {
+      (None, None) => Some(DEFAULT_TAG.to_string()),
+      (tag, _) => tag.clone()
+    };
+
+    ImageRef {
+      registry: Some(self.domain().to_string()),
+      image: self.path(),
+      tag,
+      hash: self.hash.clone()
+    }
+  }
+
+  /// Returns the short form of this image that Docker displays, omitting the
+  /// `docker.io` registry and the `library/` namespace of official images.
+  /// Tags and digests are left unchanged.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::ImageRef;
+  ///
+  /// let image = ImageRef::parse("docker.io/library/alpine:3.12").familiar();
+  /// assert_eq!(image.to_string(), "alpine:3.12");
+  /// ```
+  pub fn familiar(&self) -> ImageRef {
+    if self.domain() != DEFAULT_DOMAIN {
+      return self.clone();
+    }
+
+    let image = match self.image.strip_prefix(OFFICIAL_REPO_PREFIX) {
+      Some(image) if !image.contains('/') => image.to_string(),
+      _ => self.image.clone()
+    };
+
+    ImageRef {
+      registry: None,
+      image,
+      tag: self.tag.clone(),
+      hash: self.hash.clone()
+    }
+  }
+
+  /// Returns a copy of this image with the given digest, e.g. `sha256:...`,
+  /// replacing any existing digest. The tag, if any, is kept.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::ImageRef;
+  ///
+  /// let image = ImageRef::parse("alpine:3.12").with_digest("sha256:abcd");
+  /// assert_eq!(image.to_string(), "alpine:3.12@sha256:abcd");
+  /// assert!(image.is_pinned());
+  /// ```
+  pub fn with_digest<S: Into<String>>(&self, digest: S) -> ImageRef {
+    ImageRef {
+      hash: Some(digest.into()),
+      ..self.clone()
+    }
+  }
+
+  /// Returns a copy of this image without its tag. Any digest is kept.
+  pub fn without_tag(&self) -> ImageRef {
+    ImageRef {
+      tag: None,
+      ..self.clone()
+    }
+  }
+
+  /// Parses this image's digest, if any, accepting only known algorithms. See
+  /// `Digest::parse`.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let image = ImageRef::parse("alpine@sha256:e7d88de73db3d3fd9b2d63aa7f447a10fd0220b7cbf39803c803f2af9ba256b");
+  /// assert_eq!(
+  ///   image.digest(),
+  ///   Err(DigestError::InvalidLength { algorithm: "sha256".into(), expected: 64, found: 63 })
+  /// );
+  /// assert_eq!(ImageRef::parse("alpine:3.12").digest(), Ok(None));
+  /// ```
+  pub fn digest(&self) -> Result<Option<Digest>, DigestError> {
+    self.hash.as_deref().map(Digest::parse).transpose()
+  }
+
+  /// Returns true if this image is pinned to a digest.
+  pub fn is_pinned(&self) -> bool {
+    self.hash.as_ref().map(|h| !h.is_empty()).unwrap_or(false)
+  }
+
+  /// Returns the reference that is actually pulled for a pinned image: its
+  /// name and digest, without the tag (which is ignored when a digest is
+  /// present). Returns `None` if this image is not pinned.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::ImageRef;
+  ///
+  /// let image = ImageRef::parse("alpine:3.12@sha256:abcd");
+  /// assert_eq!(image.pinned().unwrap().to_string(), "alpine@sha256:abcd");
+  /// assert_eq!(ImageRef::parse("alpine:3.12").pinned(), None);
+  /// ```
+  pub fn pinned(&self) -> Option<ImageRef> {
+    if self.is_pinned() {
+      Some(self.without_tag())
+    } else {
+      None
+    }
+  }
+
   /// Given a Dockerfile (and its global `ARG`s), perform any necessary
   /// variable substitution to resolve any variable references in this
   /// `ImageRef` and returns a list of variables included in the end result.
   ///
-  /// If this `ImageRef` contains any unknown variables or if any references are
-  /// excessively recursive, returns None; otherwise, returns the
+  /// Global `ARG` defaults may refer to previously declared `ARG`s, and are
+  /// expanded in order as Docker does.
+  ///
+  /// If this `ImageRef` refers to any unknown variables or is otherwise
+  /// invalid (see `expand_vars_strict(...)`), returns None; otherwise, returns the
   /// fully-substituted string.
-  pub fn resolve_vars_with_context<'a>(
-    &self, dockerfile: &'a Dockerfile
+  pub fn resolve_vars_with_context(
+    &self, dockerfile: &Dockerfile
   ) -> Option<(ImageRef, HashSet<String>)> {
-    let vars: HashMap<&'a str, &'a str> = HashMap::from_iter(
-      dockerfile.global_args
-        .iter()
-        .filter_map(|a| match a.value.as_ref() {
-          Some(v) => Some((a.name.as_ref(), v.as_ref())),
-          None => None
-        })
-    );
+    self.resolve_vars_with_options(dockerfile, &BuildOptions::default())
+  }
 
-    let mut used_vars = HashSet::new();
+  /// Like `resolve_vars_with_context(...)`, but additionally uses the given
+  /// build args to override global `ARG` defaults, and defines any
+  /// predefined platform and proxy args as a real build would.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   ARG GO_VERSION=1.15
+  ///   FROM --platform=$BUILDPLATFORM golang:${GO_VERSION}-${TARGETOS}
+  /// "#)?;
+  /// let from = dockerfile.instructions[1].as_from().unwrap();
+  ///
+  /// let options = BuildOptions {
+  ///   build_platform: Platform::parse("linux/amd64"),
+  ///   ..Default::default()
+  /// }.build_arg("GO_VERSION", "1.16");
+  ///
+  /// let (image, used_vars) = from.image_parsed
+  ///   .resolve_vars_with_options(&dockerfile, &options)
+  ///   .unwrap();
+  /// assert_eq!(image, ImageRef::parse("golang:1.16-linux"));
+  /// assert_eq!(used_vars.len(), 2);
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn resolve_vars_with_options(
+    &self, dockerfile: &Dockerfile, options: &BuildOptions
+  ) -> Option<(ImageRef, HashSet<String>)> {
+    let predefined = options.predefined_args();
+    let mut vars: HashMap<&str, String> = predefined.iter().cloned().collect();
+    let mut arg_vars = HashMap::new();
+
+    for arg in &dockerfile.global_args {
+      let name = arg.name.as_ref();
+      let used_vars = arg_vars.entry(name).or_insert_with(HashSet::new);
+
+      if PLATFORM_ARGS.contains(&name) && vars.contains_key(name) {
+        continue;
+      } else if let Some(value) = options.build_args.get(name) {
+        used_vars.clear();
+        vars.insert(name, value.clone());
+      } else if let Some(value) = &arg.value {
+        used_vars.clear();
+
+        // expand the original text, as quotes affect expansion
+        let raw = &dockerfile.content[value.span.start..value.span.end];
+        match expand_vars_strict(raw, &vars, used_vars) {
+          Ok(value) => vars.insert(name, value),
+          Err(_) => vars.remove(name),
+        };
+      }
+    }
 
-    if let Some(s) = substitute(&self.to_string(), &vars, &mut used_vars, 16) {
-      Some((ImageRef::parse(&s), used_vars))
-    } else {
-      None
+    let mut used_vars = HashSet::new();
+    let image = expand_vars_strict(&self.to_string(), &vars, &mut used_vars).ok()?;
+
+    // include variables used indirectly via other ARGs
+    let mut pending: Vec<String> = used_vars.iter().cloned().collect();
+    while let Some(name) = pending.pop() {
+      if let Some(indirect) = arg_vars.get(name.as_str()) {
+        for var in indirect {
+          if used_vars.insert(var.clone()) {
+            pending.push(var.clone());
+          }
+        }
+      }
     }
+
+    Some((ImageRef::parse(&image), used_vars))
   }
 
   /// Given a Dockerfile (and its global `ARG`s), perform any necessary
   /// variable substitution to resolve any variable references in this
   /// `ImageRef`.
   ///
-  /// If this `ImageRef` contains any unknown variables or if any references are
-  /// excessively recursive, returns None; otherwise, returns the
-  /// fully-substituted string.
+  /// If this `ImageRef` refers to any unknown variables or is otherwise
+  /// invalid, returns None; otherwise, returns the fully-substituted string.
   pub fn resolve_vars(&self, dockerfile: &Dockerfile) -> Option<ImageRef> {
     self.resolve_vars_with_context(dockerfile).map(|(image, _vars)| image)
   }
 }
 
+impl PartialEq for ImageRef {
+  fn eq(&self, other: &ImageRef) -> bool {
+    let (a, b) = (self.normalized(), other.normalized());
+
+    a.registry == b.registry && a.image == b.image && a.tag == b.tag && a.hash == b.hash
+  }
+}
+
+impl Eq for ImageRef {}
+
+impl Hash for ImageRef {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    let normalized = self.normalized();
+
+    normalized.registry.hash(state);
+    normalized.image.hash(state);
+    normalized.tag.hash(state);
+    normalized.hash.hash(state);
+  }
+}
+
 impl fmt::Display for ImageRef {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     if let Some(registry) = &self.registry {
@@ -205,7 +690,9 @@ impl fmt::Display for ImageRef {
 
     if let Some(tag) = &self.tag {
       write!(f, ":{}", tag)?;
-    } else if let Some(hash) = &self.hash {
+    }
+
+    if let Some(hash) = &self.hash {
       write!(f, "@{}", hash)?;
     }
 
@@ -220,6 +707,7 @@ mod tests {
   use std::convert::TryInto;
   use indoc::indoc;
   use crate::instructions::*;
+  use crate::platform::Platform;
 
   #[test]
   fn test_image_parse_dockerhub() {
@@ -479,143 +967,121 @@ mod tests {
   }
 
   #[test]
-  fn test_substitute() {
-    let mut vars = HashMap::new();
-    vars.insert("foo", "bar");
-    vars.insert("baz", "qux");
-    vars.insert("lorem", "$foo");
-    vars.insert("ipsum", "${lorem}");
-    vars.insert("recursion1", "$recursion2");
-    vars.insert("recursion2", "$recursion1");
-
-    let mut used_vars = HashSet::new();
-    assert_eq!(
-      substitute("hello world", &vars, &mut used_vars, 16).as_deref(),
-      Some("hello world")
-    );
-
-    let mut used_vars = HashSet::new();
-    assert_eq!(
-      substitute("hello $foo", &vars, &mut used_vars, 16).as_deref(),
-      Some("hello bar")
-    );
-    assert_eq!(used_vars, {
-      let mut h = HashSet::new();
-      h.insert("foo".to_string());
-      h
-    });
+  fn test_resolve_vars() {
+    let d = Dockerfile::parse(indoc!(r#"
+      ARG image=alpine:3.12
+      FROM $image
+    "#)).unwrap();
 
-    let mut used_vars = HashSet::new();
-    assert_eq!(
-      substitute("hello $foo", &vars, &mut used_vars, 0).as_deref(),
-      None
-    );
-    assert!(used_vars.is_empty());
+    let from: &FromInstruction = d.instructions
+      .get(1).unwrap()
+      .try_into().unwrap();
 
-    let mut used_vars = HashSet::new();
     assert_eq!(
-      substitute("hello ${foo}", &vars, &mut used_vars, 16).as_deref(),
-      Some("hello bar")
+      from.image_parsed.resolve_vars(&d),
+      Some(ImageRef::parse("alpine:3.12"))
     );
-    assert_eq!(used_vars, {
-      let mut h = HashSet::new();
-      h.insert("foo".to_string());
-      h
-    });
+  }
 
-    let mut used_vars = HashSet::new();
-    assert_eq!(
-      substitute("$baz $foo", &vars, &mut used_vars, 16).as_deref(),
-      Some("qux bar")
-    );
-    assert_eq!(used_vars, {
-      let mut h = HashSet::new();
-      h.insert("baz".to_string());
-      h.insert("foo".to_string());
-      h
-    });
+  #[test]
+  fn test_resolve_vars_nested() {
+    let d = Dockerfile::parse(indoc!(r#"
+      ARG image=alpine
+      ARG unnecessarily_nested=${image}
+      ARG tag=3.12
+      FROM ${unnecessarily_nested}:${tag}
+    "#)).unwrap();
 
-    let mut used_vars = HashSet::new();
-    assert_eq!(
-      substitute("hello $lorem", &vars, &mut used_vars, 16).as_deref(),
-      Some("hello bar")
-    );
-    assert_eq!(used_vars, {
-      let mut h = HashSet::new();
-      h.insert("foo".to_string());
-      h.insert("lorem".to_string());
-      h
-    });
+    let from: &FromInstruction = d.instructions
+      .get(3).unwrap()
+      .try_into().unwrap();
 
-    let mut used_vars = HashSet::new();
     assert_eq!(
-      substitute("hello $lorem", &vars, &mut used_vars, 1).as_deref(),
-      None
+      from.image_parsed.resolve_vars(&d),
+      Some(ImageRef::parse("alpine:3.12"))
     );
-    assert!(used_vars.is_empty());
+  }
 
-    let mut used_vars = HashSet::new();
-    assert_eq!(
-      substitute("hello $ipsum", &vars, &mut used_vars, 16).as_deref(),
-      Some("hello bar")
-    );
-    assert_eq!(used_vars, {
-      let mut h = HashSet::new();
-      h.insert("foo".to_string());
-      h.insert("lorem".to_string());
-      h.insert("ipsum".to_string());
-      h
-    });
+  #[test]
+  fn test_resolve_vars_operators() {
+    let d = Dockerfile::parse(indoc!(r#"
+      ARG tag
+      ARG registry=docker.io/library
+      ARG image=${registry}/alpine
+      FROM ${image##*/}:${tag:-3.12}
+    "#)).unwrap();
 
-    let mut used_vars = HashSet::new();
-    assert_eq!(
-      substitute("hello $ipsum", &vars, &mut used_vars, 2).as_deref(),
-      None
-    );
-    assert!(used_vars.is_empty());
+    let from: &FromInstruction = d.instructions
+      .get(3).unwrap()
+      .try_into().unwrap();
 
-    let mut used_vars = HashSet::new();
     assert_eq!(
-      substitute("hello $recursion1", &vars, &mut used_vars, 16).as_deref(),
-      None
+      from.image_parsed.resolve_vars_with_context(&d),
+      Some((ImageRef::parse("alpine:3.12"), {
+        let mut h = HashSet::new();
+        h.insert("image".to_string());
+        h.insert("registry".to_string());
+        h
+      }))
     );
-    assert!(used_vars.is_empty());
   }
 
   #[test]
-  fn test_resolve_vars() {
+  fn test_resolve_vars_quoted() {
     let d = Dockerfile::parse(indoc!(r#"
-      ARG image=alpine:3.12
+      ARG tag=3.12
+      ARG image="alpine:$tag"
+      ARG literal='alpine:$tag'
       FROM $image
+      FROM $literal
     "#)).unwrap();
 
-    let from: &FromInstruction = d.instructions
-      .get(1).unwrap()
-      .try_into().unwrap();
+    let images: Vec<Option<ImageRef>> = d.instructions[3..]
+      .iter()
+      .map(|ins| ins.as_from().unwrap().image_parsed.resolve_vars(&d))
+      .collect();
 
-    assert_eq!(
-      from.image_parsed.resolve_vars(&d),
-      Some(ImageRef::parse("alpine:3.12"))
-    );
+    assert_eq!(images, vec![
+      Some(ImageRef::parse("alpine:3.12")),
+      Some(ImageRef::parse("alpine:$tag")),
+    ]);
   }
 
   #[test]
-  fn test_resolve_vars_nested() {
+  fn test_resolve_vars_with_options() {
     let d = Dockerfile::parse(indoc!(r#"
-      ARG image=alpine
-      ARG unnecessarily_nested=${image}
-      ARG tag=3.12
-      FROM ${unnecessarily_nested}:${tag}
+      ARG image
+      ARG TARGETARCH=ignored
+      ARG full=${image:-alpine}:${TARGETARCH}
+      FROM $full
     "#)).unwrap();
 
     let from: &FromInstruction = d.instructions
       .get(3).unwrap()
       .try_into().unwrap();
 
+    let options = BuildOptions {
+      target_platform: Platform::parse("linux/arm64"),
+      ..Default::default()
+    };
     assert_eq!(
-      from.image_parsed.resolve_vars(&d),
-      Some(ImageRef::parse("alpine:3.12"))
+      from.image_parsed.resolve_vars_with_options(&d, &options),
+      Some((ImageRef::parse("alpine:arm64"), {
+        let mut h = HashSet::new();
+        h.insert("full".to_string());
+        h.insert("TARGETARCH".to_string());
+        h
+      }))
+    );
+
+    let options = options.build_arg("image", "ubuntu").build_arg("TARGETARCH", "x");
+    assert_eq!(
+      from.image_parsed.resolve_vars_with_options(&d, &options).map(|(image, _)| image),
+      Some(ImageRef::parse("ubuntu:arm64"))
     );
+
+    // without a known platform, TARGETARCH is an ordinary ARG
+    assert_eq!(from.image_parsed.resolve_vars(&d), Some(ImageRef::parse("alpine:ignored")));
   }
 
   #[test]
@@ -663,7 +1129,7 @@ mod tests {
     "#)).unwrap();
 
     let from: &FromInstruction = d.instructions
-      .get(0).unwrap()
+      .first().unwrap()
       .try_into().unwrap();
 
     assert_eq!(
@@ -671,4 +1137,162 @@ mod tests {
       None
     );
   }
+
+  #[test]
+  fn test_image_try_parse() {
+    assert_eq!(ImageRef::try_parse("alpine"), Ok(ImageRef::parse("alpine")));
+    assert_eq!(
+      ImageRef::try_parse("localhost/my-org/my__app.v2:1.0_rc-1"),
+      Ok(ImageRef::parse("localhost/my-org/my__app.v2:1.0_rc-1"))
+    );
+    assert_eq!(
+      ImageRef::try_parse("[::1]:5000/foo@sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"),
+      Ok(ImageRef {
+        registry: Some("[::1]:5000".into()),
+        image: "foo".into(),
+        tag: None,
+        hash: Some("sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef".into())
+      })
+    );
+    assert_eq!(
+      ImageRef::try_parse("nvidia/cuda:latest@sha256:2d913b09e6be8387e1a10976933642c73c840c0b735f0bf3c28d97fc9bc422e0"),
+      Ok(ImageRef::parse("nvidia/cuda:latest@sha256:2d913b09e6be8387e1a10976933642c73c840c0b735f0bf3c28d97fc9bc422e0"))
+    );
+    assert_eq!(
+      ImageRef::try_parse("Registry/foo").map(|i| i.registry),
+      Ok(Some("Registry".into()))
+    );
+  }
+
+  #[test]
+  fn test_image_try_parse_errors() {
+    fn err(s: &str) -> (ImageRefComponent, usize, String) {
+      match ImageRef::try_parse(s) {
+        Err(ImageRefError::InvalidComponent { component, position, message }) => {
+          (component, position, message)
+        },
+        other => panic!("unexpected result for {}: {:?}", s, other)
+      }
+    }
+
+    use ImageRefComponent::*;
+    assert_eq!(err(""), (Path, 0, "path components must not be empty".into()));
+    assert_eq!(err("foo//bar"), (Path, 4, "path components must not be empty".into()));
+    assert_eq!(err("org/Foo"), (Path, 4, "repository names must be lowercase".into()));
+    assert_eq!(err("foo$bar"), (Path, 3, "invalid character '$'".into()));
+    assert_eq!(err("foo..bar"), (Path, 3, "invalid separator '..'".into()));
+    assert_eq!(err("foo___bar"), (Path, 3, "invalid separator '___'".into()));
+    assert_eq!(err("-foo"), (Path, 0, "invalid separator '-'".into()));
+    assert_eq!(err("foo--bar_"), (Path, 8, "path components must end with a letter or digit".into()));
+    assert_eq!(err("foo:"), (Tag, 4, "tag must not be empty".into()));
+    assert_eq!(err("foo:.1"), (Tag, 4, "invalid character '.'".into()));
+    assert_eq!(err("foo:1.0+b"), (Tag, 7, "invalid character '+'".into()));
+    assert_eq!(
+      err(&format!("foo:{}", "a".repeat(129))),
+      (Tag, 132, "tag must not exceed 128 characters".into())
+    );
+    assert_eq!(err("exa_mple.com/foo"), (Registry, 0, "invalid hostname component 'exa_mple'".into()));
+    assert_eq!(err("example.com:50a0/foo"), (Registry, 12, "invalid port '50a0'".into()));
+    assert_eq!(err("example.-com/foo"), (Registry, 8, "invalid hostname component '-com'".into()));
+    assert_eq!(err("[::1/foo"), (Registry, 0, "unterminated IPv6 address".into()));
+    assert_eq!(err("foo@sha256"), (Digest, 4, "digest 'sha256' must be of the form <algorithm>:<hex>".into()));
+    assert_eq!(
+      err("foo@sha256:abc"),
+      (Digest, 11, "invalid sha256 digest: expected 64 hex characters, found 3".into())
+    );
+    assert_eq!(
+      err("foo@md5:abc"),
+      (Digest, 8, "invalid md5 digest: expected 32 hex characters, found 3".into())
+    );
+    assert_eq!(err("foo@sha256:abxyz"), (Digest, 13, "invalid hex character 'x'".into()));
+    assert_eq!(err("foo@sha256:ABC"), (Digest, 11, "invalid hex character 'A'".into()));
+    assert_eq!(err("foo@Sha:abc"), (Digest, 4, "invalid digest algorithm 'Sha'".into()));
+    assert_eq!(err("foo@sha+:abc"), (Digest, 4, "invalid digest algorithm 'sha+'".into()));
+
+    let long = format!("{}/{}", "a".repeat(200), "b".repeat(60));
+    assert_eq!(ImageRef::try_parse(&long), Err(ImageRefError::NameTooLong { length: 261 }));
+  }
+
+  #[test]
+  fn test_image_normalized() {
+    let image = ImageRef::parse("alpine");
+    assert_eq!(image.domain(), "docker.io");
+    assert_eq!(image.path(), "library/alpine");
+    assert_eq!(image.port(), None);
+    assert_eq!(image.normalized().to_string(), "docker.io/library/alpine:latest");
+    assert_eq!(image.familiar().to_string(), "alpine");
+
+    let image = ImageRef::parse("index.docker.io/org/app@sha256:abcd");
+    assert_eq!(image.domain(), "docker.io");
+    assert_eq!(image.path(), "org/app");
+    assert_eq!(image.normalized().to_string(), "docker.io/org/app@sha256:abcd");
+    assert_eq!(image.familiar().to_string(), "org/app@sha256:abcd");
+
+    let image = ImageRef::parse("localhost:5000/library/foo:1.0");
+    assert_eq!(image.domain(), "localhost:5000");
+    assert_eq!(image.path(), "library/foo");
+    assert_eq!(image.port(), Some(5000));
+    assert_eq!(image.normalized().to_string(), "localhost:5000/library/foo:1.0");
+    assert_eq!(image.familiar(), image);
+
+    assert_eq!(ImageRef::parse("[::1]:443/foo").port(), Some(443));
+    assert_eq!(ImageRef::parse("[::1]/foo").port(), None);
+    assert_eq!(ImageRef::parse("docker.io/library/org/foo").familiar().to_string(), "library/org/foo");
+  }
+
+  #[test]
+  fn test_image_normalized_eq() {
+    let mut set = HashSet::new();
+    set.insert(ImageRef::parse("alpine"));
+
+    for equivalent in &[
+      "alpine:latest",
+      "library/alpine",
+      "docker.io/library/alpine:latest",
+      "index.docker.io/library/alpine",
+    ] {
+      assert_eq!(ImageRef::parse(equivalent), ImageRef::parse("alpine"));
+      assert!(set.contains(&ImageRef::parse(equivalent)));
+    }
+
+    for different in &[
+      "alpine:3.12",
+      "alpine@sha256:abcd",
+      "example.com/alpine",
+      "org/alpine",
+    ] {
+      assert_ne!(ImageRef::parse(different), ImageRef::parse("alpine"));
+      assert!(!set.contains(&ImageRef::parse(different)));
+    }
+  }
+
+  #[test]
+  fn test_image_display_round_trip() {
+    for s in &[
+      "alpine",
+      "alpine:3.12",
+      "alpine@sha256:abcd",
+      "alpine:3.12@sha256:abcd",
+      "example.com:5000/org/app:1.0@sha256:abcd",
+    ] {
+      assert_eq!(&ImageRef::parse(s).to_string(), s);
+    }
+  }
+
+  #[test]
+  fn test_image_pinning() {
+    let image = ImageRef::parse("example.com/app:1.0");
+    assert!(!image.is_pinned());
+    assert_eq!(image.pinned(), None);
+    assert_eq!(image.without_tag().to_string(), "example.com/app");
+
+    let pinned = image.with_digest("sha256:abcd");
+    assert!(pinned.is_pinned());
+    assert_eq!(pinned.to_string(), "example.com/app:1.0@sha256:abcd");
+    assert_eq!(pinned.with_digest("sha256:ef01").to_string(), "example.com/app:1.0@sha256:ef01");
+    assert_eq!(pinned.without_tag().to_string(), "example.com/app@sha256:abcd");
+    assert_eq!(pinned.pinned().map(|p| p.to_string()), Some("example.com/app@sha256:abcd".into()));
+
+    assert!(!ImageRef::parse("app@").is_pinned());
+  }
 }
@@ -117,7 +117,7 @@ impl CopyInstruction {
           }
         }
         ensure!(
-          sources.len() >= 1,
+          !sources.is_empty(),
           GenericParseError {
             message: "copy requires at least one source and a destination"
           }
@@ -129,7 +129,7 @@ impl CopyInstruction {
           destination
         })
       },
-      _ => return Err(unexpected_token(field))
+      _ => Err(unexpected_token(field))
     }
   }
 }
@@ -360,7 +360,7 @@ mod tests {
           span: Span::new(81, 86),
           content: "/tmp/".into(),
         },
-      }.into()
+      }
     );
 
     Ok(())
@@ -406,7 +406,7 @@ mod tests {
           span: Span::new(11, 43),
           content: "/usr/share/nginx/html/index.html".to_string(),
         },
-      }.into()
+      }
     );
 
     Ok(())
@@ -434,7 +434,7 @@ mod tests {
           span: Span::new(11, 24),
           content: "/tmp/test.txt".to_string(),
         },
-      }.into()
+      }
     );
 
     Ok(())
@@ -493,7 +493,7 @@ mod tests {
           span: Span::new(11, 25),
           content: "/tmp/script.sh".to_string(),
         },
-      }.into()
+      }
     );
 
     Ok(())
@@ -521,7 +521,7 @@ mod tests {
           span: Span::new(11, 25),
           content: "/tmp/empty.txt".to_string(),
         },
-      }.into()
+      }
     );
 
     Ok(())
@@ -570,7 +570,7 @@ mod tests {
           span: Span::new(26, 42),
           content: "/tmp/config.json".to_string(),
         },
-      }.into()
+      }
     );
 
     Ok(())
@@ -607,7 +607,7 @@ mod tests {
           span: Span::new(11, 27),
           content: "/tmp/special.txt".to_string(),
         },
-      }.into()
+      }
     );
 
     Ok(())
@@ -644,7 +644,7 @@ mod tests {
           span: Span::new(11, 38),
           content: "/tmp/dockerfile-content.txt".to_string(),
         },
-      }.into()
+      }
     );
 
     Ok(())
@@ -688,7 +688,7 @@ mod tests {
           span: Span::new(22, 35),
           content: "/tmp/test.txt".to_string(),
         },
-      }.into()
+      }
     );
 
     Ok(())
@@ -103,10 +103,11 @@ impl RunInstruction {
         match first_field.as_rule() {
           Rule::run_heredoc => {
             let heredoc = parse_heredoc(first_field)?;
+            let start = heredoc.span.start;
             Ok(RunInstruction {
               span,
               options,
-              expr: ShellOrExecExpr::ShellWithHeredoc(BreakableString::new((4, 4)), heredoc),
+              expr: ShellOrExecExpr::ShellWithHeredoc(BreakableString::new((start, start)), heredoc),
             })
           },
           Rule::any_breakable => {
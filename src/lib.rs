@@ -36,12 +36,29 @@ mod error;
 mod parser;
 mod util;
 mod image;
+mod digest;
+mod tag;
+mod expand;
 mod instructions;
 mod splicer;
+mod line_index;
+mod buildkit;
+mod builder;
+mod edit;
+mod diff;
+mod platform;
 mod stage;
+mod graph;
+mod prune;
+mod diagram;
+mod rename;
+mod sources;
+mod pin;
+mod scope;
+mod references;
+mod expanded;
 mod dockerfile_parser;
 
-pub use image::*;
 pub use error::*;
 pub use splicer::*;
 pub use util::*;
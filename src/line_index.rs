@@ -0,0 +1,219 @@
+use std::ops::Range;
+
+use crate::splicer::Span;
+
+/// The unit used to count columns within a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnEncoding {
+  /// Columns are counted in UTF-8 bytes.
+  Utf8,
+
+  /// Columns are counted in UTF-16 code units, as used by the Language Server
+  /// Protocol and most editors.
+  Utf16,
+}
+
+/// A 0-indexed line and column position within a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+  pub line: usize,
+  pub column: usize,
+}
+
+impl Position {
+  pub fn new(line: usize, column: usize) -> Position {
+    Position { line, column }
+  }
+}
+
+/// An index of line start offsets, used to convert between byte offsets and
+/// line/column positions.
+///
+/// Building the index requires a single scan of the document; afterwards each
+/// lookup is a binary search over line starts (plus a scan of a single line
+/// for UTF-16 columns), unlike `Span::relative_span(...)` which rescans the
+/// document on every call.
+///
+/// # Example
+/// ```
+/// use dockerfile_parser::*;
+///
+/// let dockerfile = Dockerfile::parse("FROM alpine:3.10\nRUN echo \\\n  hi\n")?;
+/// let index = dockerfile.line_index();
+///
+/// let run = dockerfile.instructions[1].span();
+/// let range = index.range(&run, ColumnEncoding::Utf8);
+/// assert_eq!(range, Position::new(1, 0)..Position::new(2, 4));
+/// assert_eq!(index.span(&range, ColumnEncoding::Utf8), Some(run));
+/// # Ok::<(), dockerfile_parser::Error>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct LineIndex<'a> {
+  content: &'a str,
+  starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+  /// Builds a new index for the given content.
+  pub fn new(content: &'a str) -> LineIndex<'a> {
+    let mut starts = vec![0];
+    starts.extend(
+      content.bytes().enumerate().filter(|(_, b)| *b == b'\n').map(|(i, _)| i + 1)
+    );
+
+    LineIndex { content, starts }
+  }
+
+  /// The indexed content.
+  pub fn content(&self) -> &'a str {
+    self.content
+  }
+
+  /// The number of lines in the document. A trailing newline begins a final,
+  /// empty line.
+  pub fn line_count(&self) -> usize {
+    self.starts.len()
+  }
+
+  /// Returns the 0-indexed line containing the given byte offset. Offsets past
+  /// the end of the document return the last line.
+  pub fn line(&self, offset: usize) -> usize {
+    match self.starts.binary_search(&offset) {
+      Ok(i) => i,
+      Err(i) => i - 1
+    }
+  }
+
+  /// Returns the byte offset of the start of the given line, if it exists.
+  pub fn line_start(&self, line: usize) -> Option<usize> {
+    self.starts.get(line).copied()
+  }
+
+  /// Returns the span of the given line, excluding its line ending.
+  pub fn line_span(&self, line: usize) -> Option<Span> {
+    let start = self.line_start(line)?;
+    let end = self.line_start(line + 1).unwrap_or(self.content.len());
+    let text = &self.content[start..end];
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    let text = text.strip_suffix('\r').unwrap_or(text);
+
+    Some(Span::new(start, start + text.len()))
+  }
+
+  /// Converts a byte offset into a line and column.
+  ///
+  /// Offsets past the end of the document are clamped to the end, and offsets
+  /// within a multi-byte character are rounded down to its start.
+  pub fn position(&self, offset: usize, encoding: ColumnEncoding) -> Position {
+    let mut offset = offset.min(self.content.len());
+    while !self.content.is_char_boundary(offset) {
+      offset -= 1;
+    }
+
+    let line = self.line(offset);
+    let prefix = &self.content[self.starts[line]..offset];
+    let column = match encoding {
+      ColumnEncoding::Utf8 => prefix.len(),
+      ColumnEncoding::Utf16 => prefix.encode_utf16().count(),
+    };
+
+    Position { line, column }
+  }
+
+  /// Converts a line and column into a byte offset, returning None if the
+  /// position is not within the document or does not fall on a character
+  /// boundary.
+  ///
+  /// A column equal to the line length (i.e. pointing at the line ending) is
+  /// allowed.
+  pub fn offset(&self, position: Position, encoding: ColumnEncoding) -> Option<usize> {
+    let line = self.line_span(position.line)?;
+    let text = &self.content[line.start..line.end];
+
+    let column = match encoding {
+      ColumnEncoding::Utf8 => {
+        if position.column > text.len() || !text.is_char_boundary(position.column) {
+          return None;
+        }
+
+        position.column
+      },
+      ColumnEncoding::Utf16 => {
+        let mut units = 0;
+        let mut bytes = 0;
+        for c in text.chars() {
+          if units >= position.column {
+            break;
+          }
+
+          units += c.len_utf16();
+          bytes += c.len_utf8();
+        }
+
+        if units != position.column {
+          return None;
+        }
+
+        bytes
+      }
+    };
+
+    Some(line.start + column)
+  }
+
+  /// Converts a span into a start and end line and column. Spans may cross
+  /// any number of lines.
+  pub fn range(&self, span: &Span, encoding: ColumnEncoding) -> Range<Position> {
+    self.position(span.start, encoding)..self.position(span.end, encoding)
+  }
+
+  /// Converts a start and end line and column into a span, returning None if
+  /// either position is invalid.
+  pub fn span(&self, range: &Range<Position>, encoding: ColumnEncoding) -> Option<Span> {
+    Some(Span::new(
+      self.offset(range.start, encoding)?,
+      self.offset(range.end, encoding)?
+    ))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  #[test]
+  fn test_line_index() {
+    let content = "FROM alpine\r\nLABEL a=\"\u{1F600}\" b=c\n\nRUN x";
+    let index = LineIndex::new(content);
+
+    assert_eq!(index.line_count(), 4);
+    assert_eq!(index.line_span(0), Some(Span::new(0, 11)));
+    assert_eq!(index.line_span(2), Some(Span::new(32, 32)));
+    assert_eq!(index.line_span(3), Some(Span::new(33, 38)));
+    assert_eq!(index.line_span(4), None);
+
+    let b = content.find("b=c").unwrap();
+    assert_eq!(index.position(b, ColumnEncoding::Utf8), Position::new(1, 15));
+    assert_eq!(index.position(b, ColumnEncoding::Utf16), Position::new(1, 13));
+    assert_eq!(index.offset(Position::new(1, 15), ColumnEncoding::Utf8), Some(b));
+    assert_eq!(index.offset(Position::new(1, 13), ColumnEncoding::Utf16), Some(b));
+
+    // positions inside the emoji
+    assert_eq!(index.offset(Position::new(1, 10), ColumnEncoding::Utf8), None);
+    assert_eq!(index.offset(Position::new(1, 10), ColumnEncoding::Utf16), None);
+    assert_eq!(index.position(b - 3, ColumnEncoding::Utf16), Position::new(1, 9));
+
+    // past the end of the line or document
+    assert_eq!(index.offset(Position::new(0, 11), ColumnEncoding::Utf8), Some(11));
+    assert_eq!(index.offset(Position::new(0, 12), ColumnEncoding::Utf8), None);
+    assert_eq!(index.offset(Position::new(9, 0), ColumnEncoding::Utf8), None);
+    assert_eq!(index.position(100, ColumnEncoding::Utf8), Position::new(3, 5));
+
+    let span = Span::new(5, b + 3);
+    let range = index.range(&span, ColumnEncoding::Utf16);
+    assert_eq!(range, Position::new(0, 5)..Position::new(1, 16));
+    assert_eq!(index.span(&range, ColumnEncoding::Utf16), Some(span));
+  }
+}
@@ -0,0 +1,542 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use snafu::ResultExt;
+
+use crate::digest::Digest;
+use crate::dockerfile_parser::Dockerfile;
+use crate::error::*;
+use crate::image::ImageRef;
+use crate::scope::{literal_default, rewrite_literal, BuildOptions, Evaluator};
+use crate::sources::CopyFromSource;
+use crate::splicer::{Span, Splicer};
+use crate::stage::{Stage, StageParent};
+
+/// Returns the key an image is locked under: its normalized name and tag,
+/// without any digest.
+fn lock_key(image: &ImageRef) -> String {
+  ImageRef { hash: None, ..image.clone() }.normalized().to_string()
+}
+
+/// A set of images and the digests they are locked to, keyed by normalized
+/// name and tag, e.g. `docker.io/library/alpine:3.12`.
+///
+/// Lockfiles are plain text with one `<image> <digest>` entry per line. Blank
+/// lines and lines starting with `#` are ignored. Entries are written in
+/// sorted order so lockfiles can be diffed and checked in.
+///
+/// ```
+/// use dockerfile_parser::*;
+///
+/// let lockfile = Lockfile::parse(r#"
+///   docker.io/library/alpine:3.12 sha256:e7d88de73db3d3fd9b2d63aa7f447a10fd0220b7cbf39803c803f2af9ba256b3
+/// "#).unwrap();
+///
+/// let digest = lockfile.get(&ImageRef::parse("alpine:3.12")).unwrap();
+/// assert_eq!(digest.algorithm, "sha256");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lockfile {
+  images: BTreeMap<String, Digest>
+}
+
+impl Lockfile {
+  pub fn new() -> Lockfile {
+    Lockfile::default()
+  }
+
+  /// Parses a lockfile. Images must be valid references without a digest, and
+  /// digests must use a known algorithm.
+  pub fn parse(s: &str) -> Result<Lockfile, LockfileError> {
+    let mut lockfile = Lockfile::new();
+
+    for (i, line) in s.lines().enumerate() {
+      let line_number = i + 1;
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      let (image, digest) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        [image, digest] => (image, digest),
+        _ => return Err(LockfileError::MalformedEntry { line: line_number })
+      };
+
+      let image = ImageRef::try_parse(image).context(InvalidLockedImage { line: line_number })?;
+      if image.hash.is_some() {
+        return Err(LockfileError::MalformedEntry { line: line_number });
+      }
+
+      let digest = Digest::parse(digest).context(InvalidLockedDigest { line: line_number })?;
+      if lockfile.insert(&image, digest).is_some() {
+        return Err(LockfileError::DuplicateEntry { line: line_number, image: lock_key(&image) });
+      }
+    }
+
+    Ok(lockfile)
+  }
+
+  /// Locks an image to a digest, returning the digest it was previously
+  /// locked to, if any. Any digest in the image itself is ignored.
+  pub fn insert(&mut self, image: &ImageRef, digest: Digest) -> Option<Digest> {
+    self.images.insert(lock_key(image), digest)
+  }
+
+  /// Returns the digest an image is locked to, if any. Any digest in the image
+  /// itself is ignored.
+  pub fn get(&self, image: &ImageRef) -> Option<&Digest> {
+    self.images.get(&lock_key(image))
+  }
+
+  /// Returns an iterator over all locked images and their digests, in sorted
+  /// order.
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &Digest)> {
+    self.images.iter().map(|(image, digest)| (image.as_str(), digest))
+  }
+
+  pub fn len(&self) -> usize {
+    self.images.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.images.is_empty()
+  }
+}
+
+impl fmt::Display for Lockfile {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (image, digest) in self.iter() {
+      writeln!(f, "{} {}", image, digest)?;
+    }
+
+    Ok(())
+  }
+}
+
+/// A problem found while verifying image pins against a lockfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PinIssue {
+  /// The image is locked but not pinned to a digest.
+  Missing {
+    expected: Digest
+  },
+
+  /// The image is pinned to a different digest than it is locked to.
+  Stale {
+    found: Digest,
+    expected: Digest
+  },
+
+  /// The image is pinned to a digest that could not be parsed.
+  InvalidDigest {
+    error: DigestError,
+    expected: Digest
+  },
+
+  /// The image is not in the lockfile.
+  NotLocked,
+
+  /// The image reference could not be resolved, e.g. due to an undefined
+  /// variable.
+  Unresolved
+}
+
+/// An image reference with a `PinIssue`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinReport {
+  /// The index of the stage containing the reference.
+  pub stage: usize,
+
+  /// The span of the image reference in its `FROM` or `COPY` instruction.
+  pub span: Span,
+
+  /// The referenced image, after variable expansion if successful.
+  pub image: ImageRef,
+
+  pub issue: PinIssue
+}
+
+/// An external image referenced by a `FROM` or `COPY --from` instruction.
+struct PinnableImage {
+  stage: usize,
+
+  /// The span of the reference itself
+  span: Span,
+
+  /// The resolved image, if expansion succeeded
+  image: Option<ImageRef>,
+
+  /// The span of the literal text to rewrite when (un)pinning: either the
+  /// reference itself, or the default value of the `ARG` it consists of
+  target: Option<Span>
+}
+
+impl<'a> Evaluator<'a> {
+  /// Returns the span of the literal text to rewrite to (un)pin the image
+  /// referenced at `span`.
+  fn pin_target(&self, stage: &Stage<'a>, span: Span, resolved: Option<&str>) -> Option<Span> {
+    let dockerfile = self.dockerfile();
+    if !dockerfile.content[span.start..span.end].contains('$') {
+      return Some(span);
+    }
+
+    // the default must actually be used, i.e. not overridden by a build arg
+    let value = literal_default(dockerfile, stage, span)?;
+    if Some(value.content.as_str()) == resolved {
+      Some(value.span)
+    } else {
+      None
+    }
+  }
+
+  /// Returns all external images referenced by `FROM` and `COPY --from`.
+  fn pinnable_images(&self) -> Vec<PinnableImage> {
+    let mut images = Vec::new();
+    let copies = self.copy_from_sources();
+
+    for stage in self.stages().iter() {
+      if let StageParent::Image(_) = stage.parent {
+        let span = stage.instructions[0].as_from().unwrap().image.span;
+        let image = stage.resolved_parent.as_ref().cloned();
+        let resolved = image.as_ref().map(|i| i.to_string());
+
+        images.push(PinnableImage {
+          stage: stage.index,
+          span,
+          target: self.pin_target(stage, span, resolved.as_deref()),
+          image
+        });
+      }
+
+      for copy in copies.iter().filter(|c| c.stage == stage.index) {
+        let image = match &copy.source {
+          CopyFromSource::Image(image) => Some(image.clone()),
+          CopyFromSource::Unresolved(_) => None,
+          _ => continue
+        };
+        let resolved = image.as_ref().map(|_| copy.value.as_str());
+
+        images.push(PinnableImage {
+          stage: stage.index,
+          span: copy.span,
+          target: self.pin_target(stage, copy.span, resolved),
+          image
+        });
+      }
+    }
+
+    images
+  }
+
+  /// Checks every external image referenced by `FROM` and `COPY --from`
+  /// against a lockfile, returning any images that are unpinned, pinned to a
+  /// stale or invalid digest, not locked, or could not be resolved.
+  ///
+  /// Images pinned only by digest (e.g. `alpine@sha256:...`) are checked
+  /// against the lockfile entry for their implied `latest` tag.
+  pub fn verify_pins(&self, lockfile: &Lockfile) -> Vec<PinReport> {
+    let content = &self.dockerfile().content;
+    let mut reports = Vec::new();
+
+    for pinnable in self.pinnable_images() {
+      let (image, issue) = match (pinnable.image, lockfile) {
+        (None, _) => {
+          let raw = &content[pinnable.span.start..pinnable.span.end];
+          (ImageRef::parse(raw), PinIssue::Unresolved)
+        },
+        (Some(image), lockfile) => {
+          let issue = match (lockfile.get(&image), image.digest()) {
+            (None, _) => PinIssue::NotLocked,
+            (Some(expected), Ok(None)) => PinIssue::Missing { expected: expected.clone() },
+            (Some(expected), Ok(Some(found))) if found != *expected => PinIssue::Stale {
+              found,
+              expected: expected.clone()
+            },
+            (Some(expected), Err(error)) => PinIssue::InvalidDigest {
+              error,
+              expected: expected.clone()
+            },
+            _ => continue
+          };
+
+          (image, issue)
+        }
+      };
+
+      reports.push(PinReport {
+        stage: pinnable.stage,
+        span: pinnable.span,
+        image,
+        issue
+      });
+    }
+
+    reports
+  }
+
+  /// Pins every external image referenced by `FROM` and `COPY --from` to its
+  /// digest in the lockfile, keeping any tag, e.g. `alpine:3.12@sha256:...`.
+  ///
+  /// References consisting of a single variable, e.g. `FROM ${BASE}`, are
+  /// pinned by rewriting the default value of the corresponding `ARG`, as
+  /// long as it is a literal and is not overridden by a build arg. Other
+  /// references built from variables, and images missing from the lockfile,
+  /// are left unchanged; use `verify_pins` to find them.
+  ///
+  /// The returned `Splicer`'s `content` holds the updated Dockerfile. A
+  /// `SpliceError` is returned if the rewritten references overlap.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   ARG BASE=alpine:3.12
+  ///   FROM ${BASE}
+  ///   COPY --from=nginx:1.25 /etc/nginx /etc/nginx
+  /// "#)?;
+  ///
+  /// let mut lockfile = Lockfile::new();
+  /// let digest = Digest::parse(&format!("sha256:{}", "a".repeat(64))).unwrap();
+  /// lockfile.insert(&ImageRef::parse("alpine:3.12"), digest.clone());
+  /// lockfile.insert(&ImageRef::parse("nginx:1.25"), digest.clone());
+  ///
+  /// let evaluator = Evaluator::with_options(&dockerfile, BuildOptions::default());
+  /// let pinned = evaluator.pin_images(&lockfile).unwrap();
+  /// assert_eq!(pinned.content, format!(r#"
+  ///   ARG BASE=alpine:3.12@{0}
+  ///   FROM ${{BASE}}
+  ///   COPY --from=nginx:1.25@{0} /etc/nginx /etc/nginx
+  /// "#, digest));
+  ///
+  /// assert!(Dockerfile::parse(&pinned.content)?.verify_pins(&lockfile).is_empty());
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn pin_images(&self, lockfile: &Lockfile) -> Result<Splicer, SpliceError> {
+    self.rewrite_pins(|image| {
+      lockfile
+        .get(image)
+        .filter(|digest| image.digest().ok().flatten().as_ref() != Some(*digest))
+        .map(|digest| digest.to_string())
+        .map(|digest| move |literal: ImageRef| literal.with_digest(digest.clone()))
+    })
+  }
+
+  /// Removes the digest from every external image referenced by `FROM` and
+  /// `COPY --from`, including `ARG` defaults as in `pin_images`. Images
+  /// pinned only by digest fall back to their implied `latest` tag.
+  pub fn unpin_images(&self) -> Result<Splicer, SpliceError> {
+    self.rewrite_pins(|image| {
+      image.hash.as_ref().map(|_| |literal: ImageRef| ImageRef { hash: None, ..literal })
+    })
+  }
+
+  /// Rewrites the literal text of every pinnable image for which `f` returns
+  /// a rewriting function.
+  fn rewrite_pins<F, R>(&self, f: F) -> Result<Splicer, SpliceError>
+  where
+    F: Fn(&ImageRef) -> Option<R>,
+    R: Fn(ImageRef) -> ImageRef
+  {
+    let dockerfile = self.dockerfile();
+
+    // several references may share an ARG default
+    let mut edits = BTreeMap::new();
+    for pinnable in self.pinnable_images() {
+      if let (Some(image), Some(target)) = (&pinnable.image, pinnable.target) {
+        if let Some(rewrite) = f(image) {
+          let literal = &dockerfile.content[target.start..target.end];
+          edits.insert(target, rewrite_literal(literal, |s| rewrite(ImageRef::parse(s)).to_string()));
+        }
+      }
+    }
+
+    let mut splicer = dockerfile.splicer();
+    let mut transaction = splicer.transaction();
+    for (span, replacement) in &edits {
+      transaction.splice(span, replacement);
+    }
+
+    transaction.commit()?;
+
+    Ok(splicer)
+  }
+}
+
+impl Dockerfile {
+  /// Checks image pins against a lockfile without any build args. See
+  /// `Evaluator::verify_pins`.
+  pub fn verify_pins(&self, lockfile: &Lockfile) -> Vec<PinReport> {
+    Evaluator::with_options(self, BuildOptions::default()).verify_pins(lockfile)
+  }
+
+  /// Pins images to their digests in a lockfile without any build args. See
+  /// `Evaluator::pin_images`.
+  pub fn pin_images(&self, lockfile: &Lockfile) -> Result<Splicer, SpliceError> {
+    Evaluator::with_options(self, BuildOptions::default()).pin_images(lockfile)
+  }
+
+  /// Removes image digests without any build args. See
+  /// `Evaluator::unpin_images`.
+  pub fn unpin_images(&self) -> Result<Splicer, SpliceError> {
+    Evaluator::with_options(self, BuildOptions::default()).unpin_images()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  fn digest(c: char) -> Digest {
+    Digest::parse(&format!("sha256:{}", c.to_string().repeat(64))).unwrap()
+  }
+
+  fn lockfile() -> Lockfile {
+    let mut lockfile = Lockfile::new();
+    lockfile.insert(&ImageRef::parse("alpine:3.12"), digest('a'));
+    lockfile.insert(&ImageRef::parse("nginx:1.25"), digest('b'));
+    lockfile.insert(&ImageRef::parse("example.com/app"), digest('c'));
+    lockfile
+  }
+
+  #[test]
+  fn test_lockfile_parse() {
+    let lockfile = lockfile();
+    assert_eq!(Lockfile::parse(&lockfile.to_string()), Ok(lockfile.clone()));
+    assert_eq!(lockfile.to_string(), format!(
+      "docker.io/library/alpine:3.12 {}\ndocker.io/library/nginx:1.25 {}\nexample.com/app:latest {}\n",
+      digest('a'), digest('b'), digest('c')
+    ));
+    assert_eq!(lockfile.get(&ImageRef::parse("index.docker.io/library/alpine:3.12")), Some(&digest('a')));
+    assert_eq!(lockfile.get(&ImageRef::parse("example.com/app:latest@sha256:1234")), Some(&digest('c')));
+    assert_eq!(lockfile.get(&ImageRef::parse("alpine")), None);
+
+    assert_eq!(
+      Lockfile::parse("# images\n\nalpine\n"),
+      Err(LockfileError::MalformedEntry { line: 3 })
+    );
+    assert_eq!(
+      Lockfile::parse(&format!("alpine@{0} {0}", digest('a'))),
+      Err(LockfileError::MalformedEntry { line: 1 })
+    );
+    assert!(matches!(
+      Lockfile::parse("Alpine sha256:1234"),
+      Err(LockfileError::InvalidLockedImage { line: 1, .. })
+    ));
+    assert!(matches!(
+      Lockfile::parse("alpine sha256:1234"),
+      Err(LockfileError::InvalidLockedDigest { line: 1, .. })
+    ));
+    assert_eq!(
+      Lockfile::parse(&format!("alpine {}\nlibrary/alpine:latest {}", digest('a'), digest('b'))),
+      Err(LockfileError::DuplicateEntry { line: 2, image: "docker.io/library/alpine:latest".into() })
+    );
+  }
+
+  #[test]
+  fn test_pin_images() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG BASE="alpine:3.12"
+      ARG VERSION=1.25
+      FROM $BASE as base
+      ARG VERSION
+      ARG APP=example.com/app
+      COPY --from=${APP} /app /app
+      COPY --from=nginx:${VERSION} /etc/nginx /etc/nginx
+
+      FROM ${BASE}
+      ARG BASE
+      COPY --from=$BASE /etc/os-release /
+      COPY --from=base /app /app
+      COPY --from=nginx:1.25@sha256:1234 /etc/nginx /etc/nginx
+      COPY --from=ubuntu:20.04 / /
+    "#)).unwrap();
+
+    let pinned = dockerfile.pin_images(&lockfile()).unwrap();
+    assert_eq!(pinned.content, format!(indoc!(r#"
+      ARG BASE="alpine:3.12@{a}"
+      ARG VERSION=1.25
+      FROM $BASE as base
+      ARG VERSION
+      ARG APP=example.com/app@{c}
+      COPY --from=${{APP}} /app /app
+      COPY --from=nginx:${{VERSION}} /etc/nginx /etc/nginx
+
+      FROM ${{BASE}}
+      ARG BASE
+      COPY --from=$BASE /etc/os-release /
+      COPY --from=base /app /app
+      COPY --from=nginx:1.25@{b} /etc/nginx /etc/nginx
+      COPY --from=ubuntu:20.04 / /
+    "#), a = digest('a'), b = digest('b'), c = digest('c')));
+
+    let pinned = Dockerfile::parse(&pinned.content).unwrap();
+    assert_eq!(pinned.unpin_images().unwrap().content, dockerfile.content.replace("@sha256:1234", ""));
+
+    let reports: Vec<(usize, String, PinIssue)> = pinned
+      .verify_pins(&lockfile())
+      .into_iter()
+      .map(|r| (r.stage, r.image.to_string(), r.issue))
+      .collect();
+    assert_eq!(reports, vec![
+      (0, "nginx:1.25".into(), PinIssue::Missing { expected: digest('b') }),
+      (1, "ubuntu:20.04".into(), PinIssue::NotLocked),
+    ]);
+  }
+
+  #[test]
+  fn test_pin_images_build_args() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG BASE=alpine:3.12
+      FROM ${BASE}
+      COPY --from=${MISSING:?required} / /
+    "#)).unwrap();
+
+    // the ARG default isn't used, so it must not be rewritten
+    let options = BuildOptions::default().build_arg("BASE", "nginx:1.25");
+    let evaluator = Evaluator::with_options(&dockerfile, options);
+    assert_eq!(evaluator.pin_images(&lockfile()).unwrap().content, dockerfile.content);
+
+    let reports: Vec<(Span, PinIssue)> = evaluator
+      .verify_pins(&lockfile())
+      .into_iter()
+      .map(|r| (r.span, r.issue))
+      .collect();
+    assert_eq!(reports, vec![
+      (Span::new(26, 33), PinIssue::Missing { expected: digest('b') }),
+      (Span::new(46, 66), PinIssue::Unresolved),
+    ]);
+  }
+
+  #[test]
+  fn test_verify_pins_digests() {
+    let dockerfile = Dockerfile::parse(&format!(indoc!(r#"
+      FROM alpine:3.12@{a}
+      COPY --from=nginx:1.25@{a} / /
+      COPY --from=example.com/app@sha256:1234 / /
+    "#), a = digest('a'))).unwrap();
+
+    let issues: Vec<PinIssue> = dockerfile
+      .verify_pins(&lockfile())
+      .into_iter()
+      .map(|r| r.issue)
+      .collect();
+    assert_eq!(issues, vec![
+      PinIssue::Stale { found: digest('a'), expected: digest('b') },
+      PinIssue::InvalidDigest {
+        error: DigestError::InvalidLength { algorithm: "sha256".into(), expected: 64, found: 4 },
+        expected: digest('c')
+      },
+    ]);
+
+    // invalid digests are replaced when pinning
+    assert_eq!(dockerfile.pin_images(&lockfile()).unwrap().content, format!(indoc!(r#"
+      FROM alpine:3.12@{a}
+      COPY --from=nginx:1.25@{b} / /
+      COPY --from=example.com/app@{c} / /
+    "#), a = digest('a'), b = digest('b'), c = digest('c')));
+  }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+/// A build or target platform, e.g. `linux/amd64` or `linux/arm/v7`.
+///
+/// ```
+/// use dockerfile_parser::Platform;
+///
+/// let platform = Platform::parse("linux/arm/v7").unwrap();
+/// assert_eq!(platform.os, "linux");
+/// assert_eq!(platform.architecture, "arm");
+/// assert_eq!(platform.variant.as_deref(), Some("v7"));
+/// assert_eq!(platform.to_string(), "linux/arm/v7");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Platform {
+  pub os: String,
+  pub architecture: String,
+  pub variant: Option<String>
+}
+
+impl Platform {
+  pub fn new(os: impl Into<String>, architecture: impl Into<String>) -> Platform {
+    Platform {
+      os: os.into(),
+      architecture: architecture.into(),
+      variant: None
+    }
+  }
+
+  /// Returns a copy of this platform with the given variant.
+  pub fn with_variant(mut self, variant: impl Into<String>) -> Platform {
+    self.variant = Some(variant.into());
+    self
+  }
+
+  /// Parses a platform of the form `os/architecture[/variant]`. Returns None
+  /// if any component is missing or empty.
+  pub fn parse(s: &str) -> Option<Platform> {
+    let parts: Vec<&str> = s.split('/').collect();
+    if parts.iter().any(|p| p.is_empty()) {
+      return None;
+    }
+
+    match parts.as_slice() {
+      [os, arch] => Some(Platform::new(*os, *arch)),
+      [os, arch, variant] => Some(Platform::new(*os, *arch).with_variant(*variant)),
+      _ => None
+    }
+  }
+}
+
+impl fmt::Display for Platform {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.os, self.architecture)?;
+
+    if let Some(variant) = &self.variant {
+      write!(f, "/{}", variant)?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_platform_parse() {
+    assert_eq!(Platform::parse("linux/amd64"), Some(Platform::new("linux", "amd64")));
+    assert_eq!(
+      Platform::parse("linux/arm64/v8"),
+      Some(Platform::new("linux", "arm64").with_variant("v8"))
+    );
+    assert_eq!(Platform::parse("linux"), None);
+    assert_eq!(Platform::parse("linux/"), None);
+    assert_eq!(Platform::parse("linux/arm/v7/x"), None);
+  }
+}
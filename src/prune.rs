@@ -0,0 +1,502 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use snafu::ResultExt;
+
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::error::*;
+use crate::expand::referenced_vars;
+use crate::graph::{StageEdgeKind, StageGraph};
+use crate::references::expanded_spans;
+use crate::splicer::{directives_end, Span, SpliceEdit, Splicer};
+use crate::stage::{Stage, Stages};
+
+/// Resolves a build target, given as a stage name or index, to a stage index.
+fn resolve_target(stages: &Stages, target: &str) -> Result<usize, StageGraphError> {
+  stages
+    .get(target)
+    .map(|s| s.index)
+    .ok_or_else(|| StageGraphError::UndefinedTarget { name: target.to_string() })
+}
+
+/// Returns the span of each stage's full text in the original document, from
+/// the comments attached to its `FROM` up to the next stage. Spans never
+/// include parser directives.
+fn stage_spans(dockerfile: &Dockerfile, stages: &Stages, splicer: &Splicer) -> Vec<Span> {
+  let directives_end = directives_end(&dockerfile.content);
+  let starts: Vec<usize> = stages
+    .iter()
+    .map(|s| splicer.attached_span(s.instructions[0]).start.max(directives_end))
+    .collect();
+
+  starts
+    .iter()
+    .enumerate()
+    .map(|(i, start)| {
+      let end = starts.get(i + 1).copied().unwrap_or(dockerfile.content.len());
+      Span::new(*start, end)
+    })
+    .collect()
+}
+
+/// Returns the edits needed to delete every stage not in `required` and
+/// renumber numeric references to the remaining stages.
+fn retain_stages(
+  dockerfile: &Dockerfile,
+  splicer: &Splicer,
+  stages: &Stages,
+  graph: &StageGraph,
+  required: &BTreeSet<usize>
+) -> Vec<SpliceEdit> {
+  let renumbered: HashMap<usize, usize> = required
+    .iter()
+    .enumerate()
+    .map(|(new, old)| (*old, new))
+    .collect();
+
+  let mut edits: Vec<SpliceEdit> = stage_spans(dockerfile, stages, splicer)
+    .into_iter()
+    .enumerate()
+    .filter(|(index, _)| !required.contains(index))
+    .map(|(_, span)| SpliceEdit::new(span, ""))
+    .collect();
+
+  for edge in graph.edges() {
+    if edge.kind == StageEdgeKind::From || !required.contains(&edge.stage) {
+      continue;
+    }
+
+    let reference = &dockerfile.content[edge.reference.start..edge.reference.end];
+    if reference.parse::<usize>().is_ok() {
+      edits.push(SpliceEdit::new(edge.reference, renumbered[&edge.dependency].to_string()));
+    }
+  }
+
+  edits
+}
+
+/// Returns the names of the global `ARG`s used by the given stages, either in
+/// their `FROM` instructions or by re-declaring them, including any used
+/// indirectly by the defaults of other global `ARG`s.
+fn used_global_args(
+  dockerfile: &Dockerfile, stages: &Stages, required: &BTreeSet<usize>
+) -> HashSet<String> {
+  let mut used = HashSet::new();
+
+  for index in required {
+    for ins in &stages[*index].instructions {
+      match ins {
+        Instruction::From(_) => {
+          for span in expanded_spans(ins) {
+            used.extend(referenced_vars(&dockerfile.content[span.start..span.end]));
+          }
+        },
+        Instruction::Arg(arg) if arg.value.is_none() => {
+          used.insert(arg.name.content.clone());
+        },
+        _ => ()
+      }
+    }
+  }
+
+  // defaults may only refer to preceding global ARGs
+  for arg in dockerfile.global_args.iter().rev() {
+    if let Some(value) = &arg.value {
+      if used.contains(arg.name.as_ref()) {
+        used.extend(referenced_vars(&dockerfile.content[value.span.start..value.span.end]));
+      }
+    }
+  }
+
+  used
+}
+
+impl Dockerfile {
+  /// Determines the stages that would be built for the given target (e.g. as
+  /// passed to `docker build --target`), given as a stage name or index.
+  ///
+  /// The result includes the target and every stage it depends on via
+  /// `FROM`, `COPY --from` or `RUN --mount=from=`. Stages not included are
+  /// dead code for this target.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   FROM alpine:3.12 as base
+  ///   FROM base as test
+  ///   FROM golang:1.15 as build
+  ///   FROM base
+  ///   COPY --from=build /app /app
+  /// "#)?;
+  ///
+  /// let stages: Vec<usize> = dockerfile.target_stages("3").unwrap().into_iter().collect();
+  /// assert_eq!(stages, vec![0, 2, 3]);
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn target_stages(&self, target: &str) -> Result<BTreeSet<usize>, StageGraphError> {
+    let target = resolve_target(&self.stages(), target)?;
+    let graph = StageGraph::new(self)?;
+
+    Ok(graph.required_stages(target))
+  }
+
+  /// Renders a copy of this Dockerfile containing only the stages needed to
+  /// build the given target, as determined by `target_stages()`.
+  ///
+  /// Removed stages are deleted along with any comments directly above their
+  /// `FROM` instruction. Numeric stage references in `COPY --from` and
+  /// `RUN --mount=from=` are renumbered to match the remaining stages.
+  /// Global `ARG`s and parser directives are kept as-is.
+  ///
+  /// The returned `Splicer`'s `content` holds the pruned Dockerfile. Returns an
+  /// error if the target does not exist or the stage graph is invalid.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   FROM alpine:3.12 as test
+  ///   RUN make test
+  ///
+  ///   FROM golang:1.15
+  ///   RUN make
+  ///
+  ///   FROM alpine:3.12
+  ///   COPY --from=1 /app /app
+  /// "#)?;
+  ///
+  /// let pruned = dockerfile.prune("2").unwrap();
+  /// assert_eq!(pruned.content, r#"
+  ///   FROM golang:1.15
+  ///   RUN make
+  ///
+  ///   FROM alpine:3.12
+  ///   COPY --from=0 /app /app
+  /// "#);
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn prune(&self, target: &str) -> Result<Splicer, PruneError> {
+    let stages = self.stages();
+    let target = resolve_target(&stages, target).context(PruneGraphError)?;
+    let graph = StageGraph::new(self).context(PruneGraphError)?;
+    let required = graph.required_stages(target);
+
+    let mut splicer = self.splicer();
+    let edits = retain_stages(self, &splicer, &stages, &graph, &required);
+
+    let mut transaction = splicer.transaction();
+    for edit in edits {
+      transaction.splice(&edit.span, &edit.replacement);
+    }
+
+    transaction.commit().context(PruneSpliceError)?;
+
+    Ok(splicer)
+  }
+
+  /// Renders a standalone Dockerfile containing only the given stage and the
+  /// stages it depends on, e.g. to split a reusable base image out of a
+  /// multi-stage build.
+  ///
+  /// Like `prune()`, unneeded stages are removed and numeric stage references
+  /// are renumbered. Additionally, global `ARG`s that are not used by the
+  /// remaining stages (in their `FROM` instructions, by re-declaring them, or
+  /// indirectly via another global `ARG`) are removed along with any comments
+  /// directly above them. Parser directives and all other comments are kept.
+  ///
+  /// Stages keep their relative order, so if the given stage depends on a
+  /// later stage it will not be the last stage of the result.
+  ///
+  /// Returns an error if the stage graph is invalid or the edits cannot be
+  /// applied.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   ARG ALPINE_VERSION=3.12
+  ///   ARG GO_VERSION=1.15
+  ///
+  ///   FROM alpine:${ALPINE_VERSION} as base
+  ///   RUN apk add ca-certificates
+  ///
+  ///   FROM golang:${GO_VERSION}
+  ///   COPY --from=base /etc/ssl /etc/ssl
+  /// "#)?;
+  ///
+  /// let stages = dockerfile.stages();
+  /// let extracted = dockerfile.extract_stage(&stages[0]).unwrap();
+  /// assert_eq!(extracted.content, r#"
+  ///   ARG ALPINE_VERSION=3.12
+  ///
+  ///   FROM alpine:${ALPINE_VERSION} as base
+  ///   RUN apk add ca-certificates
+  ///
+  /// "#);
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn extract_stage(&self, stage: &Stage) -> Result<Splicer, PruneError> {
+    let stages = self.stages();
+    let graph = StageGraph::new(self).context(PruneGraphError)?;
+    let required = graph.required_stages(stage.index);
+    let used = used_global_args(self, &stages, &required);
+
+    let mut splicer = self.splicer();
+    let mut edits = retain_stages(self, &splicer, &stages, &graph, &required);
+
+    // note: instructions and global_args are distinct copies
+    let unused = self.global_args
+      .iter()
+      .filter(|arg| !used.contains(arg.name.as_ref()))
+      .filter_map(|arg| self.instructions.iter().find(|i| i.span() == arg.span));
+
+    let directives_end = directives_end(&self.content);
+    for ins in unused {
+      let span = splicer.attached_span(ins);
+      edits.push(SpliceEdit::new(Span::new(span.start.max(directives_end), span.end), ""));
+    }
+
+    let mut transaction = splicer.transaction();
+    for edit in edits {
+      transaction.splice(&edit.span, &edit.replacement);
+    }
+
+    transaction.commit().context(PruneSpliceError)?;
+
+    Ok(splicer)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  #[test]
+  fn test_target_stages() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.12 as base
+      FROM base as lint
+      FROM golang:1.15 as assets
+      FROM golang:1.15 as build
+      RUN --mount=type=bind,from=assets,target=/assets make
+      FROM base as final
+      COPY --from=build /app /app
+    "#)).unwrap();
+
+    let stages = |target| dockerfile.target_stages(target).map(|s| s.into_iter().collect::<Vec<_>>());
+    assert_eq!(stages("final"), Ok(vec![0, 2, 3, 4]));
+    assert_eq!(stages("LINT"), Ok(vec![0, 1]));
+    assert_eq!(stages("2"), Ok(vec![2]));
+    assert_eq!(stages("test"), Err(StageGraphError::UndefinedTarget { name: "test".into() }));
+    assert_eq!(stages("5"), Err(StageGraphError::UndefinedTarget { name: "5".into() }));
+
+    let graph = dockerfile.stage_graph().unwrap();
+    assert_eq!(graph.unreachable_stages(4), vec![1].into_iter().collect());
+  }
+
+  #[test]
+  fn test_prune() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      # syntax=docker/dockerfile:1
+      ARG version=1.0
+
+      # the base image
+      FROM alpine:3.12 as base
+      RUN apk add make
+
+      # tests, unused by the final image
+      FROM base as test
+      RUN make test
+
+      FROM golang:1.15
+      COPY --from=0 /etc/apk /etc/apk
+      RUN --mount=type=cache,target=/cache,from=0 make
+
+      # final image
+      FROM base
+      COPY --from=2 /app /app
+      COPY --from=alpine:3.12 /bin/sh /bin/sh
+    "#)).unwrap();
+
+    assert_eq!(dockerfile.prune("3").unwrap().content, indoc!(r#"
+      # syntax=docker/dockerfile:1
+      ARG version=1.0
+
+      # the base image
+      FROM alpine:3.12 as base
+      RUN apk add make
+
+      FROM golang:1.15
+      COPY --from=0 /etc/apk /etc/apk
+      RUN --mount=type=cache,target=/cache,from=0 make
+
+      # final image
+      FROM base
+      COPY --from=1 /app /app
+      COPY --from=alpine:3.12 /bin/sh /bin/sh
+    "#));
+
+    assert_eq!(dockerfile.prune("test").unwrap().content, indoc!(r#"
+      # syntax=docker/dockerfile:1
+      ARG version=1.0
+
+      # the base image
+      FROM alpine:3.12 as base
+      RUN apk add make
+
+      # tests, unused by the final image
+      FROM base as test
+      RUN make test
+
+    "#));
+
+    assert_eq!(
+      dockerfile.prune("final").err().unwrap(),
+      PruneError::PruneGraphError {
+        source: StageGraphError::UndefinedTarget { name: "final".into() }
+      }
+    );
+  }
+
+  #[test]
+  fn test_prune_directives() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      # syntax=docker/dockerfile:1
+      FROM alpine:3.12 as test
+      RUN make test
+      FROM golang:1.15
+      RUN make
+    "#)).unwrap();
+
+    assert_eq!(dockerfile.prune("1").unwrap().content, indoc!(r#"
+      # syntax=docker/dockerfile:1
+      FROM golang:1.15
+      RUN make
+    "#));
+  }
+
+  #[test]
+  fn test_extract_stage() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      # syntax=docker/dockerfile:1
+      # unused
+      ARG unused=1
+      ARG registry=docker.io
+      ARG base=${registry}/library/alpine
+      ARG VERSION=3.12
+      ARG BUILD=1
+      ARG other=${unused}
+
+      # base image
+      FROM $base:${VERSION:-latest} as base
+      ARG BUILD
+      RUN echo $other
+
+      FROM base as unrelated
+      COPY --from=2 /a /a
+
+      # build stage
+      FROM golang:1.15 as build
+      COPY --from=base /etc/ssl /etc/ssl
+
+      # final image
+      FROM base
+      COPY --from=2 /app /app
+    "#)).unwrap();
+
+    let stages = dockerfile.stages();
+    assert_eq!(dockerfile.extract_stage(&stages[3]).unwrap().content, indoc!(r#"
+      # syntax=docker/dockerfile:1
+      ARG registry=docker.io
+      ARG base=${registry}/library/alpine
+      ARG VERSION=3.12
+      ARG BUILD=1
+
+      # base image
+      FROM $base:${VERSION:-latest} as base
+      ARG BUILD
+      RUN echo $other
+
+      # build stage
+      FROM golang:1.15 as build
+      COPY --from=base /etc/ssl /etc/ssl
+
+      # final image
+      FROM base
+      COPY --from=1 /app /app
+    "#));
+
+    assert_eq!(dockerfile.extract_stage(&stages[2]).unwrap().content, indoc!(r#"
+      # syntax=docker/dockerfile:1
+      ARG registry=docker.io
+      ARG base=${registry}/library/alpine
+      ARG VERSION=3.12
+      ARG BUILD=1
+
+      # base image
+      FROM $base:${VERSION:-latest} as base
+      ARG BUILD
+      RUN echo $other
+
+      # build stage
+      FROM golang:1.15 as build
+      COPY --from=base /etc/ssl /etc/ssl
+
+    "#));
+  }
+
+  #[test]
+  fn test_extract_stage_directives() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      # syntax=docker/dockerfile:1
+      FROM alpine:3.12 as test
+      RUN make test
+      FROM golang:1.15
+      RUN make
+    "#)).unwrap();
+
+    let stages = dockerfile.stages();
+    assert_eq!(dockerfile.extract_stage(&stages[1]).unwrap().content, indoc!(r#"
+      # syntax=docker/dockerfile:1
+      FROM golang:1.15
+      RUN make
+    "#));
+
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      # syntax=docker/dockerfile:1
+      ARG unused=1
+      FROM alpine:3.12
+    "#)).unwrap();
+
+    let stages = dockerfile.stages();
+    assert_eq!(dockerfile.extract_stage(&stages[0]).unwrap().content, indoc!(r#"
+      # syntax=docker/dockerfile:1
+      FROM alpine:3.12
+    "#));
+  }
+
+  #[test]
+  fn test_extract_stage_quoted_args() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG literal=1
+      ARG expanded=2
+      ARG image='$literal'
+      ARG tag="$expanded"
+      FROM $image:$tag
+    "#)).unwrap();
+
+    let stages = dockerfile.stages();
+    assert_eq!(dockerfile.extract_stage(&stages[0]).unwrap().content, indoc!(r#"
+      ARG expanded=2
+      ARG image='$literal'
+      ARG tag="$expanded"
+      FROM $image:$tag
+    "#));
+  }
+}
@@ -0,0 +1,359 @@
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::expanded::EXPANDED_MISC_INSTRUCTIONS;
+use crate::instructions::SourceType;
+use crate::scope::{BuildOptions, Evaluator, Scope, VarKind, PLATFORM_ARGS};
+use crate::splicer::Span;
+use crate::util::{BreakableString, BreakableStringComponent};
+
+fn breakable_spans(s: &BreakableString) -> impl Iterator<Item = Span> + '_ {
+  s.components.iter().filter_map(|c| match c {
+    BreakableStringComponent::String(s) => Some(s.span),
+    BreakableStringComponent::Comment(_) => None
+  })
+}
+
+/// Returns the spans of an instruction's source text that are subject to
+/// variable expansion.
+///
+/// Shell-form and exec-form `RUN`, `CMD` and `ENTRYPOINT` instructions are
+/// never expanded by Docker (variables are left to the shell), so return no
+/// spans.
+pub(crate) fn expanded_spans(ins: &Instruction) -> Vec<Span> {
+  match ins {
+    Instruction::From(from) => {
+      let mut spans: Vec<Span> = from.flags.iter().map(|f| f.value.span).collect();
+      spans.push(from.image.span);
+      spans
+    },
+    Instruction::Arg(arg) => arg.value.iter().map(|v| v.span).collect(),
+    Instruction::Label(label) => label.labels
+      .iter()
+      .flat_map(|l| vec![l.name.span, l.value.span])
+      .collect(),
+    Instruction::Copy(copy) => {
+      let mut spans: Vec<Span> = copy.flags.iter().map(|f| f.value.span).collect();
+      spans.extend(copy.sources.iter().filter_map(|s| match s {
+        SourceType::FileName(name) => Some(name.span),
+        SourceType::FileContents(_) => None
+      }));
+      spans.push(copy.destination.span);
+      spans
+    },
+    Instruction::Env(env) => env.vars
+      .iter()
+      .flat_map(|v| breakable_spans(&v.value))
+      .collect(),
+    Instruction::Misc(misc) => {
+      let name = misc.instruction.as_ref().to_ascii_lowercase();
+      if EXPANDED_MISC_INSTRUCTIONS.contains(&name.as_str()) {
+        breakable_spans(&misc.arguments).collect()
+      } else {
+        Vec::new()
+      }
+    },
+    Instruction::Run(_) | Instruction::Entrypoint(_) | Instruction::Cmd(_) => Vec::new()
+  }
+}
+
+/// Returns the references to undefined variables within the given span,
+/// along with their absolute spans. Single-quoted text is not expanded, so
+/// references within it are ignored.
+///
+/// If `platform` is set, platform args are always considered declared, even
+/// if no platform is known.
+fn undefined_in(
+  content: &str, span: Span, scope: &Scope, platform: bool
+) -> Vec<(String, Span)> {
+  let mut undefined = Vec::new();
+  let _ = scope.expand_recording(&content[span.start..span.end], &mut undefined);
+
+  undefined
+    .into_iter()
+    .filter(|(name, _)| scope.get(name).is_none())
+    .filter(|(name, _)| !(platform && PLATFORM_ARGS.contains(&name.as_str())))
+    .map(|(name, s)| (name, Span::new(span.start + s.start, span.start + s.end)))
+    .collect()
+}
+
+/// A declaration of a variable via `ARG` or `ENV`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+  pub kind: VarKind,
+
+  /// The span of the declared name.
+  pub span: Span,
+
+  /// The index of the declaring stage, or None for global ARGs.
+  pub stage: Option<usize>
+}
+
+/// A reference to a variable that is not in scope where it is used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndefinedVariable {
+  pub name: String,
+
+  /// The span of the reference, e.g. `$foo` or `${foo}`.
+  pub span: Span,
+
+  /// The index of the stage containing the reference, or None for global ARGs.
+  pub stage: Option<usize>,
+
+  /// The nearest declaration with the same name elsewhere in the Dockerfile,
+  /// preferring preceding declarations, if any. For example, a global ARG
+  /// that was not re-declared in the referencing stage.
+  pub nearest_definition: Option<Definition>
+}
+
+impl<'a> Evaluator<'a> {
+  /// Returns all declarations of variables in the Dockerfile, in order.
+  fn definitions(&self) -> Vec<(&'a str, Definition)> {
+    let mut definitions: Vec<(&str, Definition)> = self.dockerfile().global_args
+      .iter()
+      .map(|arg| (arg.name.as_ref(), Definition {
+        kind: VarKind::Arg,
+        span: arg.name.span,
+        stage: None
+      }))
+      .collect();
+
+    for stage in self.stages().iter() {
+      for ins in &stage.instructions {
+        match ins {
+          Instruction::Arg(arg) => definitions.push((arg.name.as_ref(), Definition {
+            kind: VarKind::Arg,
+            span: arg.name.span,
+            stage: Some(stage.index)
+          })),
+          Instruction::Env(env) => definitions.extend(env.vars.iter().map(|var| {
+            (var.key.as_ref(), Definition {
+              kind: VarKind::Env,
+              span: var.key.span,
+              stage: Some(stage.index)
+            })
+          })),
+          _ => ()
+        }
+      }
+    }
+
+    definitions
+  }
+
+  /// Finds references to variables that are not in scope where they are used
+  /// in any instruction subject to variable expansion, such as a global `ARG`
+  /// used within a stage without being re-declared.
+  ///
+  /// Variables that are declared but unset (e.g. an `ARG` without a default
+  /// value) and references with a default (e.g. `${foo:-bar}`) are not
+  /// reported, nor are references within single quotes. Platform args such as
+  /// `$BUILDPLATFORM` are always considered declared in global ARGs and `FROM`
+  /// instructions, even if no platform is known.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   ARG version=1.0
+  ///   FROM alpine:3.12
+  ///   LABEL version=$version
+  /// "#)?;
+  ///
+  /// let undefined = dockerfile.undefined_variables();
+  /// assert_eq!(undefined.len(), 1);
+  /// assert_eq!(undefined[0].name, "version");
+  /// assert_eq!(&dockerfile.content[undefined[0].span.start..undefined[0].span.end], "$version");
+  /// assert_eq!(undefined[0].nearest_definition.as_ref().unwrap().stage, None);
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn undefined_variables(&self) -> Vec<UndefinedVariable> {
+    let content = &self.dockerfile().content;
+    let mut references = Vec::new();
+
+    // global ARGs may only refer to predefined args and preceding global ARGs
+    let mut global = Scope::default();
+    for var in self.global_scope().iter().filter(|v| v.kind == VarKind::Predefined) {
+      global.declare(var.clone());
+    }
+
+    for arg in &self.dockerfile().global_args {
+      if let Some(value) = &arg.value {
+        references.extend(
+          undefined_in(content, value.span, &global, true).into_iter().map(|r| (None, r))
+        );
+      }
+
+      if let Some(var) = self.global_scope().get(arg.name.as_ref()) {
+        global.declare(var.clone());
+      }
+    }
+
+    for stage in self.stages().iter() {
+      let scopes = &self.stage(stage.index).unwrap().scopes;
+
+      for (ins, scope) in stage.instructions.iter().zip(scopes) {
+        let platform = matches!(ins, Instruction::From(_));
+        for span in expanded_spans(ins) {
+          references.extend(
+            undefined_in(content, span, scope, platform)
+              .into_iter()
+              .map(|r| (Some(stage.index), r))
+          );
+        }
+      }
+    }
+
+    let definitions = self.definitions();
+    references
+      .into_iter()
+      .map(|(stage, (name, span))| {
+        let matching = definitions.iter().filter(|(n, _)| *n == name).map(|(_, d)| d);
+        let preceding = matching.clone().rfind(|d| d.span.end <= span.start);
+        let following = matching.clone().find(|d| d.span.start >= span.end);
+
+        UndefinedVariable {
+          nearest_definition: preceding.or(following).cloned(),
+          name,
+          span,
+          stage,
+        }
+      })
+      .collect()
+  }
+}
+
+impl Dockerfile {
+  /// Finds references to variables that are not in scope where they are used,
+  /// without any build args or platform. See `Evaluator::undefined_variables`.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   FROM --platform=$BUILDPLATFORM golang AS build
+  ///   RUN go build
+  /// "#)?;
+  ///
+  /// assert!(dockerfile.undefined_variables().is_empty());
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn undefined_variables(&self) -> Vec<UndefinedVariable> {
+    Evaluator::with_options(self, BuildOptions::default()).undefined_variables()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  fn undefined(dockerfile: &Dockerfile) -> Vec<(&str, &str, Option<usize>, Option<&str>)> {
+    dockerfile.undefined_variables()
+      .into_iter()
+      .map(|u| (
+        &dockerfile.content[u.span.start..u.span.end],
+        u.nearest_definition.as_ref().map(|d| &dockerfile.content[d.span.start..d.span.end]).unwrap_or(""),
+        u.stage,
+        u.nearest_definition.map(|d| match (d.kind, d.stage) {
+          (VarKind::Arg, None) => "global",
+          (VarKind::Arg, Some(_)) => "arg",
+          (_, _) => "env",
+        })
+      ))
+      .collect()
+  }
+
+  #[test]
+  fn test_undefined_variables() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG base=alpine
+      ARG tag=${version}
+      ARG optional
+      FROM $base:${tag:-latest} as build
+      LABEL a=$base b='$base' c="${optional}"
+      ARG base
+      ENV path=/opt/$base/$later
+      COPY --chown=$user ./$base /$path
+      RUN echo $undefined_but_ignored
+      WORKDIR ${path%/*}/${missing#x}
+      ARG later
+
+      FROM build
+      USER $base
+      EXPOSE $port
+      CMD ["$ignored"]
+    "#)).unwrap();
+
+    assert_eq!(undefined(&dockerfile), vec![
+      ("${version}", "", None, None),
+      ("$base", "base", Some(0), Some("global")),
+      ("${optional}", "optional", Some(0), Some("global")),
+      ("$later", "later", Some(0), Some("arg")),
+      ("$user", "", Some(0), None),
+      ("${missing#x}", "", Some(0), None),
+      ("$base", "base", Some(1), Some("arg")),
+      ("$port", "", Some(1), None),
+    ]);
+  }
+
+  #[test]
+  fn test_undefined_variables_env() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine as build
+      ENV a=1 b=$a
+      ENV c=$b \
+        # a comment with $nothing
+        d=$c
+
+      FROM build
+      ENV e=$a
+    "#)).unwrap();
+
+    assert_eq!(undefined(&dockerfile), vec![
+      ("$a", "a", Some(0), Some("env")),
+      ("$c", "c", Some(0), Some("env")),
+    ]);
+  }
+
+  #[test]
+  fn test_undefined_variables_quotes() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine
+      LABEL a=pre'$single'"$double" b="it's $quoted" c=\'$escaped\'
+      WORKDIR '/café/$x'/$y
+    "#)).unwrap();
+
+    assert_eq!(undefined(&dockerfile), vec![
+      ("$double", "", Some(0), None),
+      ("$quoted", "", Some(0), None),
+      ("$escaped", "", Some(0), None),
+      ("$y", "", Some(0), None),
+    ]);
+  }
+
+  #[test]
+  fn test_undefined_variables_platform() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM --platform=$BUILDPLATFORM golang AS build
+      RUN go build
+    "#)).unwrap();
+    assert_eq!(undefined(&dockerfile), vec![]);
+
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG BASE=alpine-$TARGETARCH
+      FROM --platform=$BUILDPLATFORM golang AS build
+      ARG TARGETOS
+      RUN GOOS=$TARGETOS go build
+
+      FROM --platform=$TARGETPLATFORM $BASE:${TARGETVARIANT}
+      LABEL arch=$TARGETARCH
+    "#)).unwrap();
+
+    assert_eq!(undefined(&dockerfile), vec![
+      ("$TARGETARCH", "", Some(1), None),
+    ]);
+  }
+}
@@ -0,0 +1,352 @@
+use std::collections::BTreeMap;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use snafu::ResultExt;
+
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::error::*;
+use crate::graph::{mount_from, StageEdgeKind, StageGraph};
+use crate::scope::{literal_default, rewrite_literal, BuildOptions, Evaluator};
+use crate::sources::CopyFromSource;
+use crate::splicer::{Span, Splicer};
+use crate::stage::StageParent;
+
+/// Applies the casing style of an existing reference to a stage's new name:
+/// references written exactly like the alias use the new name as given, while
+/// all-lowercase or all-uppercase references stay that way.
+fn match_case(reference: &str, alias: &str, name: &str) -> String {
+  if reference == alias {
+    name.to_string()
+  } else if reference == reference.to_ascii_lowercase() {
+    name.to_ascii_lowercase()
+  } else if reference == reference.to_ascii_uppercase() {
+    name.to_ascii_uppercase()
+  } else {
+    name.to_string()
+  }
+}
+
+impl Dockerfile {
+  /// Renames a stage, given by its current name or index, and rewrites every
+  /// reference to it: its `FROM ... AS` alias, later `FROM <stage>`
+  /// instructions, `COPY --from=<stage>` and `RUN --mount=from=<stage>`.
+  /// Numeric references are left as-is. If the stage has no alias, one is
+  /// added.
+  ///
+  /// References consisting of a single variable, e.g. `FROM ${BASE}` or
+  /// `COPY --from=$STAGE`, are renamed by rewriting the default value of the
+  /// corresponding `ARG` if it is a literal. Other references via variables
+  /// can't be rewritten safely, so an `UnrewritableReferences` error listing
+  /// their spans is returned instead.
+  ///
+  /// Stage names are case-insensitive. References that differ in case from
+  /// the original alias keep their style, e.g. an all-lowercase reference
+  /// stays lowercase.
+  ///
+  /// Returns an error if the new name is invalid, is already used by another
+  /// stage, or matches an external image reference that would then refer to
+  /// the renamed stage instead, or if the edits cannot be applied.
+  ///
+  /// The returned `Splicer`'s `content` holds the updated Dockerfile.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   FROM golang:1.15 AS Build
+  ///   RUN go build
+  ///
+  ///   FROM Build as test
+  ///   RUN go test
+  ///
+  ///   FROM alpine:3.12
+  ///   COPY --from=build /app /app
+  /// "#)?;
+  ///
+  /// let renamed = dockerfile.rename_stage("build", "Compile").unwrap();
+  /// assert_eq!(renamed.content, r#"
+  ///   FROM golang:1.15 AS Compile
+  ///   RUN go build
+  ///
+  ///   FROM Compile as test
+  ///   RUN go test
+  ///
+  ///   FROM alpine:3.12
+  ///   COPY --from=compile /app /app
+  /// "#);
+  ///
+  /// assert_eq!(
+  ///   dockerfile.rename_stage("build", "TEST").err().unwrap(),
+  ///   RenameStageError::StageNameCollision { name: "TEST".into(), stage: 1 }
+  /// );
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn rename_stage(&self, stage: &str, name: &str) -> Result<Splicer, RenameStageError> {
+    lazy_static! {
+      static ref STAGE_NAME: Regex = Regex::new(r"^[a-zA-Z][a-zA-Z0-9_.-]*$").unwrap();
+    }
+
+    let stages = self.stages();
+    let target = stages
+      .get(stage)
+      .ok_or_else(|| RenameStageError::UnknownStage { name: stage.to_string() })?;
+
+    if !STAGE_NAME.is_match(name) {
+      return Err(RenameStageError::InvalidStageName { name: name.to_string() });
+    }
+
+    if let Some(other) = stages.get_by_name(name).filter(|s| s.index != target.index) {
+      return Err(RenameStageError::StageNameCollision {
+        name: name.to_string(),
+        stage: other.index
+      });
+    }
+
+    // references to external images with the new name would otherwise
+    // silently start referring to the renamed stage
+    let collision = |reference: &str, span: Span| {
+      if reference.eq_ignore_ascii_case(name) {
+        Err(RenameStageError::ImageNameCollision { name: reference.to_string(), span })
+      } else {
+        Ok(())
+      }
+    };
+
+    for s in stages.iter() {
+      for ins in &s.instructions {
+        match ins {
+          Instruction::From(from) => {
+            let parent_is_stage = matches!(s.parent, StageParent::Stage(_));
+            if s.index > target.index && !parent_is_stage {
+              collision(from.image.as_ref(), from.image.span)?;
+            }
+          },
+          Instruction::Copy(copy) => {
+            for flag in copy.flags.iter().filter(|f| f.name.as_ref() == "from") {
+              if stages.get(flag.value.as_ref()).is_none() {
+                collision(flag.value.as_ref(), flag.value.span)?;
+              }
+            }
+          },
+          Instruction::Run(run) => {
+            for (reference, span) in run.options.iter().filter_map(|o| mount_from(&self.content, o)) {
+              if stages.get(&reference).is_none() {
+                collision(&reference, span)?;
+              }
+            }
+          },
+          _ => ()
+        }
+      }
+    }
+
+    let graph = StageGraph::new(self).context(RenameGraphError)?;
+    let from = target.instructions[0].as_from().unwrap();
+
+    // edits keyed by span, as several references may share an ARG default
+    let mut edits = BTreeMap::new();
+
+    let alias = match &from.alias {
+      Some(alias) => {
+        edits.insert(alias.span, name.to_string());
+        alias.as_ref()
+      },
+      None => {
+        // match the style of the `AS` keyword used elsewhere
+        let keyword = self.instructions
+          .iter()
+          .filter_map(|ins| ins.as_from())
+          .find_map(|f| f.alias.as_ref().map(|a| self.content[f.image.span.end..a.span.start].trim()))
+          .unwrap_or("AS");
+
+        let end = from.image.span.end;
+        edits.insert(Span::new(end, end), format!(" {} {}", keyword, name));
+        ""
+      }
+    };
+
+    // the graph doesn't expand variables in `COPY --from`, so use the
+    // evaluated sources instead
+    let mut references: Vec<(usize, Span)> = graph.edges()
+      .iter()
+      .filter(|e| e.dependency == target.index && e.kind != StageEdgeKind::CopyFrom)
+      .map(|e| (e.stage, e.reference))
+      .collect();
+
+    let evaluator = Evaluator::with_options(self, BuildOptions::default());
+    references.extend(evaluator
+      .copy_from_sources()
+      .into_iter()
+      .filter(|c| c.source == CopyFromSource::Stage(target.index))
+      .map(|c| (c.stage, c.span)));
+
+    let mut unrewritable = Vec::new();
+    for (stage, span) in references {
+      let reference = &self.content[span.start..span.end];
+      if reference.parse::<usize>().is_ok() {
+        continue;
+      }
+
+      if !alias.is_empty() && reference.eq_ignore_ascii_case(alias) {
+        edits.insert(span, match_case(reference, alias, name));
+        continue;
+      }
+
+      match literal_default(self, &stages[stage], span) {
+        Some(value) if value.content.parse::<usize>().is_ok() => (),
+        Some(value) if !alias.is_empty() && value.content.eq_ignore_ascii_case(alias) => {
+          let literal = &self.content[value.span.start..value.span.end];
+          edits.insert(value.span, rewrite_literal(literal, |s| match_case(s, alias, name)));
+        },
+        _ => unrewritable.push(span)
+      }
+    }
+
+    if !unrewritable.is_empty() {
+      return Err(RenameStageError::UnrewritableReferences { spans: unrewritable });
+    }
+
+    let mut splicer = self.splicer();
+    let mut transaction = splicer.transaction();
+    for (span, replacement) in &edits {
+      transaction.splice(span, replacement);
+    }
+
+    transaction.commit().context(RenameSpliceError)?;
+
+    Ok(splicer)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  #[test]
+  fn test_match_case() {
+    assert_eq!(match_case("Build", "Build", "NewName"), "NewName");
+    assert_eq!(match_case("build", "Build", "NewName"), "newname");
+    assert_eq!(match_case("BUILD", "Build", "NewName"), "NEWNAME");
+    assert_eq!(match_case("bUild", "Build", "NewName"), "NewName");
+  }
+
+  #[test]
+  fn test_rename_stage() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.12 as base
+      FROM golang:1.15 as build
+      RUN --mount=type=cache,target=/cache,from=base \
+        --mount=from=BASE,target=/b make
+      COPY --from=base /a /a
+      FROM base
+      COPY --from=0 /b /b
+      COPY --from=alpine:3.12 /c /c
+    "#)).unwrap();
+
+    assert_eq!(dockerfile.rename_stage("0", "root").unwrap().content, indoc!(r#"
+      FROM alpine:3.12 as root
+      FROM golang:1.15 as build
+      RUN --mount=type=cache,target=/cache,from=root \
+        --mount=from=ROOT,target=/b make
+      COPY --from=root /a /a
+      FROM root
+      COPY --from=0 /b /b
+      COPY --from=alpine:3.12 /c /c
+    "#));
+
+    assert_eq!(dockerfile.rename_stage("2", "final").unwrap().content, indoc!(r#"
+      FROM alpine:3.12 as base
+      FROM golang:1.15 as build
+      RUN --mount=type=cache,target=/cache,from=base \
+        --mount=from=BASE,target=/b make
+      COPY --from=base /a /a
+      FROM base as final
+      COPY --from=0 /b /b
+      COPY --from=alpine:3.12 /c /c
+    "#));
+
+    // renaming to the same name with a different case is allowed
+    assert_eq!(
+      dockerfile.rename_stage("BUILD", "Build").unwrap().content.lines().nth(1),
+      Some("FROM golang:1.15 as Build")
+    );
+  }
+
+  #[test]
+  fn test_rename_stage_errors() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.12 as base
+      COPY --from=busybox /bin/sh /bin/sh
+      FROM golang:1.15 as build
+      FROM alpine as final
+    "#)).unwrap();
+
+    assert_eq!(
+      dockerfile.rename_stage("test", "foo").err().unwrap(),
+      RenameStageError::UnknownStage { name: "test".into() }
+    );
+    assert_eq!(
+      dockerfile.rename_stage("base", "0base").err().unwrap(),
+      RenameStageError::InvalidStageName { name: "0base".into() }
+    );
+    assert_eq!(
+      dockerfile.rename_stage("base", "BUILD").err().unwrap(),
+      RenameStageError::StageNameCollision { name: "BUILD".into(), stage: 1 }
+    );
+    assert_eq!(
+      dockerfile.rename_stage("build", "busybox").err().unwrap(),
+      RenameStageError::ImageNameCollision { name: "busybox".into(), span: Span::new(37, 44) }
+    );
+    assert_eq!(
+      dockerfile.rename_stage("base", "Alpine").err().unwrap(),
+      RenameStageError::ImageNameCollision { name: "alpine".into(), span: Span::new(92, 98) }
+    );
+
+    // FROM only refers to preceding stages, so this is not a collision
+    assert!(dockerfile.rename_stage("final", "alpine").is_ok());
+  }
+
+  #[test]
+  fn test_rename_stage_variables() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG BASE=build
+      FROM golang:1.15 as build
+      FROM ${BASE}
+      ARG SRC="Build"
+      ARG INDEX=0
+      COPY --from=$SRC /a /a
+      COPY --from=${INDEX} /b /b
+      FROM $BASE
+    "#)).unwrap();
+
+    assert_eq!(dockerfile.rename_stage("build", "compile").unwrap().content, indoc!(r#"
+      ARG BASE=compile
+      FROM golang:1.15 as compile
+      FROM ${BASE}
+      ARG SRC="compile"
+      ARG INDEX=0
+      COPY --from=$SRC /a /a
+      COPY --from=${INDEX} /b /b
+      FROM $BASE
+    "#));
+
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG BASE=build
+      FROM golang:1.15 as build
+      FROM ${MISSING:-build}
+      ARG BASE
+      ARG SRC=${BASE}
+      COPY --from=${SRC} /a /a
+    "#)).unwrap();
+
+    assert_eq!(
+      dockerfile.rename_stage("build", "compile").err().unwrap(),
+      RenameStageError::UnrewritableReferences { spans: vec![Span::new(46, 63), Span::new(101, 107)] }
+    );
+  }
+}
@@ -0,0 +1,741 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::error::SubstitutionError;
+use crate::expand::expand;
+use crate::instructions::ArgInstruction;
+use crate::platform::Platform;
+use crate::splicer::Span;
+use crate::stage::{Stage, StageParent, Stages};
+use crate::util::{BreakableStringComponent, SpannedString};
+
+/// Args automatically set from the target and build platforms. These are
+/// visible to `FROM` instructions, and within stages once declared.
+pub const PLATFORM_ARGS: &[&str] = &[
+  "TARGETPLATFORM", "TARGETOS", "TARGETARCH", "TARGETVARIANT",
+  "BUILDPLATFORM", "BUILDOS", "BUILDARCH", "BUILDVARIANT",
+];
+
+/// Proxy args, which are visible everywhere without being declared if passed
+/// as build args.
+pub const PROXY_ARGS: &[&str] = &[
+  "HTTP_PROXY", "http_proxy", "HTTPS_PROXY", "https_proxy",
+  "FTP_PROXY", "ftp_proxy", "NO_PROXY", "no_proxy",
+  "ALL_PROXY", "all_proxy",
+];
+
+/// Options for a build, used when evaluating variables.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildOptions {
+  /// Build args, as would be passed via `--build-arg`.
+  pub build_args: HashMap<String, String>,
+
+  /// The target platform, as would be passed via `--platform`. Defaults to
+  /// the build platform.
+  pub target_platform: Option<Platform>,
+
+  /// The platform of the builder.
+  pub build_platform: Option<Platform>,
+
+  /// Named build contexts, as would be passed via `--build-context`, mapped
+  /// to their sources.
+  pub build_contexts: HashMap<String, String>
+}
+
+impl BuildOptions {
+  /// Adds a build arg.
+  pub fn build_arg(mut self, name: impl Into<String>, value: impl Into<String>) -> BuildOptions {
+    self.build_args.insert(name.into(), value.into());
+    self
+  }
+
+  /// Adds a named build context.
+  pub fn build_context(mut self, name: impl Into<String>, source: impl Into<String>) -> BuildOptions {
+    self.build_contexts.insert(name.into(), source.into());
+    self
+  }
+
+  /// Returns the values of all predefined args: platform args, if any platform
+  /// is known, and any proxy args given as build args.
+  ///
+  /// Platform args can't be overridden by build args.
+  pub fn predefined_args(&self) -> Vec<(&'static str, String)> {
+    let mut args = Vec::new();
+
+    let target = self.target_platform.as_ref().or(self.build_platform.as_ref());
+    for (prefix, platform) in &[("TARGET", target), ("BUILD", self.build_platform.as_ref())] {
+      if let Some(platform) = platform {
+        let values = vec![
+          platform.to_string(),
+          platform.os.clone(),
+          platform.architecture.clone(),
+          platform.variant.clone().unwrap_or_default(),
+        ];
+
+        let names = PLATFORM_ARGS.iter().filter(|n| n.starts_with(prefix));
+        args.extend(names.copied().zip(values));
+      }
+    }
+
+    for name in PROXY_ARGS {
+      if let Some(value) = self.build_args.get(*name) {
+        args.push((*name, value.clone()));
+      }
+    }
+
+    args
+  }
+}
+
+/// The kind of instruction that declared a variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarKind {
+  Arg,
+  Env,
+
+  /// A predefined platform or proxy arg.
+  Predefined
+}
+
+/// A variable in scope at some point in a Dockerfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Var {
+  pub name: String,
+
+  /// The variable's expanded value. ARGs declared without a default value and
+  /// without a matching build arg are in scope but unset.
+  pub value: Option<String>,
+
+  pub kind: VarKind,
+
+  /// The span of the variable name in its declaring `ARG` or `ENV`
+  /// instruction. Variables inherited from a parent stage refer to the
+  /// parent's declaration. Predefined args have no span.
+  pub span: Option<Span>
+}
+
+/// The set of variables visible at some point in a Dockerfile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Scope {
+  vars: BTreeMap<String, Var>
+}
+
+impl Scope {
+  /// Returns the variable with the given name, if in scope.
+  pub fn get(&self, name: &str) -> Option<&Var> {
+    self.vars.get(name)
+  }
+
+  /// Returns the value of the given variable, if in scope and set.
+  pub fn value(&self, name: &str) -> Option<&str> {
+    self.get(name).and_then(|v| v.value.as_deref())
+  }
+
+  /// Returns an iterator over all variables in scope, ordered by name.
+  pub fn iter(&self) -> impl Iterator<Item = &Var> {
+    self.vars.values()
+  }
+
+  /// Returns the values of all set variables, e.g. for use with
+  /// `expand_vars(...)`.
+  pub fn values(&self) -> HashMap<&str, &str> {
+    self.vars
+      .values()
+      .filter_map(|v| v.value.as_deref().map(|value| (v.name.as_str(), value)))
+      .collect()
+  }
+
+  /// Expands variable references in the given string as Docker would, i.e.
+  /// references to unset variables expand to an empty string.
+  ///
+  /// See `expand_vars(...)` for the supported syntax.
+  pub fn expand(&self, s: &str) -> Result<String, SubstitutionError> {
+    self.expand_recording(s, &mut Vec::new())
+  }
+
+  /// Like `expand(...)`, but records references to unset variables.
+  pub(crate) fn expand_recording(
+    &self, s: &str, undefined: &mut Vec<(String, Span)>
+  ) -> Result<String, SubstitutionError> {
+    let lookup = |name: &str| self.value(name).map(String::from);
+
+    expand(s, &lookup, &mut HashSet::new(), Some(undefined), true)
+  }
+
+  /// Like `expand(...)`, but treats quotes as ordinary characters, e.g. for
+  /// the JSON arguments of exec form instructions.
+  pub(crate) fn expand_unquoted(&self, s: &str) -> Result<String, SubstitutionError> {
+    let lookup = |name: &str| self.value(name).map(String::from);
+
+    expand(s, &lookup, &mut HashSet::new(), Some(&mut Vec::new()), false)
+  }
+
+  /// Declares an ARG. Per the Dockerfile reference, an ENV with the same name
+  /// always takes precedence.
+  fn declare_arg(&mut self, var: Var) {
+    match self.vars.get(&var.name) {
+      Some(existing) if existing.kind == VarKind::Env => (),
+      _ => {
+        self.vars.insert(var.name.clone(), var);
+      }
+    }
+  }
+
+  /// Declares a variable, replacing any existing variable with its name.
+  pub(crate) fn declare(&mut self, var: Var) {
+    self.vars.insert(var.name.clone(), var);
+  }
+
+  fn declare_predefined(&mut self, name: &str, value: String) {
+    self.vars.insert(name.to_string(), Var {
+      name: name.to_string(),
+      value: Some(value),
+      kind: VarKind::Predefined,
+      span: None
+    });
+  }
+
+  fn declare_env(&mut self, var: Var) {
+    self.vars.insert(var.name.clone(), var);
+  }
+
+  /// Returns a copy of this scope containing only ENV variables, i.e. those
+  /// persisted in a stage's image and inherited by child stages.
+  fn env_only(&self) -> Scope {
+    Scope {
+      vars: self.vars
+        .iter()
+        .filter(|(_, v)| v.kind == VarKind::Env)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+    }
+  }
+}
+
+/// An error encountered while expanding a value.
+///
+/// When evaluating an `ARG` or `ENV`, the variable is left unset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeError {
+  /// The span of the `ARG` or `ENV` instruction when evaluating scopes, or of
+  /// the value when expanding instructions.
+  pub span: Span,
+
+  /// The expansion error, with spans relative to the unexpanded value.
+  pub error: SubstitutionError
+}
+
+/// The variables visible within a single build stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageScopes {
+  /// The scope visible to each of the stage's instructions, in the same order
+  /// as `Stage::instructions`. The `FROM` instruction sees only global ARGs.
+  pub scopes: Vec<Scope>,
+
+  /// The scope after the stage's last instruction.
+  pub end: Scope,
+
+  /// Any errors encountered while expanding values in this stage.
+  pub errors: Vec<ScopeError>
+}
+
+/// Evaluates the `ARG` and `ENV` instructions of a Dockerfile, producing the
+/// variables visible at each instruction following Docker's scoping rules:
+///  * global ARGs (those before the first `FROM`) are visible to `FROM`
+///    instructions, but only within a stage once re-declared there by an
+///    `ARG` without a value
+///  * build args override ARG defaults, but only for declared ARGs
+///  * ENV variables always take precedence over ARGs of the same name
+///  * ENV variables (but not ARGs) are inherited from parent stages
+///  * platform args are predefined for `FROM` instructions, and proxy args
+///    everywhere (see `BuildOptions`)
+///
+/// # Example
+/// ```
+/// use dockerfile_parser::*;
+///
+/// let dockerfile = Dockerfile::parse(r#"
+///   ARG version=3.12
+///   FROM alpine:$version as build
+///   ARG version
+///   ENV dest=/opt/v$version
+///
+///   FROM build
+///   RUN echo $dest
+/// "#)?;
+///
+/// let evaluator = Evaluator::new(&dockerfile, vec![("version", "3.13")]);
+/// let build = evaluator.stage(0).unwrap();
+/// assert_eq!(build.scopes[0].value("version"), Some("3.13"));
+/// assert_eq!(build.end.value("dest"), Some("/opt/v3.13"));
+///
+/// let child = evaluator.stage(1).unwrap();
+/// assert_eq!(child.scopes[1].value("dest"), Some("/opt/v3.13"));
+/// assert_eq!(child.scopes[1].value("version"), None);
+/// # Ok::<(), dockerfile_parser::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Evaluator<'a> {
+  dockerfile: &'a Dockerfile,
+  stages: Stages<'a>,
+  options: BuildOptions,
+  global: Scope,
+  global_errors: Vec<ScopeError>,
+  stage_scopes: Vec<StageScopes>
+}
+
+impl<'a> Evaluator<'a> {
+  /// Evaluates all stages of a Dockerfile given a set of build args, as would
+  /// be passed via `--build-arg`. No platform args are defined.
+  pub fn new<I, K, V>(dockerfile: &'a Dockerfile, build_args: I) -> Evaluator<'a>
+  where
+    I: IntoIterator<Item = (K, V)>,
+    K: Into<String>,
+    V: Into<String>
+  {
+    Evaluator::with_options(dockerfile, BuildOptions {
+      build_args: build_args.into_iter().map(|(k, v)| (k.into(), v.into())).collect(),
+      ..Default::default()
+    })
+  }
+
+  /// Evaluates all stages of a Dockerfile given a set of build options.
+  pub fn with_options(dockerfile: &'a Dockerfile, options: BuildOptions) -> Evaluator<'a> {
+    let mut evaluator = Evaluator {
+      dockerfile,
+      stages: Stages::with_options(dockerfile, &options),
+      options,
+      global: Scope::default(),
+      global_errors: Vec::new(),
+      stage_scopes: Vec::new()
+    };
+
+    for (name, value) in evaluator.options.predefined_args() {
+      evaluator.global.declare_predefined(name, value);
+    }
+
+    for arg in &dockerfile.global_args {
+      let mut global = evaluator.global.clone();
+      let mut errors = Vec::new();
+      evaluator.declare_arg(&mut global, arg, &mut errors);
+
+      evaluator.global = global;
+      evaluator.global_errors.extend(errors);
+    }
+
+    for index in 0..evaluator.stages.stages.len() {
+      let scopes = evaluator.evaluate_stage(index);
+      evaluator.stage_scopes.push(scopes);
+    }
+
+    evaluator
+  }
+
+  /// The evaluated Dockerfile.
+  pub fn dockerfile(&self) -> &'a Dockerfile {
+    self.dockerfile
+  }
+
+  /// The stages of the evaluated Dockerfile.
+  pub fn stages(&self) -> &Stages<'a> {
+    &self.stages
+  }
+
+  /// The options used for evaluation.
+  pub fn options(&self) -> &BuildOptions {
+    &self.options
+  }
+
+  /// The global scope, containing ARGs declared before the first `FROM`.
+  pub fn global_scope(&self) -> &Scope {
+    &self.global
+  }
+
+  /// Returns the scopes of the stage with the given index.
+  pub fn stage(&self, index: usize) -> Option<&StageScopes> {
+    self.stage_scopes.get(index)
+  }
+
+  /// Returns the scope visible to an instruction, given its stage index and
+  /// its index within that stage.
+  pub fn scope_at(&self, stage: usize, instruction: usize) -> Option<&Scope> {
+    self.stage(stage).and_then(|s| s.scopes.get(instruction))
+  }
+
+  /// Returns the platform a stage is built for: its `FROM --platform` flag
+  /// (with any variables expanded), or otherwise the target platform.
+  ///
+  /// Returns None if the platform is unknown or invalid.
+  pub fn stage_platform(&self, index: usize) -> Option<Platform> {
+    let stage = self.stages.stages.get(index)?;
+    let from = match stage.instructions.first() {
+      Some(Instruction::From(from)) => from,
+      _ => return None
+    };
+
+    let flag = from.flags
+      .iter()
+      .find(|f| f.name.as_ref().eq_ignore_ascii_case("platform"));
+
+    match flag {
+      Some(flag) => Platform::parse(&self.global.expand(flag.value.as_ref()).ok()?),
+      None => self.options.target_platform.clone().or_else(|| self.options.build_platform.clone())
+    }
+  }
+
+  /// Returns all errors encountered while evaluating the Dockerfile, global
+  /// ARGs first.
+  pub fn errors(&self) -> impl Iterator<Item = &ScopeError> {
+    self.global_errors
+      .iter()
+      .chain(self.stage_scopes.iter().flat_map(|s| s.errors.iter()))
+  }
+
+  fn declare_arg(&self, scope: &mut Scope, arg: &ArgInstruction, errors: &mut Vec<ScopeError>) {
+    let name = arg.name.as_ref();
+
+    let predefined = self.global
+      .get(name)
+      .filter(|v| v.kind == VarKind::Predefined && PLATFORM_ARGS.contains(&name));
+
+    let value = if let Some(var) = predefined {
+      var.value.clone()
+    } else if let Some(value) = self.options.build_args.get(name) {
+      Some(value.clone())
+    } else if let Some(default) = &arg.value {
+      // expand the original text, as quotes affect expansion
+      let content = &self.dockerfile().content;
+      match scope.expand(&content[default.span.start..default.span.end]) {
+        Ok(value) => Some(value),
+        Err(error) => {
+          errors.push(ScopeError { span: arg.span, error });
+          None
+        }
+      }
+    } else {
+      // a re-declared global arg, or an unset arg
+      self.global.value(name).map(String::from)
+    };
+
+    scope.declare_arg(Var {
+      name: name.to_string(),
+      value,
+      kind: VarKind::Arg,
+      span: Some(arg.name.span)
+    });
+  }
+
+  fn evaluate_stage(&self, index: usize) -> StageScopes {
+    let stage = &self.stages[index];
+
+    let mut scope = match stage.parent {
+      StageParent::Stage(parent) => self.stage_scopes[parent].end.env_only(),
+      _ => Scope::default()
+    };
+
+    for (name, value) in self.options.predefined_args() {
+      if PROXY_ARGS.contains(&name) {
+        scope.declare_predefined(name, value);
+      }
+    }
+
+    let mut scopes = Vec::new();
+    let mut errors = Vec::new();
+
+    for ins in &stage.instructions {
+      match ins {
+        Instruction::From(_) => {
+          scopes.push(self.global.clone());
+          continue;
+        },
+        _ => scopes.push(scope.clone())
+      }
+
+      match ins {
+        Instruction::Arg(arg) => self.declare_arg(&mut scope, arg, &mut errors),
+        Instruction::Env(env) => {
+          // all values in a single ENV instruction are expanded using the
+          // scope preceding it
+          let before = scope.clone();
+          let content = &self.dockerfile().content;
+
+          for var in &env.vars {
+            let raw: String = var.value.components
+              .iter()
+              .filter_map(|component| match component {
+                BreakableStringComponent::String(s) => Some(&content[s.span.start..s.span.end]),
+                BreakableStringComponent::Comment(_) => None
+              })
+              .collect();
+
+            let value = match before.expand(&raw) {
+              Ok(value) => Some(value),
+              Err(error) => {
+                errors.push(ScopeError { span: env.span, error });
+                None
+              }
+            };
+
+            scope.declare_env(Var {
+              name: var.key.as_ref().to_string(),
+              value,
+              kind: VarKind::Env,
+              span: Some(var.key.span)
+            });
+          }
+        },
+        _ => ()
+      }
+    }
+
+    StageScopes { scopes, end: scope, errors }
+  }
+}
+
+/// Returns the name of the variable if `s` is a single variable reference,
+/// e.g. `$BASE` or `${BASE}`.
+fn sole_var(s: &str) -> Option<&str> {
+  lazy_static! {
+    static ref SOLE_VAR: Regex = Regex::new(r"^\$(?:([a-zA-Z_]\w*)|\{([a-zA-Z_]\w*)\})$").unwrap();
+  }
+
+  let captures = SOLE_VAR.captures(s)?;
+  captures.get(1).or_else(|| captures.get(2)).map(|m| m.as_str())
+}
+
+/// Finds the `ARG` providing the default value of `name` at `position` within
+/// a stage: the last stage `ARG` with a value, or for `ARG`s redeclared
+/// without a value (and references in `FROM`), the last global `ARG`.
+fn default_arg<'a>(
+  dockerfile: &'a Dockerfile, stage: &Stage<'a>, position: usize, name: &str
+) -> Option<&'a ArgInstruction> {
+  let from = stage.instructions[0].span();
+  let global = || dockerfile.global_args
+    .iter()
+    .rev()
+    .find(|arg| arg.span.end <= from.start && arg.name.as_ref() == name);
+
+  if position < from.end {
+    return global();
+  }
+
+  let stage_arg = stage.instructions
+    .iter()
+    .rev()
+    .filter_map(|ins| ins.as_arg())
+    .find(|arg| arg.span.start < position && arg.name.as_ref() == name)?;
+
+  if stage_arg.value.is_some() {
+    Some(stage_arg)
+  } else {
+    global()
+  }
+}
+
+/// If the text at `span` is a single variable reference, returns the value of
+/// the `ARG` providing its default at that position, as long as the value is a
+/// literal without any variable references of its own.
+pub(crate) fn literal_default<'a>(
+  dockerfile: &'a Dockerfile, stage: &Stage<'a>, span: Span
+) -> Option<&'a SpannedString> {
+  let name = sole_var(&dockerfile.content[span.start..span.end])?;
+  let value = default_arg(dockerfile, stage, span.start, name)?.value.as_ref()?;
+
+  if dockerfile.content[value.span.start..value.span.end].contains('$') {
+    None
+  } else {
+    Some(value)
+  }
+}
+
+/// Rewrites the content of a possibly-quoted literal, keeping its quotes.
+pub(crate) fn rewrite_literal(literal: &str, f: impl FnOnce(&str) -> String) -> String {
+  match literal.chars().next() {
+    Some(quote @ ('"' | '\'')) if literal.len() > 1 && literal.ends_with(quote) => {
+      format!("{}{}{}", quote, f(&literal[1..literal.len() - 1]), quote)
+    },
+    _ => f(literal)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  fn values(scope: &Scope) -> Vec<(&str, Option<&str>)> {
+    scope.iter().map(|v| (v.name.as_str(), v.value.as_deref())).collect()
+  }
+
+  #[test]
+  fn test_sole_var() {
+    assert_eq!(sole_var("$BASE"), Some("BASE"));
+    assert_eq!(sole_var("${BASE}"), Some("BASE"));
+    assert_eq!(sole_var("${BASE:-alpine}"), None);
+    assert_eq!(sole_var("alpine:${VERSION}"), None);
+  }
+
+  #[test]
+  fn test_arg_scoping() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG image=alpine
+      ARG tag=3.12
+      ARG full=$image:$tag
+      FROM $full
+      RUN echo $image
+      ARG image
+      ARG extra=${image}-x
+      ARG unset
+      RUN echo $image
+    "#)).unwrap();
+
+    let evaluator = Evaluator::new(&dockerfile, vec![("tag", "3.13"), ("other", "x")]);
+    assert_eq!(values(evaluator.global_scope()), vec![
+      ("full", Some("alpine:3.13")),
+      ("image", Some("alpine")),
+      ("tag", Some("3.13")),
+    ]);
+
+    let stage = evaluator.stage(0).unwrap();
+    assert_eq!(stage.scopes.len(), 6);
+    assert_eq!(stage.scopes[0], *evaluator.global_scope());
+    assert_eq!(values(&stage.scopes[1]), vec![]);
+    assert_eq!(values(&stage.scopes[2]), vec![]);
+    assert_eq!(values(&stage.scopes[3]), vec![("image", Some("alpine"))]);
+    assert_eq!(values(&stage.end), vec![
+      ("extra", Some("alpine-x")),
+      ("image", Some("alpine")),
+      ("unset", None),
+    ]);
+
+    let start = dockerfile.content.find("ARG image\n").unwrap() + "ARG ".len();
+    assert_eq!(stage.end.get("image").unwrap().span, Some(Span::new(start, start + "image".len())));
+    assert_eq!(evaluator.scope_at(0, 5), Some(&stage.end));
+    assert!(evaluator.errors().next().is_none());
+  }
+
+  #[test]
+  fn test_env_scoping() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM alpine as base
+      ARG a=arg
+      ENV a=env b=$a
+      ARG a=arg2
+      ARG c=c
+
+      FROM base as child
+      ENV d=$b-$c
+
+      FROM child
+      ENV e="${d:?must be set}" f=${c:?}
+    "#)).unwrap();
+
+    let evaluator = Evaluator::new(&dockerfile, vec![("c", "override")]);
+
+    let base = evaluator.stage(0).unwrap();
+    assert_eq!(values(&base.end), vec![
+      ("a", Some("env")),
+      ("b", Some("arg")),
+      ("c", Some("override")),
+    ]);
+    assert_eq!(base.end.get("a").unwrap().kind, VarKind::Env);
+
+    // ARGs are not inherited by child stages
+    let child = evaluator.stage(1).unwrap();
+    assert_eq!(values(&child.scopes[1]), vec![("a", Some("env")), ("b", Some("arg"))]);
+    assert_eq!(child.end.value("d"), Some("arg-"));
+
+    let grandchild = evaluator.stage(2).unwrap();
+    assert_eq!(grandchild.end.value("e"), Some("arg-"));
+    assert_eq!(grandchild.end.get("f").unwrap().value, None);
+    assert_eq!(evaluator.errors().collect::<Vec<_>>(), vec![&ScopeError {
+      span: dockerfile.instructions[8].span(),
+      error: SubstitutionError::RequiredVariable {
+        name: "c".into(),
+        message: "is not allowed to be unset".into(),
+        span: Span::new(0, 6)
+      }
+    }]);
+  }
+
+  #[test]
+  fn test_quoted_values() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG x=1
+      FROM alpine
+      ARG x
+      ARG y='$x'
+      ARG z="$x"
+      ENV a='$x' b="$x" c=pre'$x' d="a'$x'b" e=\$x
+      ENV f pre '$x' "$x"
+      ENV g pre\
+      "$x"
+    "#)).unwrap();
+
+    let evaluator = Evaluator::new(&dockerfile, Vec::<(&str, &str)>::new());
+    let stage = evaluator.stage(0).unwrap();
+    assert_eq!(values(&stage.end), vec![
+      ("a", Some("$x")),
+      ("b", Some("1")),
+      ("c", Some("pre$x")),
+      ("d", Some("a'1'b")),
+      ("e", Some("$x")),
+      ("f", Some("pre $x 1")),
+      ("g", Some("pre1")),
+      ("x", Some("1")),
+      ("y", Some("$x")),
+      ("z", Some("1")),
+    ]);
+    assert_eq!(evaluator.errors().count(), 0);
+  }
+
+  #[test]
+  fn test_predefined_args() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      FROM --platform=$BUILDPLATFORM golang:1.15 as build
+      ARG TARGETARCH
+      ARG BUILDOS=ignored
+      RUN GOARCH=$TARGETARCH go build
+
+      FROM alpine
+    "#)).unwrap();
+
+    let options = BuildOptions {
+      target_platform: Platform::parse("linux/arm/v7"),
+      build_platform: Platform::parse("linux/amd64"),
+      ..Default::default()
+    }
+      .build_arg("TARGETARCH", "ignored")
+      .build_arg("http_proxy", "http://proxy:3128");
+
+    let evaluator = Evaluator::with_options(&dockerfile, options);
+    assert_eq!(values(evaluator.global_scope()), vec![
+      ("BUILDARCH", Some("amd64")),
+      ("BUILDOS", Some("linux")),
+      ("BUILDPLATFORM", Some("linux/amd64")),
+      ("BUILDVARIANT", Some("")),
+      ("TARGETARCH", Some("arm")),
+      ("TARGETOS", Some("linux")),
+      ("TARGETPLATFORM", Some("linux/arm/v7")),
+      ("TARGETVARIANT", Some("v7")),
+      ("http_proxy", Some("http://proxy:3128")),
+    ]);
+
+    let build = evaluator.stage(0).unwrap();
+    assert_eq!(values(&build.scopes[1]), vec![("http_proxy", Some("http://proxy:3128"))]);
+    assert_eq!(values(&build.end), vec![
+      ("BUILDOS", Some("linux")),
+      ("TARGETARCH", Some("arm")),
+      ("http_proxy", Some("http://proxy:3128")),
+    ]);
+
+    assert_eq!(evaluator.stage_platform(0), Platform::parse("linux/amd64"));
+    assert_eq!(evaluator.stage_platform(1), Platform::parse("linux/arm/v7"));
+    assert_eq!(Evaluator::new(&dockerfile, Vec::<(String, String)>::new()).stage_platform(1), None);
+  }
+}
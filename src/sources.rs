@@ -0,0 +1,207 @@
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::error::SubstitutionError;
+use crate::image::ImageRef;
+use crate::scope::{BuildOptions, Evaluator};
+use crate::splicer::Span;
+use crate::stage::{StageParent, Stages};
+
+/// The source referred to by a `COPY --from` value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CopyFromSource {
+  /// A stage in the current Dockerfile, referred to by name or index.
+  Stage(usize),
+
+  /// An external image.
+  Image(ImageRef),
+
+  /// A named build context, as passed via `--build-context`.
+  NamedContext(String),
+
+  /// A value that could not be expanded, e.g. due to a failed
+  /// `${name:?message}` check. Its source is unknown.
+  Unresolved(SubstitutionError)
+}
+
+/// A `COPY --from` flag and the source it refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyFrom {
+  /// The index of the stage containing the `COPY` instruction.
+  pub stage: usize,
+
+  /// The span of the flag's value.
+  pub span: Span,
+
+  /// The flag's value, after variable expansion if successful, otherwise as
+  /// written.
+  pub value: String,
+
+  pub source: CopyFromSource
+}
+
+/// Classifies a `--from` value as BuildKit would: stage names and indices take
+/// precedence over named build contexts, and anything else is an image.
+pub(crate) fn classify_from(stages: &Stages, options: &BuildOptions, value: &str) -> CopyFromSource {
+  if let Some(stage) = stages.get(value) {
+    CopyFromSource::Stage(stage.index)
+  } else if options.build_contexts.contains_key(value) {
+    CopyFromSource::NamedContext(value.to_string())
+  } else {
+    CopyFromSource::Image(ImageRef::parse(value))
+  }
+}
+
+impl<'a> Evaluator<'a> {
+  /// Classifies the value of every `COPY --from` flag in the Dockerfile as a
+  /// stage, named build context or external image, after expanding any
+  /// variables. Values that fail to expand are `CopyFromSource::Unresolved`.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   FROM golang:1.15 as build
+  ///   FROM alpine:3.12
+  ///   ARG NGINX=1.25
+  ///   COPY --from=build /app /app
+  ///   COPY --from=nginx:${NGINX} /etc/nginx /etc/nginx
+  ///   COPY --from=assets /static /static
+  /// "#)?;
+  ///
+  /// let options = BuildOptions::default().build_context("assets", "./assets");
+  /// let evaluator = Evaluator::with_options(&dockerfile, options);
+  /// let sources: Vec<_> = evaluator.copy_from_sources().into_iter().map(|c| c.source).collect();
+  /// assert_eq!(sources, vec![
+  ///   CopyFromSource::Stage(0),
+  ///   CopyFromSource::Image(ImageRef::parse("nginx:1.25")),
+  ///   CopyFromSource::NamedContext("assets".into()),
+  /// ]);
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn copy_from_sources(&self) -> Vec<CopyFrom> {
+    let content = &self.dockerfile().content;
+    let mut sources = Vec::new();
+
+    for stage in self.stages().iter() {
+      let scopes = &self.stage(stage.index).unwrap().scopes;
+
+      for (ins, scope) in stage.instructions.iter().zip(scopes) {
+        let copy = match ins {
+          Instruction::Copy(copy) => copy,
+          _ => continue
+        };
+
+        for flag in copy.flags.iter().filter(|f| f.name.as_ref() == "from") {
+          let span = flag.value.span;
+          let (value, source) = match scope.expand(&content[span.start..span.end]) {
+            Ok(value) => {
+              let source = classify_from(self.stages(), self.options(), &value);
+              (value, source)
+            },
+            Err(error) => (flag.value.content.clone(), CopyFromSource::Unresolved(error))
+          };
+
+          sources.push(CopyFrom { stage: stage.index, span, value, source });
+        }
+      }
+    }
+
+    sources
+  }
+
+  /// Returns every external image the build may pull, in order of first use:
+  /// resolved `FROM` parents that are not stages, named build contexts or
+  /// `scratch`, and `COPY --from` images. Images that could not be resolved
+  /// are not included.
+  pub fn external_images(&self) -> Vec<ImageRef> {
+    let mut images: Vec<ImageRef> = Vec::new();
+    let copies = self.copy_from_sources();
+
+    for stage in self.stages().iter() {
+      if let (StageParent::Image(_), Some(image)) = (&stage.parent, stage.resolved_parent.as_ref()) {
+        if !images.contains(image) {
+          images.push(image.clone());
+        }
+      }
+
+      for copy in copies.iter().filter(|c| c.stage == stage.index) {
+        if let CopyFromSource::Image(image) = &copy.source {
+          if !images.contains(image) {
+            images.push(image.clone());
+          }
+        }
+      }
+    }
+
+    images
+  }
+}
+
+impl Dockerfile {
+  /// Classifies the value of every `COPY --from` flag, without any build args
+  /// or named build contexts. See `Evaluator::copy_from_sources`.
+  pub fn copy_from_sources(&self) -> Vec<CopyFrom> {
+    Evaluator::with_options(self, BuildOptions::default()).copy_from_sources()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use indoc::indoc;
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  #[test]
+  fn test_copy_from_sources() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG BASE=ubuntu:20.04
+      FROM alpine:3.12 as build
+      FROM ${BASE}
+      ARG FROM_STAGE=build
+      COPY --from=0 /a /a
+      COPY --from=$FROM_STAGE /b /b
+      COPY --from=${missing:?required} /c /c
+      COPY --from=nginx:1.25 /d /d
+      COPY --from=alpine:3.12 /e /e
+      COPY --from=src /f /f
+
+      FROM src
+      FROM ubuntu:20.04
+    "#)).unwrap();
+
+    let sources: Vec<(usize, String, CopyFromSource)> = dockerfile
+      .copy_from_sources()
+      .into_iter()
+      .map(|c| (c.stage, c.value, c.source))
+      .collect();
+    assert_eq!(sources, vec![
+      (1, "0".into(), CopyFromSource::Stage(0)),
+      (1, "build".into(), CopyFromSource::Stage(0)),
+      (1, "${missing:?required}".into(), CopyFromSource::Unresolved(
+        SubstitutionError::RequiredVariable {
+          name: "missing".into(),
+          message: "required".into(),
+          span: Span::new(0, 20)
+        }
+      )),
+      (1, "nginx:1.25".into(), CopyFromSource::Image(ImageRef::parse("nginx:1.25"))),
+      (1, "alpine:3.12".into(), CopyFromSource::Image(ImageRef::parse("alpine:3.12"))),
+      (1, "src".into(), CopyFromSource::Image(ImageRef::parse("src"))),
+    ]);
+
+    let options = BuildOptions::default().build_context("src", "../src");
+    let evaluator = Evaluator::with_options(&dockerfile, options);
+    assert_eq!(
+      evaluator.copy_from_sources()[5].source,
+      CopyFromSource::NamedContext("src".into())
+    );
+    assert_eq!(evaluator.stages()[2].parent, StageParent::NamedContext("src".into()));
+
+    assert_eq!(evaluator.external_images(), vec![
+      ImageRef::parse("alpine:3.12"),
+      ImageRef::parse("ubuntu:20.04"),
+      ImageRef::parse("nginx:1.25"),
+    ]);
+  }
+}
@@ -4,13 +4,44 @@ use std::convert::TryInto;
 use std::fmt;
 
 use crate::parser::Pair;
-use crate::dockerfile_parser::Dockerfile;
+use crate::dockerfile_parser::{Dockerfile, Instruction};
+use crate::edit::{full_line_span, indentation_at, line_ending};
+use crate::error::SpliceError;
+
+/// Returns the offset of the end of any parser directives (e.g.
+/// `# syntax=docker/dockerfile:1`) at the start of the document.
+pub(crate) fn directives_end(content: &str) -> usize {
+  let mut end = 0;
+
+  for line in content.split_inclusive('\n') {
+    let directive = line
+      .trim()
+      .strip_prefix('#')
+      .and_then(|d| d.split_once('='))
+      .map(|(key, _)| {
+        let key = key.trim();
+        !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+      })
+      .unwrap_or(false);
+
+    if !directive {
+      break;
+    }
+
+    end += line.len();
+  }
+
+  end
+}
 
 /// An offset used to adjust proceeding Spans after content has been spliced
 #[derive(Debug)]
 struct SpliceOffset {
   position: usize,
-  offset: isize
+  offset: isize,
+
+  /// If true, the splice inserted content without replacing anything
+  insertion: bool
 }
 
 /// A byte-index tuple representing a span of characters in a string
@@ -25,6 +56,24 @@ impl Span {
     Span { start, end }
   }
 
+  /// Returns true if this span is empty, i.e. an insertion point.
+  pub fn is_empty(&self) -> bool {
+    self.start >= self.end
+  }
+
+  /// Returns true if this span overlaps another.
+  ///
+  /// Empty spans overlap another span only if they fall strictly inside it,
+  /// or if both are empty and share the same position.
+  pub fn overlaps(&self, other: &Span) -> bool {
+    match (self.is_empty(), other.is_empty()) {
+      (true, true) => self.start == other.start,
+      (true, false) => other.start < self.start && self.start < other.end,
+      (false, true) => self.start < other.start && other.start < self.end,
+      (false, false) => self.start < other.end && other.start < self.end,
+    }
+  }
+
   pub(crate) fn from_pair(record: &Pair) -> Span {
     let pest_span = record.as_span();
 
@@ -39,7 +88,12 @@ impl Span {
     let mut end = self.end as isize;
 
     for splice in offsets {
-      if splice.position < start as usize {
+      // content inserted exactly at the start of a span belongs in front of it,
+      // including earlier insertions at the same position
+      let before = splice.position < start as usize
+        || (splice.insertion && splice.position == start as usize);
+
+      if before {
         start += splice.offset;
         end += splice.offset;
       } else if splice.position < end as usize {
@@ -59,6 +113,9 @@ impl Span {
   /// A reference to the Dockerfile is necessary to examine the original input
   /// string. Note that if the original span crosses a newline boundary, the
   /// relative span's `end` field will be larger than the line length.
+  ///
+  /// This scans the input on each call; when converting many spans, or spans
+  /// that cross lines, use `Dockerfile::line_index()` instead.
   pub fn relative_span(&self, dockerfile: &Dockerfile) -> (usize, Span) {
     let mut line_start_offset = 0;
     let mut lines = 0;
@@ -88,7 +145,7 @@ impl From<(usize, usize)> for Span {
 
 impl From<&Pair<'_>> for Span {
   fn from(pair: &Pair<'_>) -> Self {
-    Span::from_pair(&pair)
+    Span::from_pair(pair)
   }
 }
 
@@ -101,6 +158,26 @@ impl fmt::Debug for Span {
   }
 }
 
+/// A single replacement recorded by a `Splicer`, relative to the original
+/// input document.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SpliceEdit {
+  pub span: Span,
+  pub replacement: String
+}
+
+impl SpliceEdit {
+  pub fn new(span: Span, replacement: impl Into<String>) -> SpliceEdit {
+    SpliceEdit { span, replacement: replacement.into() }
+  }
+
+  /// Returns true if this edit cannot be applied alongside another, i.e. their
+  /// spans overlap and they are not identical.
+  pub fn conflicts_with(&self, other: &SpliceEdit) -> bool {
+    self != other && self.span.overlaps(&other.span)
+  }
+}
+
 /// A utility to repeatedly replace spans of text within a larger document.
 ///
 /// Each subsequent call to `Splicer::splice(...)` rewrites the `content` buffer
@@ -133,25 +210,84 @@ pub struct Splicer {
   /// The current content of the splice buffer.
   pub content: String,
 
-  splice_offsets: Vec<SpliceOffset>
+  original: String,
+  splice_offsets: Vec<SpliceOffset>,
+  edits: Vec<SpliceEdit>
 }
 
 impl Splicer {
   /// Creates a new Splicer from the given Dockerfile.
   pub(crate) fn from(dockerfile: &Dockerfile) -> Splicer {
-    Splicer {
-      content: dockerfile.content.clone(),
-      splice_offsets: Vec::new()
-    }
+    Splicer::from_str(&dockerfile.content)
   }
 
   pub(crate) fn from_str(s: &str) -> Splicer {
     Splicer {
       content: s.to_string(),
-      splice_offsets: Vec::new()
+      original: s.to_string(),
+      splice_offsets: Vec::new(),
+      edits: Vec::new()
     }
   }
 
+  /// The original input document, before any splices were applied.
+  pub fn original(&self) -> &str {
+    &self.original
+  }
+
+  /// All edits applied so far, in the order they were applied.
+  pub fn edits(&self) -> &[SpliceEdit] {
+    &self.edits
+  }
+
+  /// Merges all applied edits into a sorted list of non-overlapping changed
+  /// regions, each given as a span in the original document and the
+  /// corresponding span in the current `content`.
+  pub(crate) fn changed_regions(&self) -> Vec<(Span, Span)> {
+    let mut edits: Vec<(Span, isize)> = self.edits
+      .iter()
+      .zip(self.splice_offsets.iter())
+      .map(|(edit, splice)| (edit.span, splice.offset))
+      .collect();
+    edits.sort_by_key(|(span, _)| *span);
+
+    let mut merged: Vec<(Span, isize)> = Vec::new();
+    for (span, offset) in edits {
+      match merged.last_mut() {
+        Some((last, last_offset)) if span.start <= last.end => {
+          last.end = last.end.max(span.end);
+          *last_offset += offset;
+        },
+        _ => merged.push((span, offset))
+      }
+    }
+
+    let mut shift = 0;
+    merged
+      .into_iter()
+      .map(|(span, offset)| {
+        let start = (span.start as isize + shift) as usize;
+        let end = (span.end as isize + shift + offset) as usize;
+        shift += offset;
+
+        (span, Span::new(start, end))
+      })
+      .collect()
+  }
+
+  /// Ensures a span is valid for the original input document.
+  fn validate(&self, span: &Span) -> Result<(), SpliceError> {
+    if span.start > span.end || span.end > self.original.len() {
+      return Err(SpliceError::OutOfRange { span: *span, len: self.original.len() });
+    }
+
+    if !self.original.is_char_boundary(span.start) || !self.original.is_char_boundary(span.end) {
+      return Err(SpliceError::NotCharBoundary { span: *span });
+    }
+
+    Ok(())
+  }
+
   /// Replaces a Span with the given replacement string, mutating the `content`
   /// string.
   ///
@@ -161,6 +297,7 @@ impl Splicer {
   /// Span offsets are recalculated at call-time to account for previous calls
   /// to `splice(...)` that may have shifted one or both of the span bounds.
   pub fn splice(&mut self, span: &Span, replacement: &str) {
+    self.edits.push(SpliceEdit::new(*span, replacement));
     let span = span.adjust_offsets(&self.splice_offsets);
 
     // determine the splice offset (only used on subsequent splices)
@@ -168,7 +305,7 @@ impl Splicer {
     let new_len = replacement.len();
     let offset = new_len as isize - prev_len as isize;
     self.splice_offsets.push(
-      SpliceOffset { position: span.start, offset }
+      SpliceOffset { position: span.start, offset, insertion: prev_len == 0 }
     );
 
     // split and rebuild the content with the replacement instead
@@ -176,6 +313,245 @@ impl Splicer {
     let (_, end) = rest.split_at(span.end - span.start);
     self.content = format!("{}{}{}", beginning, replacement, end);
   }
+
+  /// Like `splice(...)`, but returns an error rather than panicking if the span
+  /// is out of range, does not fall on a character boundary, or overlaps a
+  /// previously applied edit.
+  pub fn try_splice(&mut self, span: &Span, replacement: &str) -> Result<(), SpliceError> {
+    let mut transaction = self.transaction();
+    transaction.splice(span, replacement);
+    transaction.commit()
+  }
+
+  /// Begins a new transaction that collects edits and applies them atomically.
+  pub fn transaction(&mut self) -> SpliceTransaction<'_> {
+    SpliceTransaction {
+      splicer: self,
+      edits: Vec::new()
+    }
+  }
+
+  /// Determines the span of an instruction's full lines in the original
+  /// document, including its indentation, trailing line ending, and any
+  /// comment lines directly above it. Parser directives at the start of the
+  /// document are never considered attached.
+  pub(crate) fn attached_span(&self, instruction: &Instruction) -> Span {
+    let mut span = full_line_span(&self.original, &instruction.span());
+    let directives_end = directives_end(&self.original);
+
+    while span.start > directives_end {
+      let prev_start = self.original[..span.start - 1]
+        .rfind('\n')
+        .map(|i| i + 1)
+        .unwrap_or(0);
+
+      if !self.original[prev_start..span.start].trim_start().starts_with('#') {
+        break;
+      }
+
+      span.start = prev_start;
+    }
+
+    span
+  }
+
+  /// Formats a block of lines to be inserted with the given indentation,
+  /// removing any existing indentation shared by its lines.
+  fn format_block(&self, text: &str, indent: &str, strip: &str) -> String {
+    let newline = line_ending(&self.original);
+
+    text
+      .lines()
+      .map(|line| {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        format!("{}{}{}", indent, line.strip_prefix(strip).unwrap_or(line), newline)
+      })
+      .collect()
+  }
+
+  /// Inserts a new instruction (or any other lines) on its own line before the
+  /// given instruction and any comments attached to it, matching its
+  /// indentation and the document's line endings.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   FROM alpine:3.10
+  ///   CMD ["server"]
+  /// "#)?;
+  ///
+  /// let mut splicer = dockerfile.splicer();
+  /// splicer.insert_before(&dockerfile.instructions[1], "USER nonroot").unwrap();
+  ///
+  /// assert_eq!(splicer.content, r#"
+  ///   FROM alpine:3.10
+  ///   USER nonroot
+  ///   CMD ["server"]
+  /// "#);
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn insert_before(&mut self, instruction: &Instruction, text: &str) -> Result<(), SpliceError> {
+    let span = self.attached_span(instruction);
+    let indent = indentation_at(&self.original, instruction.span().start);
+    let block = self.format_block(text, indent, "");
+
+    self.try_splice(&Span::new(span.start, span.start), &block)
+  }
+
+  /// Inserts a new instruction (or any other lines) on its own line after the
+  /// given instruction, matching its indentation and the document's line
+  /// endings.
+  pub fn insert_after(&mut self, instruction: &Instruction, text: &str) -> Result<(), SpliceError> {
+    let span = self.attached_span(instruction);
+    let indent = indentation_at(&self.original, instruction.span().start);
+    let mut block = self.format_block(text, indent, "");
+
+    if !self.original[..span.end].ends_with('\n') {
+      // the instruction is on the last line, which has no trailing newline
+      let newline = line_ending(&self.original);
+      block = format!("{}{}", newline, block.trim_end_matches(newline));
+    }
+
+    self.try_splice(&Span::new(span.end, span.end), &block)
+  }
+
+  /// Deletes an instruction along with its line ending and any comment lines
+  /// directly above it.
+  pub fn delete_instruction(&mut self, instruction: &Instruction) -> Result<(), SpliceError> {
+    let span = self.attached_span(instruction);
+
+    self.try_splice(&span, "")
+  }
+
+  /// Moves an instruction, along with its attached comments, to a new position
+  /// in the document.
+  fn move_to(
+    &mut self,
+    instruction: &Instruction,
+    target: &Instruction,
+    before: bool
+  ) -> Result<(), SpliceError> {
+    let span = self.attached_span(instruction);
+    let target_span = self.attached_span(target);
+    let position = if before { target_span.start } else { target_span.end };
+
+    let strip = indentation_at(&self.original, instruction.span().start);
+    let indent = indentation_at(&self.original, target.span().start);
+    let mut block = self.format_block(&self.original[span.start..span.end], indent, strip);
+
+    if !before && !self.original[..position].ends_with('\n') {
+      let newline = line_ending(&self.original);
+      block = format!("{}{}", newline, block.trim_end_matches(newline));
+    }
+
+    let mut transaction = self.transaction();
+    transaction.splice(&span, "");
+    transaction.splice(&Span::new(position, position), &block);
+    transaction.commit()
+  }
+
+  /// Moves an instruction, along with its attached comments, to just before
+  /// another instruction, adopting the target's indentation.
+  pub fn move_before(&mut self, instruction: &Instruction, target: &Instruction) -> Result<(), SpliceError> {
+    self.move_to(instruction, target, true)
+  }
+
+  /// Moves an instruction, along with its attached comments, to just after
+  /// another instruction, adopting the target's indentation.
+  pub fn move_after(&mut self, instruction: &Instruction, target: &Instruction) -> Result<(), SpliceError> {
+    self.move_to(instruction, target, false)
+  }
+}
+
+/// A batch of edits to be applied atomically to a `Splicer`.
+///
+/// Edits are collected with `splice(...)` and only applied by `commit()`, which
+/// first validates every span and checks for overlapping edits, both within the
+/// transaction and against edits previously applied to the `Splicer`. If any
+/// check fails, no edits are applied. Identical edits (e.g. the same fix
+/// proposed twice) are applied only once, and insertions at the same position
+/// as an insertion from an earlier transaction are placed after it.
+///
+/// Since all spans are relative to the original document, edits may be added in
+/// any order.
+///
+/// # Example
+/// ```
+/// use dockerfile_parser::*;
+///
+/// let dockerfile = Dockerfile::parse("FROM alpine:3.10 as build\n")?;
+/// let from = dockerfile.instructions[0].as_from().unwrap();
+///
+/// let mut splicer = dockerfile.splicer();
+/// let mut transaction = splicer.transaction();
+/// transaction.splice(&from.alias.as_ref().unwrap().span, "builder");
+/// transaction.splice(&from.image.span, "alpine:3.11");
+/// transaction.commit().unwrap();
+/// assert_eq!(splicer.content, "FROM alpine:3.11 as builder\n");
+///
+/// let mut transaction = splicer.transaction();
+/// transaction.splice(&Span::new(5, 11), "debian");
+/// match transaction.commit() {
+///   Err(SpliceError::Conflict { first, .. }) => assert_eq!(first.span, Span::new(5, 11)),
+///   _ => panic!("expected a conflict")
+/// }
+/// # Ok::<(), dockerfile_parser::Error>(())
+/// ```
+pub struct SpliceTransaction<'a> {
+  splicer: &'a mut Splicer,
+  edits: Vec<SpliceEdit>
+}
+
+impl<'a> SpliceTransaction<'a> {
+  /// Records a replacement of the given span, relative to the original input
+  /// document. The edit is not applied until `commit()` is called.
+  pub fn splice(&mut self, span: &Span, replacement: &str) {
+    self.edits.push(SpliceEdit::new(*span, replacement));
+  }
+
+  /// The edits recorded in this transaction so far.
+  pub fn edits(&self) -> &[SpliceEdit] {
+    &self.edits
+  }
+
+  /// Validates and applies all recorded edits.
+  ///
+  /// If any edit is invalid or two edits conflict, returns an error and leaves
+  /// the `Splicer` unchanged.
+  pub fn commit(self) -> Result<(), SpliceError> {
+    let mut pending: Vec<SpliceEdit> = Vec::new();
+
+    for edit in self.edits {
+      self.splicer.validate(&edit.span)?;
+
+      // insertions at the same position as a previously applied insertion are
+      // unambiguous: they are placed after it
+      let applied = self.splicer.edits
+        .iter()
+        .filter(|e| !(e.span.is_empty() && edit.span.is_empty()));
+
+      let mut existing = applied.chain(pending.iter());
+      if let Some(conflict) = existing.find(|e| e.conflicts_with(&edit)) {
+        return Err(SpliceError::Conflict { first: edit, second: conflict.clone() });
+      }
+
+      // identical edits, including insertions, are applied once
+      if self.splicer.edits.iter().chain(pending.iter()).all(|e| e != &edit) {
+        pending.push(edit);
+      }
+    }
+
+    // apply from the end of the document so insertions at the start of a
+    // replaced span stay in front of it
+    pending.sort_by_key(|e| std::cmp::Reverse(e.span));
+    for edit in pending {
+      self.splicer.splice(&edit.span, &edit.replacement);
+    }
+
+    Ok(())
+  }
 }
 
 #[cfg(test)]
@@ -184,6 +560,8 @@ mod tests {
   use indoc::indoc;
   use crate::*;
 
+  use super::directives_end;
+
   #[test]
   fn test_relative_span() {
     let d = Dockerfile::parse(indoc!(r#"
@@ -233,4 +611,233 @@ mod tests {
       (5, (12, 17).into())
     );
   }
+
+  #[test]
+  fn test_transaction() {
+    let d = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.10 as build
+      RUN echo "hello world"
+    "#)).unwrap();
+
+    let from = d.instructions[0].as_from().unwrap();
+    let run = d.instructions[1].as_run().unwrap();
+
+    let mut splicer = d.splicer();
+    let mut transaction = splicer.transaction();
+    transaction.splice(&run.span, "RUN echo hi");
+    transaction.splice(&Span::new(run.span.start, run.span.start), "USER nobody\n");
+    transaction.splice(&from.image.span, "alpine:3.11");
+    transaction.splice(&from.image.span, "alpine:3.11");
+    transaction.commit().unwrap();
+
+    assert_eq!(splicer.content, indoc!(r#"
+      FROM alpine:3.11 as build
+      USER nobody
+      RUN echo hi
+    "#));
+    assert_eq!(splicer.edits().len(), 3);
+
+    // later splices still use original spans
+    splicer.splice(&from.alias.as_ref().unwrap().span, "builder");
+    assert_eq!(splicer.content, indoc!(r#"
+      FROM alpine:3.11 as builder
+      USER nobody
+      RUN echo hi
+    "#));
+  }
+
+  #[test]
+  fn test_transaction_identical_edits() {
+    let d = Dockerfile::parse("FROM alpine:3.10\n").unwrap();
+    let mut splicer = d.splicer();
+
+    // identical edits repeated in later transactions are applied once
+    for _ in 0..2 {
+      let mut transaction = splicer.transaction();
+      transaction.splice(&Span::new(0, 0), "# syntax=docker/dockerfile:1\n");
+      transaction.splice(&Span::new(5, 16), "alpine:3.11");
+      transaction.commit().unwrap();
+    }
+
+    assert_eq!(splicer.content, "# syntax=docker/dockerfile:1\nFROM alpine:3.11\n");
+    assert_eq!(splicer.edits().len(), 2);
+  }
+
+  #[test]
+  fn test_transaction_conflicts() {
+    let d = Dockerfile::parse("FROM alpine:3.10 as build\n").unwrap();
+    let from = d.instructions[0].as_from().unwrap();
+
+    let mut splicer = d.splicer();
+
+    // overlapping edits within a transaction
+    let mut transaction = splicer.transaction();
+    transaction.splice(&from.image.span, "alpine:3.11");
+    transaction.splice(&Span::new(from.image.span.start + 7, from.image.span.end), "3.12");
+    match transaction.commit() {
+      Err(SpliceError::Conflict { first, second }) => {
+        assert_eq!(first.replacement, "3.12");
+        assert_eq!(second.replacement, "alpine:3.11");
+      },
+      other => panic!("expected conflict, got {:?}", other)
+    }
+    assert_eq!(splicer.content, d.content);
+    assert!(splicer.edits().is_empty());
+
+    // two different insertions at the same position conflict
+    let mut transaction = splicer.transaction();
+    transaction.splice(&Span::new(0, 0), "# a\n");
+    transaction.splice(&Span::new(0, 0), "# b\n");
+    assert!(matches!(transaction.commit(), Err(SpliceError::Conflict { .. })));
+
+    // invalid spans
+    assert!(matches!(
+      splicer.try_splice(&Span::new(0, 100), ""),
+      Err(SpliceError::OutOfRange { len: 26, .. })
+    ));
+    assert!(matches!(
+      splicer.try_splice(&Span::new(4, 2), ""),
+      Err(SpliceError::OutOfRange { .. })
+    ));
+
+    let mut unicode = Splicer::from_str("LABEL foo=\"\u{e9}\"");
+    assert!(matches!(
+      unicode.try_splice(&Span::new(11, 12), "e"),
+      Err(SpliceError::NotCharBoundary { .. })
+    ));
+
+    // conflicts with previously applied edits
+    splicer.try_splice(&from.image.span, "alpine:3.11").unwrap();
+    assert!(matches!(
+      splicer.try_splice(&Span::new(from.image.span.start, from.image.span.start + 6), "debian"),
+      Err(SpliceError::Conflict { .. })
+    ));
+    assert_eq!(splicer.content, "FROM alpine:3.11 as build\n");
+  }
+
+  #[test]
+  fn test_instruction_ops() {
+    let d = Dockerfile::parse(indoc!(r#"
+      FROM alpine:3.10
+        # install curl
+        RUN apk add --no-cache \
+          curl
+
+        ENV foo=bar
+        # start
+        CMD ["sh"]
+    "#)).unwrap();
+
+    let mut splicer = d.splicer();
+    splicer.insert_after(&d.instructions[2], "ENV bar=baz").unwrap();
+    splicer.insert_before(&d.instructions[3], "USER nonroot").unwrap();
+    splicer.delete_instruction(&d.instructions[1]).unwrap();
+    assert_eq!(splicer.content, indoc!(r#"
+      FROM alpine:3.10
+
+        ENV foo=bar
+        ENV bar=baz
+        USER nonroot
+        # start
+        CMD ["sh"]
+    "#));
+    assert!(Dockerfile::parse(&splicer.content).is_ok());
+
+    let mut splicer = d.splicer();
+    splicer.move_after(&d.instructions[1], &d.instructions[3]).unwrap();
+    assert_eq!(splicer.content, indoc!(r#"
+      FROM alpine:3.10
+
+        ENV foo=bar
+        # start
+        CMD ["sh"]
+        # install curl
+        RUN apk add --no-cache \
+          curl
+    "#));
+
+    let mut splicer = d.splicer();
+    splicer.move_before(&d.instructions[3], &d.instructions[1]).unwrap();
+    assert_eq!(splicer.content, indoc!(r#"
+      FROM alpine:3.10
+        # start
+        CMD ["sh"]
+        # install curl
+        RUN apk add --no-cache \
+          curl
+
+        ENV foo=bar
+    "#));
+
+    // moving an instruction conflicts with other edits to it
+    let mut splicer = d.splicer();
+    splicer.try_splice(&d.instructions[3].span(), "CMD [\"bash\"]").unwrap();
+    assert!(splicer.move_before(&d.instructions[3], &d.instructions[1]).is_err());
+  }
+
+  #[test]
+  fn test_directives_end() {
+    assert_eq!(directives_end("# syntax=foo\n#escape = `\nFROM alpine\n"), 25);
+    assert_eq!(directives_end("# a comment\n# syntax=foo\n"), 0);
+    assert_eq!(directives_end("FROM alpine\n"), 0);
+  }
+
+  #[test]
+  fn test_instruction_ops_directives() {
+    let d = Dockerfile::parse(indoc!(r#"
+      # syntax=docker/dockerfile:1
+      # base image
+      FROM alpine:3.10
+      CMD ["sh"]
+    "#)).unwrap();
+
+    let mut splicer = d.splicer();
+    splicer.insert_before(&d.instructions[0], "ARG VERSION=1").unwrap();
+    assert_eq!(splicer.content, indoc!(r#"
+      # syntax=docker/dockerfile:1
+      ARG VERSION=1
+      # base image
+      FROM alpine:3.10
+      CMD ["sh"]
+    "#));
+
+    let mut splicer = d.splicer();
+    splicer.delete_instruction(&d.instructions[0]).unwrap();
+    assert_eq!(splicer.content, indoc!(r#"
+      # syntax=docker/dockerfile:1
+      CMD ["sh"]
+    "#));
+
+    // directives directly above an instruction are not attached to it
+    let d = Dockerfile::parse("# syntax=docker/dockerfile:1\nFROM alpine:3.10\nCMD [\"sh\"]\n").unwrap();
+
+    let mut splicer = d.splicer();
+    splicer.insert_before(&d.instructions[0], "ARG VERSION=1").unwrap();
+    assert_eq!(splicer.content, "# syntax=docker/dockerfile:1\nARG VERSION=1\nFROM alpine:3.10\nCMD [\"sh\"]\n");
+
+    let mut splicer = d.splicer();
+    splicer.delete_instruction(&d.instructions[0]).unwrap();
+    assert_eq!(splicer.content, "# syntax=docker/dockerfile:1\nCMD [\"sh\"]\n");
+
+    let mut splicer = d.splicer();
+    splicer.move_after(&d.instructions[0], &d.instructions[1]).unwrap();
+    assert_eq!(splicer.content, "# syntax=docker/dockerfile:1\nCMD [\"sh\"]\nFROM alpine:3.10\n");
+  }
+
+  #[test]
+  fn test_instruction_ops_crlf() {
+    let d = Dockerfile::parse("FROM alpine:3.10\r\nCMD [\"sh\"]").unwrap();
+
+    let mut splicer = d.splicer();
+    splicer.insert_after(&d.instructions[1], "USER nobody").unwrap();
+    splicer.insert_before(&d.instructions[1], "WORKDIR /app").unwrap();
+    assert_eq!(
+      splicer.content,
+      "FROM alpine:3.10\r\nWORKDIR /app\r\nCMD [\"sh\"]\r\nUSER nobody"
+    );
+
+    let mut splicer = d.splicer();
+    splicer.move_before(&d.instructions[1], &d.instructions[0]).unwrap();
+    assert_eq!(splicer.content, "CMD [\"sh\"]\r\nFROM alpine:3.10\r\n");
+  }
 }
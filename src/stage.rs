@@ -5,6 +5,7 @@ use std::ops::Index;
 
 use crate::dockerfile_parser::{Dockerfile, Instruction};
 use crate::image::ImageRef;
+use crate::scope::BuildOptions;
 
 /// The parent image of a Docker build stage
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -16,7 +17,10 @@ pub enum StageParent<'a> {
   Stage(usize),
 
   /// The empty (scratch) parent image
-  Scratch
+  Scratch,
+
+  /// A named build context, as passed via `--build-context`
+  NamedContext(String)
 }
 
 impl<'a> fmt::Display for StageParent<'a> {
@@ -24,7 +28,8 @@ impl<'a> fmt::Display for StageParent<'a> {
     match self {
       StageParent::Image(image) => image.fmt(f),
       StageParent::Stage(index) => index.fmt(f),
-      StageParent::Scratch => write!(f, "scratch")
+      StageParent::Scratch => write!(f, "scratch"),
+      StageParent::NamedContext(name) => name.fmt(f)
     }
   }
 }
@@ -55,12 +60,20 @@ pub struct Stage<'a> {
 
   /// The direct parent of this stage.
   ///
-  /// If this is the first stage, it will be equal to the root stage.
+  /// If this is the first stage, it will be equal to the root stage. An
+  /// external image is the image as written in the `FROM` instruction, which
+  /// may contain variables; see `resolved_parent` for the expanded image.
   pub parent: StageParent<'a>,
 
   /// The root image of this stage, either an external reference (possibly from
   /// a remote registry) or `scratch`.
-  pub root: StageParent<'a>
+  pub root: StageParent<'a>,
+
+  /// This stage's `FROM` image after substituting global `ARG`s (and any
+  /// build args the stages were resolved with), or None if it refers to
+  /// undefined variables or is otherwise invalid. For stages whose parent is
+  /// another stage, this is the name of that stage.
+  pub resolved_parent: Option<ImageRef>
 }
 
 impl<'a> Ord for Stage<'a> {
@@ -71,7 +84,7 @@ impl<'a> Ord for Stage<'a> {
 
 impl<'a> PartialOrd for Stage<'a> {
   fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-    Some(self.cmp(&other))
+    Some(self.cmp(other))
   }
 }
 
@@ -86,6 +99,8 @@ impl<'a> Stage<'a> {
   /// the given name. Per the Dockerfile spec, only instructions following the
   /// ARG definition in a particular stage will have the value in scope, even
   /// if it was a defined globally or in a previous stage.
+  ///
+  /// To determine the actual values in scope, see `Evaluator`.
   pub fn arg_index(&self, name: &str) -> Option<usize> {
     self.instructions
       .iter()
@@ -121,7 +136,44 @@ pub struct Stages<'a> {
 }
 
 impl<'a> Stages<'a> {
+  /// Splits a Dockerfile into its stages, resolving variables in `FROM`
+  /// images using the defaults of global `ARG`s.
   pub fn new(dockerfile: &'a Dockerfile) -> Stages<'a> {
+    Stages::with_options(dockerfile, &BuildOptions::default())
+  }
+
+  /// Splits a Dockerfile into its stages, resolving variables in `FROM`
+  /// images using global `ARG`s along with the given build args and platforms.
+  ///
+  /// A `FROM` image that resolves to the name of a previous stage makes that
+  /// stage the parent. Otherwise, an image matching the name of a build
+  /// context in `options` refers to that context. Images that can't be
+  /// resolved are treated as external images; see `Stage::resolved_parent`
+  /// and `unresolved_parents()`.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let dockerfile = Dockerfile::parse(r#"
+  ///   ARG BASE=builder
+  ///   FROM alpine:3.12 as builder
+  ///   FROM ${BASE}
+  ///   FROM $undefined
+  /// "#)?;
+  ///
+  /// let stages = dockerfile.stages();
+  /// assert_eq!(stages[1].parent, StageParent::Stage(0));
+  /// assert_eq!(stages[1].resolved_parent, Some(ImageRef::parse("builder")));
+  /// assert_eq!(stages[2].resolved_parent, None);
+  ///
+  /// let options = BuildOptions::default().build_arg("BASE", "ubuntu:20.04");
+  /// let stages = Stages::with_options(&dockerfile, &options);
+  /// assert!(matches!(stages[1].parent, StageParent::Image(_)));
+  /// assert_eq!(stages[1].resolved_parent, Some(ImageRef::parse("ubuntu:20.04")));
+  /// # Ok::<(), dockerfile_parser::Error>(())
+  /// ```
+  pub fn with_options(dockerfile: &'a Dockerfile, options: &BuildOptions) -> Stages<'a> {
     // note: instructions before the first FROM are not part of any stage and
     // are not included in the first stage's instruction list
 
@@ -130,11 +182,25 @@ impl<'a> Stages<'a> {
 
     for ins in &dockerfile.instructions {
       if let Instruction::From(from) = ins {
-        let image_name = from.image.as_ref().to_ascii_lowercase();
-        let parent = if image_name == "scratch" {
+        let resolved_parent = if from.image.as_ref().contains('$') {
+          from.image_parsed
+            .resolve_vars_with_options(dockerfile, options)
+            .map(|(image, _)| image)
+        } else {
+          Some(from.image_parsed.clone())
+        };
+
+        let image_name = match &resolved_parent {
+          Some(image) => image.to_string(),
+          None => from.image.as_ref().to_string()
+        };
+
+        let parent = if image_name.eq_ignore_ascii_case("scratch") {
           StageParent::Scratch
         } else if let Some(stage) = stages.get_by_name(&image_name) {
           StageParent::Stage(stage.index)
+        } else if options.build_contexts.contains_key(&image_name) {
+          StageParent::NamedContext(image_name)
         } else {
           StageParent::Image(&from.image_parsed)
         };
@@ -150,7 +216,8 @@ impl<'a> Stages<'a> {
           name: from.alias.as_ref().map(|a| a.as_ref().to_ascii_lowercase()),
           instructions: vec![ins],
           parent,
-          root
+          root,
+          resolved_parent
         });
 
         next_stage_index += 1;
@@ -165,6 +232,12 @@ impl<'a> Stages<'a> {
     stages
   }
 
+  /// Returns the stages whose `FROM` image could not be resolved, e.g. due to
+  /// references to undefined variables.
+  pub fn unresolved_parents(&self) -> impl Iterator<Item = &Stage<'a>> {
+    self.stages.iter().filter(|s| s.resolved_parent.is_none())
+  }
+
   /// Attempts to fetch a stage by its name (`FROM` alias).
   pub fn get_by_name(&'a self, name: &str) -> Option<&'a Stage<'a>> {
     self.stages.iter().find(|s| s.name == Some(name.to_ascii_lowercase()))
@@ -232,6 +305,7 @@ mod tests {
       instructions: vec![&dockerfile.instructions[1], &dockerfile.instructions[2]],
       parent: StageParent::Image(&ImageRef::parse("ubuntu:18.04")),
       root: StageParent::Image(&ImageRef::parse("ubuntu:18.04")),
+      resolved_parent: Some(ImageRef::parse("ubuntu:18.04")),
     });
 
     assert_eq!(stages[2], Stage {
@@ -240,6 +314,7 @@ mod tests {
       instructions: dockerfile.instructions[3..5].iter().collect(),
       parent: StageParent::Stage(1),
       root: StageParent::Image(&ImageRef::parse("ubuntu:18.04")),
+      resolved_parent: Some(ImageRef::parse("build")),
     });
 
     assert_eq!(stages[3], Stage {
@@ -248,6 +323,7 @@ mod tests {
       instructions: vec![&dockerfile.instructions[6]],
       parent: StageParent::Stage(2),
       root: StageParent::Image(&ImageRef::parse("ubuntu:18.04")),
+      resolved_parent: Some(ImageRef::parse("build")),
     });
   }
 
@@ -266,4 +342,39 @@ mod tests {
     assert_eq!(stages.get("1"), stages.get("build"));
     assert_eq!(stages.get("2"), stages.get("build2"));
   }
+
+  #[test]
+  fn test_stages_resolved_parents() {
+    let dockerfile = Dockerfile::parse(indoc!(r#"
+      ARG BASE=builder
+      ARG EMPTY=scratch
+      ARG TAG
+      FROM golang:1.15 as Builder
+      FROM ${BASE} as build2
+      FROM $EMPTY
+      FROM alpine:$TAG
+      FROM ${BASE:-ubuntu}:${VERSION}
+    "#)).unwrap();
+
+    let stages = Stages::new(&dockerfile);
+    assert_eq!(stages[1].parent, StageParent::Stage(0));
+    assert_eq!(stages[1].root, StageParent::Image(&ImageRef::parse("golang:1.15")));
+    assert_eq!(stages[2].parent, StageParent::Scratch);
+    assert_eq!(stages[3].resolved_parent, None);
+    assert_eq!(stages[4].resolved_parent, None);
+    assert_eq!(stages.unresolved_parents().map(|s| s.index).collect::<Vec<_>>(), vec![3, 4]);
+
+    let options = BuildOptions::default()
+      .build_arg("BASE", "build2")
+      .build_arg("TAG", "3.12")
+      .build_arg("VERSION", "1");
+    let stages = Stages::with_options(&dockerfile, &options);
+    assert_eq!(stages[1].parent, StageParent::Image(&ImageRef::parse("${BASE}")));
+    assert_eq!(stages[1].resolved_parent, Some(ImageRef::parse("build2")));
+    assert_eq!(stages[3].resolved_parent, Some(ImageRef::parse("alpine:3.12")));
+
+    // build args only apply to declared ARGs
+    assert_eq!(stages[4].resolved_parent, None);
+    assert_eq!(stages.unresolved_parents().count(), 1);
+  }
 }
@@ -0,0 +1,225 @@
+use std::fmt;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::image::ImageRef;
+
+/// Which version components an upgrade may change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpgradePolicy {
+  /// Only the third and later components may change, e.g. `3.11.4` to
+  /// `3.11.9`.
+  Patch,
+
+  /// The second and later components may change, e.g. `3.11.4` to `3.12.1`.
+  Minor,
+
+  /// Any component may change, e.g. `3.11.4` to `4.0.2`.
+  Major
+}
+
+impl UpgradePolicy {
+  /// The number of leading version components that must stay the same.
+  fn fixed_components(self) -> usize {
+    match self {
+      UpgradePolicy::Patch => 2,
+      UpgradePolicy::Minor => 1,
+      UpgradePolicy::Major => 0
+    }
+  }
+}
+
+/// An image tag split into a numeric version and an optional variant suffix,
+/// e.g. `3.11.4-alpine3.19` is version `3.11.4` with variant `alpine3.19`.
+///
+/// Tags are compatible if they have the same variant, the same number of
+/// version components and either both or neither have a `v` prefix, so e.g.
+/// `1.75-slim` is never upgraded to `1.76.0-slim` or `1.76-alpine`.
+///
+/// ```
+/// use dockerfile_parser::*;
+///
+/// let tag = TagVersion::parse("1.75-slim-bookworm").unwrap();
+/// assert_eq!(tag.version, vec![1, 75]);
+/// assert_eq!(tag.variant.as_deref(), Some("slim-bookworm"));
+///
+/// let candidates = vec!["1.75-slim-bookworm", "1.76-slim-bookworm", "2.0-slim-bookworm", "1.77-alpine"];
+/// let upgrade = tag.upgrade(&candidates, UpgradePolicy::Minor).unwrap();
+/// assert_eq!(upgrade.to_string(), "1.76-slim-bookworm");
+///
+/// assert_eq!(TagVersion::parse("latest"), None);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagVersion {
+  /// The original tag
+  pub tag: String,
+
+  /// True if the version is prefixed with `v`, e.g. `v1.2.3`
+  pub prefixed: bool,
+
+  /// The numeric version components
+  pub version: Vec<u64>,
+
+  /// The suffix following the version and a `-`, if any
+  pub variant: Option<String>
+}
+
+impl TagVersion {
+  /// Parses a tag of the form `[v]<number>[.<number>...][-<variant>]`.
+  /// Returns None if the tag does not start with a numeric version.
+  pub fn parse(tag: &str) -> Option<TagVersion> {
+    lazy_static! {
+      static ref TAG: Regex = Regex::new(r"^(v)?(\d+(?:\.\d+)*)(?:-(.+))?$").unwrap();
+    }
+
+    let captures = TAG.captures(tag)?;
+    let version = captures[2]
+      .split('.')
+      .map(|c| c.parse().ok())
+      .collect::<Option<Vec<u64>>>()?;
+
+    Some(TagVersion {
+      tag: tag.to_string(),
+      prefixed: captures.get(1).is_some(),
+      version,
+      variant: captures.get(3).map(|m| m.as_str().to_string())
+    })
+  }
+
+  /// Determines if the other tag has the same format and variant as this one.
+  pub fn is_compatible(&self, other: &TagVersion) -> bool {
+    self.prefixed == other.prefixed
+      && self.variant == other.variant
+      && self.version.len() == other.version.len()
+  }
+
+  /// Determines if the other tag is a newer, compatible version that the
+  /// given policy allows upgrading to.
+  pub fn allows_upgrade(&self, other: &TagVersion, policy: UpgradePolicy) -> bool {
+    let fixed = policy.fixed_components().min(self.version.len());
+
+    self.is_compatible(other)
+      && self.version[..fixed] == other.version[..fixed]
+      && other.version > self.version
+  }
+
+  /// Returns the newest of the candidate tags that this tag may be upgraded to
+  /// under the given policy, if any. Candidates that aren't versions are
+  /// ignored.
+  pub fn upgrade<I, S>(&self, candidates: I, policy: UpgradePolicy) -> Option<TagVersion>
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>
+  {
+    candidates
+      .into_iter()
+      .filter_map(|c| TagVersion::parse(c.as_ref()))
+      .filter(|c| self.allows_upgrade(c, policy))
+      .max_by(|a, b| a.version.cmp(&b.version))
+  }
+}
+
+impl fmt::Display for TagVersion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.tag)
+  }
+}
+
+impl ImageRef {
+  /// Parses this image's tag as a version. See `TagVersion::parse`.
+  pub fn tag_version(&self) -> Option<TagVersion> {
+    self.tag.as_deref().and_then(TagVersion::parse)
+  }
+
+  /// Returns a copy of this image with its tag upgraded to the newest of the
+  /// candidate tags allowed by the given policy, if any. Any digest is
+  /// removed, since it refers to the previous tag.
+  ///
+  /// # Example
+  /// ```
+  /// use dockerfile_parser::*;
+  ///
+  /// let image = ImageRef::parse("python:3.11.4-alpine3.19");
+  /// let tags = "3.11.4-alpine3.19\n3.11.9-alpine3.19\n3.12.1-alpine3.19\n3.11.10-alpine3.20\n";
+  ///
+  /// let patch = image.upgrade_tag(tags.lines(), UpgradePolicy::Patch).unwrap();
+  /// assert_eq!(patch.to_string(), "python:3.11.9-alpine3.19");
+  ///
+  /// let minor = image.upgrade_tag(tags.lines(), UpgradePolicy::Minor).unwrap();
+  /// assert_eq!(minor.to_string(), "python:3.12.1-alpine3.19");
+  /// ```
+  pub fn upgrade_tag<I, S>(&self, candidates: I, policy: UpgradePolicy) -> Option<ImageRef>
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>
+  {
+    let upgrade = self.tag_version()?.upgrade(candidates, policy)?;
+
+    Some(ImageRef {
+      tag: Some(upgrade.tag),
+      hash: None,
+      ..self.clone()
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use pretty_assertions::assert_eq;
+
+  use super::*;
+
+  #[test]
+  fn test_tag_version_parse() {
+    assert_eq!(TagVersion::parse("3.11.4-alpine3.19"), Some(TagVersion {
+      tag: "3.11.4-alpine3.19".into(),
+      prefixed: false,
+      version: vec![3, 11, 4],
+      variant: Some("alpine3.19".into())
+    }));
+    assert_eq!(TagVersion::parse("v1.2").map(|t| (t.prefixed, t.version)), Some((true, vec![1, 2])));
+    assert_eq!(TagVersion::parse("20.04").map(|t| t.version), Some(vec![20, 4]));
+    assert_eq!(TagVersion::parse("20.04").unwrap().to_string(), "20.04");
+    assert_eq!(TagVersion::parse("8-jdk").map(|t| t.variant), Some(Some("jdk".into())));
+
+    assert_eq!(TagVersion::parse("latest"), None);
+    assert_eq!(TagVersion::parse("alpine3.19"), None);
+    assert_eq!(TagVersion::parse("1..2"), None);
+    assert_eq!(TagVersion::parse("1.2-"), None);
+    assert_eq!(TagVersion::parse("99999999999999999999999"), None);
+  }
+
+  #[test]
+  fn test_tag_version_upgrade() {
+    let candidates = vec![
+      "latest",
+      "3.11.3", "3.11.4", "3.11.10", "3.12.0", "3.12.2", "4.0.0",
+      "3.11.12-slim", "v3.11.11", "3.12", "4.0.0-rc1",
+    ];
+
+    let upgrade = |tag: &str, policy| {
+      TagVersion::parse(tag).unwrap().upgrade(&candidates, policy).map(|t| t.tag)
+    };
+
+    assert_eq!(upgrade("3.11.4", UpgradePolicy::Patch), Some("3.11.10".into()));
+    assert_eq!(upgrade("3.11.4", UpgradePolicy::Minor), Some("3.12.2".into()));
+    assert_eq!(upgrade("3.11.4", UpgradePolicy::Major), Some("4.0.0".into()));
+    assert_eq!(upgrade("4.0.0", UpgradePolicy::Major), None);
+    assert_eq!(upgrade("3.11.4-slim", UpgradePolicy::Patch), Some("3.11.12-slim".into()));
+    assert_eq!(upgrade("v3.11.4", UpgradePolicy::Patch), Some("v3.11.11".into()));
+    assert_eq!(upgrade("3.11", UpgradePolicy::Patch), None);
+    assert_eq!(upgrade("3.11", UpgradePolicy::Minor), Some("3.12".into()));
+  }
+
+  #[test]
+  fn test_image_upgrade_tag() {
+    let image = ImageRef::parse("example.com/app:1.2.3@sha256:abcd");
+    assert_eq!(
+      image.upgrade_tag(["1.2.4", "1.3.0"], UpgradePolicy::Patch),
+      Some(ImageRef::parse("example.com/app:1.2.4"))
+    );
+    assert_eq!(image.upgrade_tag(["1.2.2"], UpgradePolicy::Major), None);
+    assert_eq!(ImageRef::parse("app").upgrade_tag(["1.0"], UpgradePolicy::Major), None);
+  }
+}
@@ -228,6 +228,7 @@ impl BreakableString {
     }
   }
 
+  #[allow(clippy::should_implement_trait)]
   pub fn add(mut self, c: impl Into<BreakableStringComponent>) -> Self {
     self.components.push(c.into());
 
@@ -304,16 +305,10 @@ pub struct Heredoc {
 
 pub(crate) fn parse_heredoc(record: Pair) -> Result<Heredoc> {
   let span = Span::from_pair(&record);
-  let content;
-
-  match record.as_rule() {
-    Rule::run_heredoc => {
-      content = record.as_str().to_string();
-    }
-    _ => return {
-      Err(unexpected_token(record))
-    }
-  }
+  let content = match record.as_rule() {
+    Rule::run_heredoc => record.as_str().to_string(),
+    _ => return Err(unexpected_token(record))
+  };
 
   Ok(Heredoc {
     span,